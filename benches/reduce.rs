@@ -0,0 +1,75 @@
+//! Time of the reduction of synthetic scripts with more and more success sites, which should
+//! grow linearly with the number of sites.
+//!
+//! Run with `cargo bench --bench reduce`.
+
+use bitcoin::ScriptBuf;
+use bitcoin_script::{define_pushable, script};
+use fraud_proof_compiler::reduce::reduce;
+use fraud_proof_compiler::structured_script::StructuredScript;
+use fraud_proof_compiler::OP_IF_RETURN_TRUE;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+define_pushable!();
+
+const SITES: [usize; 4] = [1250, 2500, 5000, 10000];
+const RUNS: u32 = 5;
+
+/// One script with all the sites next to each other.
+fn flat_script(sites: usize) -> ScriptBuf {
+    script! {
+        for i in 0..sites {
+            OP_DUP { i } OP_EQUAL OP_IF_RETURN_TRUE
+        }
+        OP_RETURN
+    }
+}
+
+/// A sequence of gadgets, each with its site in a conditional.
+fn gadget_script(sites: usize) -> ScriptBuf {
+    script! {
+        for i in 0..sites {
+            OP_DUP { i } OP_EQUAL
+            OP_IF
+                OP_DUP OP_SHA256 OP_SIZE OP_NIP 32 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_ENDIF
+        }
+        OP_RETURN
+    }
+}
+
+fn time_reduce(script: &ScriptBuf) -> Duration {
+    let structure: StructuredScript = script.clone().into();
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let mut structure = structure.clone();
+        let start = Instant::now();
+        black_box(reduce(&mut structure));
+        best = best.min(start.elapsed());
+        black_box(structure);
+    }
+    best
+}
+
+fn main() {
+    for (name, generate) in [
+        ("flat", flat_script as fn(usize) -> ScriptBuf),
+        ("gadgets", gadget_script),
+    ] {
+        let mut first = None;
+        for sites in SITES {
+            let time = time_reduce(&generate(sites));
+            let per_site = time / sites as u32;
+            let first_per_site = *first.get_or_insert(per_site);
+            println!(
+                "{:<8} {:>6} sites {:>10.3} ms {:>8.3} us/site ({:.2}x the smallest)",
+                name,
+                sites,
+                time.as_secs_f64() * 1e3,
+                per_site.as_secs_f64() * 1e6,
+                per_site.as_secs_f64() / first_per_site.as_secs_f64(),
+            );
+        }
+    }
+}
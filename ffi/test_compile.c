@@ -0,0 +1,82 @@
+/*
+ * Smoke test of the C API, run by CI on Linux:
+ *
+ *   cargo build --release
+ *   cc -Wall -Werror -Iinclude ffi/test_compile.c -Ltarget/release -lfraud_proof_compiler -o test_compile
+ *   LD_LIBRARY_PATH=target/release ./test_compile
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "fraud_proof_compiler.h"
+
+#define OP_IF 0x63
+#define OP_ENDIF 0x68
+#define OP_RETURN 0x6a
+#define OP_RETURN_TRUE 0xc7
+
+#define CHECK(cond)                                                   \
+    do {                                                              \
+        if (!(cond)) {                                                \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__,    \
+                    __LINE__, #cond);                                 \
+            exit(1);                                                  \
+        }                                                             \
+    } while (0)
+
+static void test_compile_success(void) {
+    const uint8_t script[] = {OP_IF, OP_RETURN_TRUE, OP_ENDIF, OP_RETURN};
+
+    FpcBuffer out;
+    FpcCompileStats stats;
+    FpcStatus status = fpc_compile(script, sizeof(script), &out, &stats);
+
+    CHECK(status == FPC_OK);
+    CHECK(fpc_last_error_message() == NULL);
+    CHECK(out.data != NULL);
+    CHECK(out.len > sizeof(script));
+    CHECK(stats.input_size == sizeof(script));
+    CHECK(stats.output_size == out.len);
+    CHECK(stats.success_sites == 1);
+
+    /* the pseudo opcode must not survive the compilation */
+    CHECK(memchr(out.data, OP_RETURN_TRUE, out.len) == NULL);
+
+    fpc_buffer_free(out);
+}
+
+static void test_compile_error(void) {
+    const uint8_t script[] = {OP_IF, OP_RETURN_TRUE};
+
+    FpcBuffer out;
+    FpcStatus status = fpc_compile(script, sizeof(script), &out, NULL);
+
+    CHECK(status == FPC_COMPILE_ERROR);
+    CHECK(out.data == NULL);
+    CHECK(out.len == 0);
+    CHECK(fpc_last_error_message() != NULL);
+    CHECK(strstr(fpc_last_error_message(), "unbalanced") != NULL);
+
+    /* freeing an empty buffer is allowed */
+    fpc_buffer_free(out);
+}
+
+static void test_invalid_argument(void) {
+    const uint8_t script[] = {OP_RETURN};
+
+    CHECK(fpc_compile(script, sizeof(script), NULL, NULL) == FPC_INVALID_ARGUMENT);
+    CHECK(fpc_last_error_message() != NULL);
+}
+
+int main(void) {
+    CHECK(strlen(fpc_version()) > 0);
+
+    test_compile_success();
+    test_compile_error();
+    test_invalid_argument();
+
+    printf("all C API tests passed (version %s)\n", fpc_version());
+    return 0;
+}
@@ -0,0 +1,105 @@
+#ifndef FRAUD_PROOF_COMPILER_H
+#define FRAUD_PROOF_COMPILER_H
+
+/* This file is generated by cbindgen from src/ffi.rs. Do not edit it manually. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * Status codes returned by the functions of the C API.
+ */
+typedef enum FpcStatus {
+  /**
+   * The call succeeded.
+   */
+  FPC_OK = 0,
+  /**
+   * A required pointer argument is null.
+   */
+  FPC_INVALID_ARGUMENT = 1,
+  /**
+   * The script cannot be compiled, see `fpc_last_error_message`.
+   */
+  FPC_COMPILE_ERROR = 2,
+  /**
+   * The compiler panicked, see `fpc_last_error_message`.
+   */
+  FPC_INTERNAL_ERROR = 3,
+} FpcStatus;
+
+/**
+ * A byte buffer owned by the library, to be released with `fpc_buffer_free`.
+ */
+typedef struct FpcBuffer {
+  uint8_t *data;
+  size_t len;
+} FpcBuffer;
+
+/**
+ * Statistics of a compilation.
+ */
+typedef struct FpcCompileStats {
+  /**
+   * Size of the input script in bytes.
+   */
+  size_t input_size;
+  /**
+   * Size of the compiled script in bytes.
+   */
+  size_t output_size;
+  /**
+   * Number of success sites that survive the code cleanup.
+   */
+  size_t success_sites;
+} FpcCompileStats;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Compile the script at `script`/`script_len` and write the compiled script into `out`.
+ *
+ * On success, `out` owns a buffer that must be released with `fpc_buffer_free`, and `stats`,
+ * if not null, receives the statistics of the compilation. On failure, `out` is set to an
+ * empty buffer and the reason can be retrieved with `fpc_last_error_message`.
+ *
+ * # Safety
+ *
+ * `script` must point to `script_len` readable bytes (it may be null if `script_len` is 0),
+ * `out` must be a valid pointer, and `stats` must be either null or a valid pointer.
+ */
+enum FpcStatus fpc_compile(const uint8_t *script,
+                           size_t script_len,
+                           struct FpcBuffer *out,
+                           struct FpcCompileStats *stats);
+
+/**
+ * Release a buffer returned by the library. Releasing an empty buffer is a no-op.
+ *
+ * # Safety
+ *
+ * `buffer` must have been returned by this library and not been released before.
+ */
+void fpc_buffer_free(struct FpcBuffer buffer);
+
+/**
+ * Return the error message of the last failed call on this thread, or null if the last call
+ * succeeded.
+ *
+ * The string is owned by the library and stays valid until the next call into the library
+ * on the same thread.
+ */
+const char *fpc_last_error_message(void);
+
+/**
+ * Return the version of the compiler as a static NUL-terminated string.
+ */
+const char *fpc_version(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* FRAUD_PROOF_COMPILER_H */
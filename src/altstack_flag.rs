@@ -0,0 +1,135 @@
+//! Lowering of the success sites with the success flag on the altstack.
+//!
+//! Instead of nesting the code after a success site in the branch where the site did not
+//! succeed, as `reduce` does, the flag lives on the altstack, and the code after a site is
+//! split into segments, each of which runs only if the flag is still 0:
+//!
+//! ```text
+//! 0 OP_TOALTSTACK
+//! <segment 0> OP_IF OP_FROMALTSTACK OP_NOT OP_TOALTSTACK OP_ENDIF
+//! OP_FROMALTSTACK OP_DUP OP_TOALTSTACK OP_NOTIF
+//!     <segment 1> OP_IF OP_FROMALTSTACK OP_NOT OP_TOALTSTACK OP_ENDIF
+//! OP_ENDIF
+//! ...
+//! OP_FROMALTSTACK
+//! ```
+//!
+//! The main stack then only holds the user's elements, and the segments do not nest. Since
+//! a segment only runs while the flag is 0, a site sets the flag with `OP_NOT`. The script
+//! must not use the altstack itself, which `check_altstack_unused` ensures.
+
+use crate::reduce::EmitOpIfSuccess;
+use crate::segment::{lower_segments, script, SiteFlag};
+use crate::structured_script::StructuredScript;
+use crate::{_OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE};
+use bitcoin::opcodes::all::{OP_DUP, OP_FROMALTSTACK, OP_NOT, OP_TOALTSTACK};
+use bitcoin::opcodes::OP_0;
+use bitcoin::script::Instruction;
+use bitcoin::{Opcode, Script};
+
+/// Whether the script leaves the altstack to the success flag.
+pub fn check_altstack_unused(script: &Script) -> bool {
+    !script.instructions().any(|inst| {
+        matches!(inst, Ok(Instruction::Op(op)) if op == OP_TOALTSTACK || op == OP_FROMALTSTACK)
+    })
+}
+
+/// Lower the success sites, after `op_return_true_to_op_if_return_true`, and leave the flag
+/// on top of the main stack at the end, as `reduce` does.
+pub fn lower_with_altstack_flag(structure: &mut StructuredScript) -> EmitOpIfSuccess {
+    if !structure.contains_pseudo_opcodes() {
+        return EmitOpIfSuccess::NO;
+    }
+
+    let body = lower_segments::<AltstackFlag>(std::mem::take(structure));
+    *structure = StructuredScript::MultiScript(vec![
+        script(vec![OP_0, OP_TOALTSTACK]),
+        body,
+        script(vec![OP_FROMALTSTACK]),
+    ]);
+    EmitOpIfSuccess::YES
+}
+
+/// The flag on top of the altstack, which is 0 wherever a site can be reached.
+struct AltstackFlag;
+
+impl SiteFlag for AltstackFlag {
+    fn site(op: Opcode) -> StructuredScript {
+        let set_flag = script(vec![OP_FROMALTSTACK, OP_NOT, OP_TOALTSTACK]);
+        if op == _OP_IF_RETURN_TRUE {
+            StructuredScript::IfEndIf(Box::new(set_flag))
+        } else if op == _OP_NOTIF_RETURN_TRUE {
+            StructuredScript::NotIfEndIf(Box::new(set_flag))
+        } else {
+            set_flag
+        }
+    }
+
+    fn guard(segments: &mut Vec<StructuredScript>, segment: Vec<StructuredScript>) {
+        segments.push(script(vec![OP_FROMALTSTACK, OP_DUP, OP_TOALTSTACK]));
+        segments.push(StructuredScript::NotIfEndIf(Box::new(
+            StructuredScript::MultiScript(segment),
+        )));
+    }
+
+    /// The flag stays on the altstack.
+    fn no_success() -> Option<StructuredScript> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::altstack_flag::lower_with_altstack_flag;
+    use crate::reduce::EmitOpIfSuccess;
+    use crate::structured_script::StructuredScript;
+    use crate::OP_IF_RETURN_TRUE;
+    use bitcoin::ScriptBuf;
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_lower_with_altstack_flag() {
+        let test_script = script! {
+            OP_NOP1
+            OP_IF_RETURN_TRUE
+            OP_NOP2
+            OP_IF
+                OP_NOP3
+                OP_IF_RETURN_TRUE
+                OP_NOP4
+            OP_ENDIF
+            OP_NOP5
+        };
+        let mut script: StructuredScript = test_script.into();
+        assert_eq!(lower_with_altstack_flag(&mut script), EmitOpIfSuccess::YES);
+
+        let expected = script! {
+            0 OP_TOALTSTACK
+            OP_NOP1
+            OP_IF OP_FROMALTSTACK OP_NOT OP_TOALTSTACK OP_ENDIF
+            OP_FROMALTSTACK OP_DUP OP_TOALTSTACK
+            OP_NOTIF
+                OP_NOP2
+                OP_IF
+                    OP_NOP3
+                    OP_IF OP_FROMALTSTACK OP_NOT OP_TOALTSTACK OP_ENDIF
+                    OP_FROMALTSTACK OP_DUP OP_TOALTSTACK
+                    OP_NOTIF
+                        OP_NOP4
+                    OP_ENDIF
+                OP_ENDIF
+            OP_ENDIF
+            OP_FROMALTSTACK OP_DUP OP_TOALTSTACK
+            OP_NOTIF
+                OP_NOP5
+            OP_ENDIF
+            OP_FROMALTSTACK
+        };
+        assert_eq!(ScriptBuf::from(script), expected);
+
+        let mut script: StructuredScript = script! { OP_NOP1 OP_IF OP_NOP2 OP_ENDIF }.into();
+        assert_eq!(lower_with_altstack_flag(&mut script), EmitOpIfSuccess::NO);
+    }
+}
@@ -0,0 +1,210 @@
+//! Attribution of a successful execution to the success site that caused it.
+//!
+//! The compiled script merges all the success sites into one final emit, so the site that
+//! fired cannot be read from an execution of it. Instead, for each success site, a variant of
+//! the script is compiled where every other site fails whenever it would succeed, and it is
+//! executed with the same witness. Since a script only runs forward, only the variant of the
+//! first site that is reached with a true condition succeeds. A success without any site is
+//! detected by the variant where all the sites are disabled.
+
+use crate::compile::{check_lowering, lower, prepare, stack_cleanup, CompileError, CompileOptions};
+use crate::final_emit::StackCleanup;
+use crate::incremental::IncrementalStats;
+use crate::site_label::{is_success_site, label_len, site_labels};
+use crate::structured_script::{OwnedInstruction, StructuredScript};
+use crate::{_OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE};
+use bitcoin::opcodes::all::{OP_ENDIF, OP_IF, OP_NOTIF, OP_RETURN};
+use bitcoin::{Script, ScriptBuf};
+use bitcoin_scriptexec::execute_script_with_witness;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SuccessAttribution {
+    /// The compiled script fails with the witness.
+    Failure,
+    /// The compiled script succeeds through a success site. `index` refers to
+    /// `CompileReport::site_labels`.
+    Site { index: usize, label: Option<String> },
+    /// The compiled script succeeds without going through any success site.
+    FallThrough,
+}
+
+/// Run the witness against the compiled script, with the default options, and report which
+/// success site caused the success.
+pub fn attribute_success(
+    script: &Script,
+    witness: Vec<Vec<u8>>,
+) -> Result<SuccessAttribution, CompileError> {
+    attribute_success_with_options(script, &CompileOptions::default(), witness)
+}
+
+/// Run the witness against the compiled script and report which success site caused the
+/// success.
+///
+/// This compiles and executes the script once more for each success site, so it is meant
+/// for investigating a disprove transaction rather than for the hot path.
+pub fn attribute_success_with_options(
+    script: &Script,
+    options: &CompileOptions,
+    witness: Vec<Vec<u8>>,
+) -> Result<SuccessAttribution, CompileError> {
+    let altstack_available = check_lowering(script, options)?;
+    let (structured_script, _) = prepare(script)?;
+    let labels = site_labels(&structured_script);
+    // disabling sites does not change the depths at the other ones
+    let cleanup = stack_cleanup(&structured_script, options);
+
+    let compiled = lower(
+        structured_script.clone(),
+        options,
+        altstack_available,
+        cleanup,
+        &mut IncrementalStats::default(),
+    )
+    .script;
+    if !execute_script_with_witness(compiled, witness.clone()).success {
+        return Ok(SuccessAttribution::Failure);
+    }
+
+    // with all the sites disabled, the script still succeeds if and only if no site is
+    // reached with a true condition
+    if execute_variant(
+        &structured_script,
+        None,
+        options,
+        altstack_available,
+        cleanup,
+        &witness,
+    ) {
+        return Ok(SuccessAttribution::FallThrough);
+    }
+
+    for (index, label) in labels.into_iter().enumerate() {
+        if execute_variant(
+            &structured_script,
+            Some(index),
+            options,
+            altstack_available,
+            cleanup,
+            &witness,
+        ) {
+            return Ok(SuccessAttribution::Site { index, label });
+        }
+    }
+
+    // unreachable if the compilation preserves the semantics of the script
+    Ok(SuccessAttribution::FallThrough)
+}
+
+/// Whether the variant of the script where only the `keep`-th success site is left succeeds.
+fn execute_variant(
+    structured_script: &StructuredScript,
+    keep: Option<usize>,
+    options: &CompileOptions,
+    altstack_available: bool,
+    cleanup: StackCleanup,
+    witness: &[Vec<u8>],
+) -> bool {
+    let mut variant = structured_script.clone();
+    disable_other_sites(&mut variant, keep);
+
+    // the disabled sites introduce new conditionals, so the script is parsed again
+    let variant: StructuredScript = ScriptBuf::from(variant).into();
+    let compiled = lower(
+        variant,
+        options,
+        altstack_available,
+        cleanup,
+        &mut IncrementalStats::default(),
+    )
+    .script;
+
+    execute_script_with_witness(compiled, witness.to_vec()).success
+}
+
+/// Replace every success site other than the `keep`-th one, if any, with code that fails
+/// where the site would succeed, and otherwise behaves the same.
+fn disable_other_sites(structure: &mut StructuredScript, keep: Option<usize>) {
+    // the index of the next success site, in the order of the script
+    let mut next = 0;
+    structure.for_each_script_mut(|v| {
+        let mut res = vec![];
+        let mut i = 0;
+        while i < v.0.len() {
+            match &v.0[i] {
+                OwnedInstruction::Op(op) if is_success_site(*op) => {
+                    let label_end = i + 1 + label_len(&v.0, i + 1);
+                    if Some(next) == keep {
+                        res.extend_from_slice(&v.0[i..label_end]);
+                    } else if *op == _OP_IF_RETURN_TRUE || *op == _OP_NOTIF_RETURN_TRUE {
+                        let if_op = if *op == _OP_IF_RETURN_TRUE {
+                            OP_IF
+                        } else {
+                            OP_NOTIF
+                        };
+                        res.push(OwnedInstruction::Op(if_op));
+                        res.push(OwnedInstruction::Op(OP_RETURN));
+                        res.push(OwnedInstruction::Op(OP_ENDIF));
+                    } else {
+                        res.push(OwnedInstruction::Op(OP_RETURN));
+                    }
+                    next += 1;
+                    i = label_end;
+                }
+                inst => {
+                    res.push(inst.clone());
+                    i += 1;
+                }
+            }
+        }
+        v.0 = res;
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::attribution::{attribute_success, SuccessAttribution};
+    use crate::{OP_IF_RETURN_TRUE, OP_RETURN_TRUE, OP_SITE_LABEL};
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_attribution() {
+        let script = script! {
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("hash_mismatch") }
+            OP_DUP 10002 OP_GREATERTHANOREQUAL
+            OP_IF
+                OP_DUP 10003 OP_EQUAL OP_IF_RETURN_TRUE
+                OP_RETURN_TRUE { OP_SITE_LABEL("out_of_range") }
+            OP_ENDIF
+            OP_DROP
+            OP_DEPTH OP_NOT
+        };
+
+        let site = |index: usize, label: Option<&str>| SuccessAttribution::Site {
+            index,
+            label: label.map(str::to_string),
+        };
+
+        assert_eq!(
+            attribute_success(&script, vec![vec![0x11, 0x27]]),
+            Ok(site(0, Some("hash_mismatch")))
+        );
+        assert_eq!(
+            attribute_success(&script, vec![vec![0x13, 0x27]]),
+            Ok(site(1, None))
+        );
+        assert_eq!(
+            attribute_success(&script, vec![vec![0x14, 0x27]]),
+            Ok(site(2, Some("out_of_range")))
+        );
+        assert_eq!(
+            attribute_success(&script, vec![vec![0x10, 0x27]]),
+            Ok(SuccessAttribution::FallThrough)
+        );
+        assert_eq!(
+            attribute_success(&script, vec![vec![0x10, 0x27], vec![]]),
+            Ok(SuccessAttribution::Failure)
+        );
+    }
+}
@@ -0,0 +1,609 @@
+//! Content-addressed cache of compiled scripts.
+//!
+//! A compiled script is stored under a hash of the compiler version, the version of the
+//! compiled output, the compile options, and the input script. Because both versions are part
+//! of the key, entries written by a compiler whose output differs are never returned.
+
+use crate::compile::{
+    CompileOptions, CompileReport, CompileWarning, CompiledScript, LoweringReport, LoweringStrategy,
+};
+use crate::depth_analysis::BranchTaken;
+use crate::final_emit::StackCleanup;
+use crate::flag_block::TailChoice;
+use crate::incremental::IncrementalStats;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::{Opcode, Script, ScriptBuf};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Version of the compiler, which invalidates the cache when it changes.
+pub const COMPILER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Version of the compiled output, which invalidates the cache when it changes. It must be
+/// bumped by every change that changes the compiled script or the report of some input, since
+/// `COMPILER_VERSION` only changes with releases.
+pub const OUTPUT_VERSION: u32 = 7;
+
+/// Version of the on-disk encoding of `CompiledScript`.
+const DISK_FORMAT_VERSION: u8 = 11;
+
+pub type CacheKey = sha256::Hash;
+
+pub trait CompileCache: Debug + Send + Sync {
+    fn get(&self, key: &CacheKey) -> Option<CompiledScript>;
+
+    fn put(&self, key: &CacheKey, compiled: &CompiledScript);
+}
+
+pub fn cache_key(script: &Script, options: &CompileOptions) -> CacheKey {
+    cache_key_for_version(COMPILER_VERSION, OUTPUT_VERSION, script, options)
+}
+
+fn cache_key_for_version(
+    version: &str,
+    output_version: u32,
+    script: &Script,
+    options: &CompileOptions,
+) -> CacheKey {
+    let fingerprint = options.fingerprint();
+
+    let mut engine = sha256::Hash::engine();
+    engine.input(b"fraud-proof-compiler");
+    for part in [
+        version.as_bytes(),
+        &output_version.to_le_bytes(),
+        &fingerprint,
+        script.as_bytes(),
+    ] {
+        engine.input(&(part.len() as u64).to_le_bytes());
+        engine.input(part);
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    entries: Mutex<HashMap<CacheKey, CompiledScript>>,
+}
+
+impl MemoryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+impl CompileCache for MemoryCache {
+    fn get(&self, key: &CacheKey) -> Option<CompiledScript> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &CacheKey, compiled: &CompiledScript) {
+        self.entries.lock().unwrap().insert(*key, compiled.clone());
+    }
+}
+
+/// A cache that stores one file per compiled script in a directory.
+///
+/// The cache is best-effort: entries that cannot be read or decoded are treated as misses,
+/// and failures to write an entry are ignored.
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(format!("{}.fpc", key))
+    }
+}
+
+impl CompileCache for DiskCache {
+    fn get(&self, key: &CacheKey) -> Option<CompiledScript> {
+        let bytes = fs::read(self.path(key)).ok()?;
+        decode_compiled_script(&bytes)
+    }
+
+    fn put(&self, key: &CacheKey, compiled: &CompiledScript) {
+        // write to a temporary file first so that readers never see a partial entry
+        let path = self.path(key);
+        let tmp = path.with_extension(tmp_extension());
+        if fs::write(&tmp, encode_compiled_script(compiled)).is_ok()
+            && fs::rename(&tmp, &path).is_err()
+        {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+}
+
+/// Number of temporary files created by this process.
+static TMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Extension of a temporary file, unique to the call, so that concurrent writers of the same
+/// entry, in this process or another one, never write to the same file.
+fn tmp_extension() -> String {
+    format!(
+        "tmp{}-{}",
+        std::process::id(),
+        TMP_FILES.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn encode_compiled_script(compiled: &CompiledScript) -> Vec<u8> {
+    let mut buf = vec![DISK_FORMAT_VERSION];
+
+    write_usize(&mut buf, compiled.report.input_size);
+    write_usize(&mut buf, compiled.report.output_size);
+    write_usize(&mut buf, compiled.report.success_sites);
+    write_usize(&mut buf, compiled.report.incremental.pseudo_free_subtrees);
+    write_usize(&mut buf, compiled.report.incremental.reused_subtrees);
+    write_usize(&mut buf, compiled.report.incremental.reduced_subtrees);
+
+    write_usize(&mut buf, compiled.report.site_labels.len());
+    for label in compiled.report.site_labels.iter() {
+        write_label(&mut buf, label);
+    }
+
+    write_usize(&mut buf, compiled.report.warnings.len());
+    for warning in compiled.report.warnings.iter() {
+        match warning {
+            CompileWarning::FallThroughMaySucceed => buf.push(0),
+            CompileWarning::DeadSuccessSite { index, label } => {
+                buf.push(1);
+                write_usize(&mut buf, *index);
+                write_label(&mut buf, label);
+            }
+            CompileWarning::StackLimitExceeded {
+                elements,
+                input_elements,
+                position,
+                path,
+            } => {
+                buf.push(2);
+                write_usize(&mut buf, *elements);
+                write_usize(&mut buf, *input_elements);
+                write_usize(&mut buf, *position);
+                write_usize(&mut buf, path.len());
+                for taken in path.iter() {
+                    write_usize(&mut buf, taken.position);
+                    write_usize(&mut buf, taken.branch);
+                }
+            }
+            CompileWarning::StackLimitUnchecked { position, opcode } => {
+                buf.push(3);
+                write_usize(&mut buf, *position);
+                buf.push(opcode.to_u8());
+            }
+        }
+    }
+
+    for lowerings in [&compiled.report.lowerings, &compiled.report.regions] {
+        write_usize(&mut buf, lowerings.len());
+        for lowering in lowerings.iter() {
+            buf.push(lowering.strategy as u8);
+            write_usize(&mut buf, lowering.size);
+            write_usize(&mut buf, lowering.nesting_depth);
+            buf.extend_from_slice(&lowering.cost.to_le_bytes());
+        }
+    }
+
+    write_usize(&mut buf, compiled.report.tails.len());
+    for tail in compiled.report.tails.iter() {
+        write_usize(&mut buf, tail.size);
+        write_usize(&mut buf, tail.branches);
+        buf.push(tail.duplicated as u8);
+    }
+
+    match compiled.report.stack_cleanup {
+        StackCleanup::Full => buf.push(0),
+        StackCleanup::Exact(depth) => {
+            buf.push(1);
+            write_usize(&mut buf, depth);
+        }
+        StackCleanup::Bounded(max) => {
+            buf.push(2);
+            write_usize(&mut buf, max);
+        }
+        StackCleanup::Guarded(max) => {
+            buf.push(3);
+            write_usize(&mut buf, max);
+        }
+    }
+
+    match compiled.report.max_stack_elements {
+        None => buf.push(0),
+        Some(elements) => {
+            buf.push(1);
+            write_usize(&mut buf, elements);
+        }
+    }
+
+    write_usize(&mut buf, compiled.script.len());
+    buf.extend_from_slice(compiled.script.as_bytes());
+    buf
+}
+
+fn write_usize(buf: &mut Vec<u8>, v: usize) {
+    buf.extend_from_slice(&(v as u64).to_le_bytes());
+}
+
+/// Write a label as its length plus one, with 0 for a site without a label.
+fn write_label(buf: &mut Vec<u8>, label: &Option<String>) {
+    match label {
+        Some(label) => {
+            write_usize(buf, label.len() + 1);
+            buf.extend_from_slice(label.as_bytes());
+        }
+        None => write_usize(buf, 0),
+    }
+}
+
+fn decode_compiled_script(bytes: &[u8]) -> Option<CompiledScript> {
+    let (version, mut rest) = bytes.split_first()?;
+    if *version != DISK_FORMAT_VERSION {
+        return None;
+    }
+
+    let input_size = read_usize(&mut rest)?;
+    let output_size = read_usize(&mut rest)?;
+    let success_sites = read_usize(&mut rest)?;
+    let incremental = IncrementalStats {
+        pseudo_free_subtrees: read_usize(&mut rest)?,
+        reused_subtrees: read_usize(&mut rest)?,
+        reduced_subtrees: read_usize(&mut rest)?,
+    };
+
+    let num_labels = read_usize(&mut rest)?;
+    let mut site_labels = vec![];
+    for _ in 0..num_labels {
+        site_labels.push(read_label(&mut rest)?);
+    }
+
+    let num_warnings = read_usize(&mut rest)?;
+    let mut warnings = vec![];
+    for _ in 0..num_warnings {
+        let warning = match read_bytes(&mut rest, 1)?[0] {
+            0 => CompileWarning::FallThroughMaySucceed,
+            1 => CompileWarning::DeadSuccessSite {
+                index: read_usize(&mut rest)?,
+                label: read_label(&mut rest)?,
+            },
+            2 => CompileWarning::StackLimitExceeded {
+                elements: read_usize(&mut rest)?,
+                input_elements: read_usize(&mut rest)?,
+                position: read_usize(&mut rest)?,
+                path: {
+                    let len = read_usize(&mut rest)?;
+                    let mut path = vec![];
+                    for _ in 0..len {
+                        path.push(BranchTaken {
+                            position: read_usize(&mut rest)?,
+                            branch: read_usize(&mut rest)?,
+                        });
+                    }
+                    path
+                },
+            },
+            3 => CompileWarning::StackLimitUnchecked {
+                position: read_usize(&mut rest)?,
+                opcode: Opcode::from(read_bytes(&mut rest, 1)?[0]),
+            },
+            _ => return None,
+        };
+        warnings.push(warning);
+    }
+
+    let lowerings = read_lowerings(&mut rest)?;
+    let regions = read_lowerings(&mut rest)?;
+
+    let num_tails = read_usize(&mut rest)?;
+    let mut tails = vec![];
+    for _ in 0..num_tails {
+        tails.push(TailChoice {
+            size: read_usize(&mut rest)?,
+            branches: read_usize(&mut rest)?,
+            duplicated: match read_bytes(&mut rest, 1)?[0] {
+                0 => false,
+                1 => true,
+                _ => return None,
+            },
+        });
+    }
+
+    let stack_cleanup = match read_bytes(&mut rest, 1)?[0] {
+        0 => StackCleanup::Full,
+        1 => StackCleanup::Exact(read_usize(&mut rest)?),
+        2 => StackCleanup::Bounded(read_usize(&mut rest)?),
+        3 => StackCleanup::Guarded(read_usize(&mut rest)?),
+        _ => return None,
+    };
+
+    let max_stack_elements = match read_bytes(&mut rest, 1)?[0] {
+        0 => None,
+        1 => Some(read_usize(&mut rest)?),
+        _ => return None,
+    };
+
+    let script_len = read_usize(&mut rest)?;
+    if rest.len() != script_len {
+        return None;
+    }
+
+    Some(CompiledScript {
+        script: ScriptBuf::from_bytes(rest.to_vec()),
+        report: CompileReport {
+            input_size,
+            output_size,
+            success_sites,
+            site_labels,
+            cache_hit: false,
+            incremental,
+            warnings,
+            lowerings,
+            regions,
+            tails,
+            stack_cleanup,
+            max_stack_elements,
+        },
+    })
+}
+
+fn read_bytes<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if rest.len() < len {
+        return None;
+    }
+    let (v, remaining) = rest.split_at(len);
+    *rest = remaining;
+    Some(v)
+}
+
+fn read_u64(rest: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(read_bytes(rest, 8)?.try_into().unwrap()))
+}
+
+fn read_usize(rest: &mut &[u8]) -> Option<usize> {
+    usize::try_from(read_u64(rest)?).ok()
+}
+
+fn read_lowerings(rest: &mut &[u8]) -> Option<Vec<LoweringReport>> {
+    let num_lowerings = read_usize(rest)?;
+    let mut lowerings = vec![];
+    for _ in 0..num_lowerings {
+        let strategy = match read_bytes(rest, 1)?[0] {
+            0 => LoweringStrategy::Nested,
+            1 => LoweringStrategy::Altstack,
+            2 => LoweringStrategy::FlatGuard,
+            3 => LoweringStrategy::Auto,
+            _ => return None,
+        };
+        lowerings.push(LoweringReport {
+            strategy,
+            size: read_usize(rest)?,
+            nesting_depth: read_usize(rest)?,
+            cost: read_u64(rest)?,
+        });
+    }
+    Some(lowerings)
+}
+
+fn read_label(rest: &mut &[u8]) -> Option<Option<String>> {
+    match read_usize(rest)? {
+        0 => Some(None),
+        len => Some(Some(
+            String::from_utf8(read_bytes(rest, len - 1)?.to_vec()).ok()?,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cache::{
+        cache_key, cache_key_for_version, CompileCache, DiskCache, MemoryCache, COMPILER_VERSION,
+        OUTPUT_VERSION,
+    };
+    use crate::compile::{
+        compile, compile_with_options, CompileOptions, CompileWarning, LoweringStrategy,
+    };
+    use crate::{OP_IF_RETURN_TRUE, OP_NOTIF_RETURN_TRUE, OP_RETURN_TRUE, OP_SITE_LABEL};
+    use bitcoin_script::{define_pushable, script};
+    use std::sync::Arc;
+
+    define_pushable!();
+
+    #[test]
+    fn test_memory_cache() {
+        let script = script! {
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_DUP 10002 OP_EQUAL OP_IF OP_RETURN_TRUE OP_ENDIF
+            OP_RETURN
+        };
+
+        let cache = Arc::new(MemoryCache::new());
+        let options = CompileOptions {
+            cache: Some(cache.clone()),
+            ..Default::default()
+        };
+
+        let first = compile_with_options(&script, &options).unwrap();
+        assert!(!first.report.cache_hit);
+        assert_eq!(cache.len(), 1);
+
+        let second = compile_with_options(&script, &options).unwrap();
+        assert!(second.report.cache_hit);
+        assert_eq!(second.script, first.script);
+        assert_eq!(second.script, compile(&script).unwrap().script);
+
+        let other = script! { OP_IF_RETURN_TRUE OP_RETURN };
+        let third = compile_with_options(&other, &options).unwrap();
+        assert!(!third.report.cache_hit);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("fpc-cache-test-{}", std::process::id()));
+        let script = script! {
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("hash_mismatch") }
+            OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_RETURN
+            OP_IF_RETURN_TRUE { OP_SITE_LABEL("dead") }
+        };
+
+        let options = CompileOptions {
+            cache: Some(Arc::new(DiskCache::new(&dir).unwrap())),
+            strategy: LoweringStrategy::Altstack,
+            witness_elements: Some(1),
+            ..Default::default()
+        };
+        let first = compile_with_options(&script, &options).unwrap();
+        assert!(!first.report.cache_hit);
+
+        // a new cache over the same directory sees the entry
+        let options = CompileOptions {
+            cache: Some(Arc::new(DiskCache::new(&dir).unwrap())),
+            strategy: LoweringStrategy::Altstack,
+            witness_elements: Some(1),
+            ..Default::default()
+        };
+        let second = compile_with_options(&script, &options).unwrap();
+        assert!(second.report.cache_hit);
+        assert_eq!(second.script, first.script);
+        assert_eq!(second.report.output_size, first.report.output_size);
+        assert_eq!(second.report.success_sites, first.report.success_sites);
+        assert_eq!(second.report.site_labels, first.report.site_labels);
+        assert_eq!(second.report.lowerings, first.report.lowerings);
+        assert_eq!(second.report.tails, first.report.tails);
+        assert_eq!(second.report.warnings, first.report.warnings);
+        assert_eq!(
+            second.report.max_stack_elements,
+            first.report.max_stack_elements
+        );
+        assert!(first.report.max_stack_elements.is_some());
+        assert_eq!(first.report.warnings.len(), 1);
+
+        // the limit cannot be checked past OP_CAT
+        let script = script! { OP_DUP OP_CAT OP_IF_RETURN_TRUE OP_RETURN };
+        let first = compile_with_options(&script, &options).unwrap();
+        let second = compile_with_options(&script, &options).unwrap();
+        assert!(second.report.cache_hit);
+        assert_eq!(second.report.warnings, first.report.warnings);
+        assert!(matches!(
+            first.report.warnings[..],
+            [CompileWarning::StackLimitUnchecked { position: 1, .. }]
+        ));
+
+        // and so are the regions of a script lowered region by region
+        let script = script! {
+            OP_DUP 10010 OP_LESSTHAN
+            OP_IF
+                for i in 0..10 {
+                    OP_DUP { 10000 + i } OP_EQUAL OP_IF_RETURN_TRUE
+                }
+            OP_ENDIF
+            OP_DUP OP_SIZE OP_NOTIF_RETURN_TRUE
+            OP_DUP 10012 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_RETURN
+        };
+        let options = CompileOptions {
+            cache: Some(Arc::new(DiskCache::new(&dir).unwrap())),
+            strategy: LoweringStrategy::Auto,
+            max_nesting_depth: Some(6),
+            ..Default::default()
+        };
+        let first = compile_with_options(&script, &options).unwrap();
+        let second = compile_with_options(&script, &options).unwrap();
+        assert!(second.report.cache_hit);
+        assert_eq!(second.report.lowerings, first.report.lowerings);
+        assert_eq!(second.report.regions, first.report.regions);
+        assert_eq!(first.report.regions.len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_cache_concurrent_puts() {
+        let dir = std::env::temp_dir().join(format!("fpc-cache-concurrent-{}", std::process::id()));
+        let script = script! { OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE OP_RETURN };
+        let compiled = compile(&script).unwrap();
+        let cache = DiskCache::new(&dir).unwrap();
+        let key = cache_key(&script, &CompileOptions::default());
+
+        // every write goes through its own temporary file, so none of them is lost or torn
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        cache.put(&key, &compiled);
+                    }
+                });
+            }
+        });
+        let entry = cache.get(&key).unwrap();
+        assert_eq!(entry.script, compiled.script);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_version() {
+        let script = script! { OP_IF_RETURN_TRUE OP_RETURN };
+        let options = CompileOptions::default();
+
+        assert_eq!(
+            cache_key(&script, &options),
+            cache_key_for_version(env!("CARGO_PKG_VERSION"), OUTPUT_VERSION, &script, &options)
+        );
+        assert_ne!(
+            cache_key_for_version("0.1.0", OUTPUT_VERSION, &script, &options),
+            cache_key_for_version("0.1.1", OUTPUT_VERSION, &script, &options)
+        );
+    }
+
+    #[test]
+    fn test_stale_entries() {
+        let dir = std::env::temp_dir().join(format!("fpc-stale-test-{}", std::process::id()));
+        let script = script! {
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_RETURN
+        };
+        let options = CompileOptions {
+            cache: Some(Arc::new(DiskCache::new(&dir).unwrap())),
+            ..Default::default()
+        };
+
+        // an entry written by a compiler with the same release but an older output
+        let mut stale = compile(&script).unwrap();
+        stale.script = script! { OP_TRUE };
+        let key = cache_key_for_version(COMPILER_VERSION, OUTPUT_VERSION - 1, &script, &options);
+        DiskCache::new(&dir).unwrap().put(&key, &stale);
+
+        let compiled = compile_with_options(&script, &options).unwrap();
+        assert!(!compiled.report.cache_hit);
+        assert_eq!(compiled.script, compile(&script).unwrap().script);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,100 +1,106 @@
-use crate::structured_script::{OwnedInstruction, StructuredScript};
-use crate::{_OP_IF_RETURN_TRUE, _OP_RETURN_TRUE};
-use bitcoin::opcodes::all::{
-    OP_PUSHNUM_1, OP_PUSHNUM_10, OP_PUSHNUM_11, OP_PUSHNUM_12, OP_PUSHNUM_13, OP_PUSHNUM_14,
-    OP_PUSHNUM_15, OP_PUSHNUM_16, OP_PUSHNUM_2, OP_PUSHNUM_3, OP_PUSHNUM_4, OP_PUSHNUM_5,
-    OP_PUSHNUM_6, OP_PUSHNUM_7, OP_PUSHNUM_8, OP_PUSHNUM_9, OP_PUSHNUM_NEG1,
-};
+use crate::constant::{minimal_truth, pushed_value};
+use crate::site_label::label_len;
+use crate::structured_script::{Node, OwnedInstruction, OwnedInstructions, StructuredScript};
+use crate::{_OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE, _OP_RETURN_RESULT, _OP_RETURN_TRUE};
+use bitcoin::opcodes::all::OP_VERIFY;
 
 pub fn find_op_return_true_cleanup(structure: &mut StructuredScript) -> bool {
-    match structure {
-        StructuredScript::Script(v) => {
-            let len = v.0.len();
-
-            for i in 0..len {
-                if v.0[i] == OwnedInstruction::Op(_OP_RETURN_TRUE) {
-                    v.0.truncate(i + 1);
-                    return true;
-                }
-
-                if let OwnedInstruction::Op(op) = &v.0[i] {
-                    if i < len - 1
-                        && v.0[i + 1] == OwnedInstruction::Op(_OP_IF_RETURN_TRUE)
-                        && [
-                            OP_PUSHNUM_1,
-                            OP_PUSHNUM_2,
-                            OP_PUSHNUM_3,
-                            OP_PUSHNUM_4,
-                            OP_PUSHNUM_NEG1,
-                            OP_PUSHNUM_5,
-                            OP_PUSHNUM_6,
-                            OP_PUSHNUM_7,
-                            OP_PUSHNUM_8,
-                            OP_PUSHNUM_9,
-                            OP_PUSHNUM_10,
-                            OP_PUSHNUM_11,
-                            OP_PUSHNUM_12,
-                            OP_PUSHNUM_13,
-                            OP_PUSHNUM_14,
-                            OP_PUSHNUM_15,
-                            OP_PUSHNUM_16,
-                        ]
-                        .contains(op)
-                    {
-                        v.0.truncate(i);
-                        v.0.push(OwnedInstruction::Op(_OP_RETURN_TRUE));
-                        return true;
-                    }
-                }
-
-                if let OwnedInstruction::PushBytes(p) = &v.0[i] {
-                    if !p.is_empty()
-                        && i < len - 1
-                        && v.0[i + 1] == OwnedInstruction::Op(_OP_IF_RETURN_TRUE)
-                    {
-                        v.0.truncate(i);
-                        v.0.push(OwnedInstruction::Op(_OP_RETURN_TRUE));
-                        return true;
-                    }
+    // bottom up, every node with whether it ends in an OP_RETURN_TRUE, in which case the
+    // code after it in the enclosing sequences is removed
+    let (cleaned, res) = std::mem::take(structure).fold(|node| match node {
+        Node::Script(mut v) => {
+            let res = cleanup_instructions(&mut v);
+            (StructuredScript::Script(v), res)
+        }
+        Node::MultiScript(mut vv) => {
+            let res = match vv.iter().position(|(_, res)| *res) {
+                Some(i) => {
+                    vv.truncate(i + 1);
+                    true
                 }
+                None => false,
+            };
+            if res && vv.len() == 1 {
+                return vv.pop().unwrap();
             }
-            false
+            let vv = vv.into_iter().map(|(v, _)| v).collect();
+            (StructuredScript::MultiScript(vv), res)
         }
-        StructuredScript::MultiScript(vv) => {
-            let len = vv.len();
+        Node::IfEndIf((v, _)) => (StructuredScript::IfEndIf(Box::new(v)), false),
+        Node::NotIfEndIf((v, _)) => (StructuredScript::NotIfEndIf(Box::new(v)), false),
+        Node::IfElseEndIf((v1, _), (v2, _)) => (
+            StructuredScript::IfElseEndIf(Box::new(v1), Box::new(v2)),
+            false,
+        ),
+        Node::NotIfElseEndIf((v1, _), (v2, _)) => (
+            StructuredScript::NotIfElseEndIf(Box::new(v1), Box::new(v2)),
+            false,
+        ),
+        Node::Switch(arms) => (
+            StructuredScript::Switch(arms.into_iter().map(|(v, _)| v).collect()),
+            false,
+        ),
+    });
+    *structure = cleaned;
+    res
+}
 
-            for i in 0..len {
-                let res = find_op_return_true_cleanup(&mut vv[i]);
-                if res {
-                    vv.truncate(i + 1);
+fn cleanup_instructions(v: &mut OwnedInstructions) -> bool {
+    let len = v.0.len();
+
+    for i in 0..len {
+        if v.0[i] == OwnedInstruction::Op(_OP_RETURN_TRUE) {
+            v.0.truncate(i + 1 + label_len(&v.0, i + 1));
+            return true;
+        }
 
-                    if vv.len() == 1 {
-                        let content = vv[0].clone();
-                        *structure = content;
-                    }
+        // OP_RETURN_RESULT fails unless the top stack element is true, in which case
+        // it is the same as OP_RETURN_TRUE
+        if v.0[i] == OwnedInstruction::Op(_OP_RETURN_RESULT) {
+            let label = v.0[i + 1..i + 1 + label_len(&v.0, i + 1)].to_vec();
+            v.0.truncate(i);
+            v.0.push(OwnedInstruction::Op(OP_VERIFY));
+            v.0.push(OwnedInstruction::Op(_OP_RETURN_TRUE));
+            v.0.extend(label);
+            return true;
+        }
 
-                    return true;
+        // a constant condition that always triggers the OP_IF_RETURN_TRUE or the
+        // OP_NOTIF_RETURN_TRUE right after it
+        if i < len - 1 {
+            let always_succeeds = match &v.0[i + 1] {
+                OwnedInstruction::Op(op) if *op == _OP_IF_RETURN_TRUE => {
+                    constant_truth(&v.0[i]) == Some(true)
+                }
+                OwnedInstruction::Op(op) if *op == _OP_NOTIF_RETURN_TRUE => {
+                    constant_truth(&v.0[i]) == Some(false)
                 }
+                _ => false,
+            };
+
+            if always_succeeds {
+                let label = v.0[i + 2..i + 2 + label_len(&v.0, i + 2)].to_vec();
+                v.0.truncate(i);
+                v.0.push(OwnedInstruction::Op(_OP_RETURN_TRUE));
+                v.0.extend(label);
+                return true;
             }
-            false
-        }
-        StructuredScript::IfEndIf(v) | StructuredScript::NotIfEndIf(v) => {
-            find_op_return_true_cleanup(v);
-            false
-        }
-        StructuredScript::IfElseEndIf(v1, v2) | StructuredScript::NotIfElseEndIf(v1, v2) => {
-            find_op_return_true_cleanup(v1);
-            find_op_return_true_cleanup(v2);
-            false
         }
     }
+    false
+}
+
+/// The truth of the constant pushed by the instruction, if it is 0 or 1. Any other constant
+/// is left to the site, which fails on it under MINIMALIF once lowered.
+fn constant_truth(inst: &OwnedInstruction) -> Option<bool> {
+    pushed_value(inst).and_then(|value| minimal_truth(&value))
 }
 
 #[cfg(test)]
 mod test {
     use crate::code_cleanup::find_op_return_true_cleanup;
     use crate::structured_script::StructuredScript;
-    use crate::{OP_IF_RETURN_TRUE, OP_RETURN_TRUE};
+    use crate::{OP_IF_RETURN_TRUE, OP_NOTIF_RETURN_TRUE, OP_RETURN_RESULT, OP_RETURN_TRUE};
     use bitcoin_script::{define_pushable, script};
 
     define_pushable!();
@@ -112,7 +118,7 @@ mod test {
                 OP_ENDIF
             OP_ENDIF
             OP_NOP5
-            12 OP_IF_RETURN_TRUE
+            1 OP_IF_RETURN_TRUE
             OP_NOP6
         };
 
@@ -132,4 +138,86 @@ mod test {
         let expected = StructuredScript::from(expected_script);
         assert_eq!(expected, structured_script);
     }
+
+    #[test]
+    fn test_cleanup_notif() {
+        let script = script! {
+            OP_NOP1
+            1 OP_NOTIF_RETURN_TRUE
+            OP_NOP2
+            0 OP_NOTIF_RETURN_TRUE
+            OP_NOP3
+        };
+
+        let mut structured_script = StructuredScript::from(script);
+        find_op_return_true_cleanup(&mut structured_script);
+
+        let expected_script = script! {
+            OP_NOP1
+            1 OP_NOTIF_RETURN_TRUE
+            OP_NOP2
+            OP_RETURN_TRUE
+        };
+
+        let expected = StructuredScript::from(expected_script);
+        assert_eq!(expected, structured_script);
+    }
+
+    #[test]
+    fn test_cleanup_non_minimal_constants() {
+        // the sites fail on any constant other than 0 and 1 under MINIMALIF, so they are kept
+        let script = script! {
+            OP_NOP1
+            12 OP_IF_RETURN_TRUE
+            { vec![0x00] } OP_NOTIF_RETURN_TRUE
+            { vec![0x00, 0x80] } OP_NOTIF_RETURN_TRUE
+            OP_NOP2
+            0 OP_NOTIF_RETURN_TRUE
+            OP_NOP3
+        };
+
+        let mut structured_script = StructuredScript::from(script);
+        find_op_return_true_cleanup(&mut structured_script);
+
+        let expected_script = script! {
+            OP_NOP1
+            12 OP_IF_RETURN_TRUE
+            { vec![0x00] } OP_NOTIF_RETURN_TRUE
+            { vec![0x00, 0x80] } OP_NOTIF_RETURN_TRUE
+            OP_NOP2
+            OP_RETURN_TRUE
+        };
+
+        let expected = StructuredScript::from(expected_script);
+        assert_eq!(expected, structured_script);
+    }
+
+    #[test]
+    fn test_cleanup_return_result() {
+        let script = script! {
+            OP_NOP1
+            OP_IF
+                OP_NOP2
+                OP_RETURN_RESULT
+                OP_NOP3
+            OP_ENDIF
+            OP_NOP4
+        };
+
+        let mut structured_script = StructuredScript::from(script);
+        find_op_return_true_cleanup(&mut structured_script);
+
+        let expected_script = script! {
+            OP_NOP1
+            OP_IF
+                OP_NOP2
+                OP_VERIFY
+                OP_RETURN_TRUE
+            OP_ENDIF
+            OP_NOP4
+        };
+
+        let expected = StructuredScript::from(expected_script);
+        assert_eq!(expected, structured_script);
+    }
 }
@@ -0,0 +1,979 @@
+use crate::altstack_flag::{check_altstack_unused, lower_with_altstack_flag};
+use crate::cache::{cache_key, CompileCache};
+use crate::code_cleanup::find_op_return_true_cleanup;
+use crate::cost::CostModel;
+use crate::dead_code::{eliminate_dead_code, DeadSite};
+use crate::depth_analysis::{analyze_stack_depths, BranchTaken, SiteDepths};
+use crate::final_emit::{
+    append_failure_tail, append_final_emit_script, FallThrough, StackCleanup, SuccessEpilogue,
+};
+use crate::flag_block::{optimize_flag_blocks, TailChoice, DEFAULT_MAX_TAIL_SIZE};
+use crate::flat_guard::{join_flat_guarded, lower_with_flat_guards};
+use crate::hoist::hoist_common_code;
+use crate::incremental::{reduce_incremental, IncrementalStats, ReductionMemo};
+use crate::op_return_true_to_op_if_return_true::op_return_true_to_op_if_return_true;
+use crate::parallel;
+use crate::peephole::peephole_optimize;
+use crate::reduce::{reduce, EmitOpIfSuccess};
+use crate::segment::script;
+use crate::site_label::{is_success_site, site_labels, strip_site_labels};
+use crate::stack_effect::MAX_STACK_ELEMENTS;
+use crate::stack_limit::{check_stack_limit, StackLimitError};
+use crate::structured_script::{Node, OwnedInstruction, StructuredScript};
+use crate::{_OP_CASE, _OP_ENDSWITCH, _OP_RETURN_TRUE, _OP_SITE_LABEL, _OP_SWITCH};
+use bitcoin::opcodes::all::{OP_ELSE, OP_ENDIF, OP_IF, OP_NOTIF, OP_RETURN};
+use bitcoin::opcodes::OP_0;
+use bitcoin::script::Instruction;
+use bitcoin::{Opcode, Script, ScriptBuf};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    /// The input bytes cannot be decoded into script instructions.
+    InvalidScript(String),
+    /// An `OP_IF`/`OP_NOTIF` without its `OP_ENDIF`, or an `OP_ELSE`/`OP_ENDIF` without its `OP_IF`.
+    UnbalancedConditional,
+    /// An `OP_IF`/`OP_NOTIF` with more than one `OP_ELSE`, which the compiler does not support.
+    MultipleElse,
+    /// An `OP_SWITCH` that is not directly followed by an `OP_CASE` or not closed by an
+    /// `OP_ENDSWITCH`, or an `OP_CASE`/`OP_ENDSWITCH` outside of a switch.
+    MalformedSwitch,
+    /// An `OP_SITE_LABEL` that does not directly follow a success pseudo opcode and the push
+    /// of its label.
+    MisplacedSiteLabel,
+    /// A `SuccessEpilogue` that does not leave exactly one element on the stack, or whose
+    /// stack effect cannot be determined.
+    InvalidEpilogue(String),
+    /// With `LoweringStrategy::Altstack`, the script or the success epilogue uses the
+    /// altstack, which holds the success flag.
+    AltstackInUse,
+    /// The compiled script nests `OP_IF`/`OP_NOTIF` deeper than
+    /// `CompileOptions::max_nesting_depth`.
+    NestingTooDeep { depth: usize, max: usize },
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::InvalidScript(e) => write!(f, "the script cannot be decoded: {}", e),
+            CompileError::UnbalancedConditional => {
+                write!(
+                    f,
+                    "the script has unbalanced OP_IF/OP_NOTIF/OP_ELSE/OP_ENDIF"
+                )
+            }
+            CompileError::MultipleElse => {
+                write!(f, "an OP_IF or OP_NOTIF has more than one OP_ELSE")
+            }
+            CompileError::MalformedSwitch => {
+                write!(f, "an OP_SWITCH/OP_CASE/OP_ENDSWITCH is malformed")
+            }
+            CompileError::MisplacedSiteLabel => {
+                write!(
+                    f,
+                    "an OP_SITE_LABEL does not follow a success pseudo opcode and its label"
+                )
+            }
+            CompileError::InvalidEpilogue(e) => write!(f, "invalid success epilogue: {}", e),
+            CompileError::AltstackInUse => write!(
+                f,
+                "the altstack lowering strategy cannot be used with a script or an epilogue that uses the altstack"
+            ),
+            CompileError::NestingTooDeep { depth, max } => write!(
+                f,
+                "the compiled script nests conditionals {} deep, more than the maximum of {}",
+                depth, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileWarning {
+    /// With `FallThrough::AlwaysFail`, the script can terminate normally without an
+    /// `OP_RETURN`, so it could have succeeded without the failure that the compiler appends.
+    FallThroughMaySucceed,
+    /// A success site that can never succeed, because it follows a failure or a success, or
+    /// a constant condition does not lead to it, see `crate::dead_code`. The index is the
+    /// position of the site among the success sites of the input script.
+    DeadSuccessSite { index: usize, label: Option<String> },
+    /// With `CompileOptions::witness_elements`, the compiled script may hold more than
+    /// `MAX_STACK_ELEMENTS` elements on the main stack and the altstack together, counting the
+    /// flags and the success code, see `crate::stack_limit`. The position and the path are
+    /// those of the first point where the input script holds the most elements.
+    StackLimitExceeded {
+        elements: usize,
+        input_elements: usize,
+        position: usize,
+        path: Vec<BranchTaken>,
+    },
+    /// With `CompileOptions::witness_elements`, the limit of `MAX_STACK_ELEMENTS` elements could
+    /// not be checked, because the effect of the opcode at `position` of the input script, or of
+    /// the compiled script if the input script does not reach it, is not known, see
+    /// `crate::stack_limit`. `CompileReport::max_stack_elements` is then `None`.
+    StackLimitUnchecked { position: usize, opcode: Opcode },
+}
+
+impl Display for CompileWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileWarning::FallThroughMaySucceed => write!(
+                f,
+                "the script can terminate normally with a stack that may succeed, which now always fails"
+            ),
+            CompileWarning::DeadSuccessSite { index, label } => {
+                write!(f, "the success site {}", index)?;
+                if let Some(label) = label {
+                    write!(f, " ({})", label)?;
+                }
+                write!(f, " can never succeed and has been removed")
+            }
+            CompileWarning::StackLimitExceeded {
+                elements,
+                input_elements,
+                position,
+                path,
+            } => {
+                write!(
+                    f,
+                    "the compiled script may hold {} elements on its stacks, more than the limit of {}, \
+                     and the script holds {} of them before the instruction {}",
+                    elements, MAX_STACK_ELEMENTS, input_elements, position
+                )?;
+                for (i, taken) in path.iter().enumerate() {
+                    let separator = if i == 0 { ", through" } else { "," };
+                    write!(
+                        f,
+                        "{} the branch {} of the instruction {}",
+                        separator, taken.branch, taken.position
+                    )?;
+                }
+                Ok(())
+            }
+            CompileWarning::StackLimitUnchecked { position, opcode } => write!(
+                f,
+                "the stack limit of {} elements could not be checked, as the effect of {} at {} is not known",
+                MAX_STACK_ELEMENTS, opcode, position
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileReport {
+    /// Size of the input script in bytes.
+    pub input_size: usize,
+    /// Size of the compiled script in bytes.
+    pub output_size: usize,
+    /// Number of `OP_RETURN_TRUE`/`OP_IF_RETURN_TRUE`/`OP_NOTIF_RETURN_TRUE` that survive the
+    /// code cleanup.
+    pub success_sites: usize,
+    /// Labels of these success sites, in the order of the script, with `None` for the sites
+    /// without a label.
+    pub site_labels: Vec<Option<String>>,
+    /// Whether the result was taken from the cache instead of being compiled.
+    pub cache_hit: bool,
+    /// Reuse of reductions, if `CompileOptions::reduction_memo` is set.
+    pub incremental: IncrementalStats,
+    /// Suspicious constructs found in the script, which do not prevent the compilation.
+    pub warnings: Vec<CompileWarning>,
+    /// Lowerings of the script, the one of `CompileOptions::strategy` first, or the one chosen
+    /// for `LoweringStrategy::Auto`, followed by the ones it is compared with: the other ones
+    /// of `Auto`, or the default one with `CompileOptions::compare_with_nested`. The script
+    /// lowered region by region is reported with the strategy `Auto`.
+    pub lowerings: Vec<LoweringReport>,
+    /// Lowerings chosen for the top-level regions of the script, in the order of the script,
+    /// if `LoweringStrategy::Auto` chose to lower it region by region. Every report is for the
+    /// region alone, without the guard around it.
+    pub regions: Vec<LoweringReport>,
+    /// Flag blocks of the chosen lowering whose tail could be duplicated into the branches
+    /// before them, and whether it was, see `crate::flag_block`.
+    pub tails: Vec<TailChoice>,
+    /// How the success code drops the elements on the main stack.
+    pub stack_cleanup: StackCleanup,
+    /// Most elements on the main stack and the altstack together through the compiled script,
+    /// if `CompileOptions::witness_elements` is set and the depths can be followed, see
+    /// `crate::stack_limit`.
+    pub max_stack_elements: Option<usize>,
+}
+
+/// How the success flag is carried from the success sites to the final emit code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoweringStrategy {
+    /// The flag is kept on the main stack, and the code after a success site is nested in
+    /// the branch where the site did not succeed, see `crate::reduce`.
+    #[default]
+    Nested,
+    /// The flag is kept on the altstack, and the code after a success site is guarded by
+    /// reading it back, see `crate::altstack_flag`. The script must not use the altstack.
+    Altstack,
+    /// The flag is kept on the main stack, and the code after a success site is guarded by
+    /// it without nesting, see `crate::flat_guard`.
+    FlatGuard,
+    /// The script is lowered with each of the strategies above that applies to it, and also
+    /// region by region: every top-level region, which ends with a conditional or a sequence
+    /// with success sites, is lowered with `Nested` and `FlatGuard`, which both keep the flag
+    /// on top of the main stack, and the cheaper one is kept for it. The regions after the
+    /// first one are guarded by the flag, as the segments of `FlatGuard` are.
+    ///
+    /// The lowering with the lowest cost under `CompileOptions::cost_model` is kept, among
+    /// those within `CompileOptions::max_nesting_depth` if any.
+    Auto,
+}
+
+/// A script, or one of its regions, lowered with a strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoweringReport {
+    pub strategy: LoweringStrategy,
+    /// Size of the script lowered with the strategy, in bytes.
+    pub size: usize,
+    /// Deepest nesting of `OP_IF`/`OP_NOTIF` in the script lowered with the strategy.
+    pub nesting_depth: usize,
+    /// Cost of the script lowered with the strategy, under `CompileOptions::cost_model`.
+    pub cost: u64,
+}
+
+/// Options of the compilation.
+///
+/// Every option that affects the compiled script must be covered by `fingerprint`, which is
+/// part of the cache key.
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Cache of compiled scripts, see `crate::cache`. Compiled scripts are looked up and
+    /// stored by a hash of the input script and the options.
+    pub cache: Option<Arc<dyn CompileCache>>,
+    /// Memo of reductions of conditionals, see `crate::incremental`. It can be shared across
+    /// compilations of similar scripts and does not affect the compiled script.
+    pub reduction_memo: Option<Arc<ReductionMemo>>,
+    /// Code that decides whether a success is accepted, see `SuccessEpilogue`.
+    pub epilogue: SuccessEpilogue,
+    /// What happens when the script terminates normally, see `FallThrough`.
+    pub fall_through: FallThrough,
+    /// How the success flag is lowered, see `LoweringStrategy`.
+    pub strategy: LoweringStrategy,
+    /// Maximum nesting of `OP_IF`/`OP_NOTIF` in the compiled script, including the final emit
+    /// code, beyond which the compilation fails with `CompileError::NestingTooDeep`.
+    pub max_nesting_depth: Option<usize>,
+    /// Maximum size in bytes of the code duplicated into several branches instead of
+    /// threading the success flag through it, see `crate::flag_block`. Defaults to
+    /// `crate::flag_block::DEFAULT_MAX_TAIL_SIZE`.
+    pub max_tail_size: Option<usize>,
+    /// Weights of the size and the executed opcodes, by which the lowerings are chosen.
+    pub cost_model: CostModel,
+    /// Number of elements of the witness, which are on the main stack when the script starts.
+    /// If it is set and the depth of the main stack at every success site can be proved, the
+    /// success code only drops as many elements as there can be, see `StackCleanup`.
+    pub witness_elements: Option<usize>,
+    /// Most elements on the main stack at a success site, as declared by the caller. If the
+    /// depths at the success sites cannot be proved within it, the success code checks it at
+    /// runtime, and fails if there are more.
+    pub max_stack_depth: Option<usize>,
+    /// Whether `CompileReport::lowerings` compares a lowering with any strategy other than the
+    /// default one with the default one, which lowers the script a second time.
+    pub compare_with_nested: bool,
+}
+
+impl CompileOptions {
+    /// Encode the options that affect the compiled script, for the cache key.
+    pub fn fingerprint(&self) -> Vec<u8> {
+        let mut res = vec![];
+
+        let epilogue = self.epilogue.script().as_bytes();
+        res.extend_from_slice(&(epilogue.len() as u64).to_le_bytes());
+        res.extend_from_slice(epilogue);
+        res.extend_from_slice(&(self.epilogue.altstack_inputs() as u64).to_le_bytes());
+        res.push(self.fall_through as u8);
+        res.push(self.strategy as u8);
+        res.push(self.compare_with_nested as u8);
+        // a script over the maximum is never cached, but one under it must not be returned
+        // for a lower maximum
+        res.extend_from_slice(&(self.max_nesting_depth.unwrap_or(usize::MAX) as u64).to_le_bytes());
+        res.extend_from_slice(&(self.max_tail_size() as u64).to_le_bytes());
+        res.extend_from_slice(&self.cost_model.size_weight.to_le_bytes());
+        res.extend_from_slice(&self.cost_model.opcode_weight.to_le_bytes());
+        for bound in [self.witness_elements, self.max_stack_depth] {
+            match bound {
+                None => res.push(0),
+                Some(bound) => {
+                    res.push(1);
+                    res.extend_from_slice(&(bound as u64).to_le_bytes());
+                }
+            }
+        }
+
+        res
+    }
+
+    fn max_tail_size(&self) -> usize {
+        self.max_tail_size.unwrap_or(DEFAULT_MAX_TAIL_SIZE)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledScript {
+    pub script: ScriptBuf,
+    pub report: CompileReport,
+}
+
+/// Compile a script with pseudo opcodes into a plain Bitcoin script, with the default options.
+pub fn compile(script: &Script) -> Result<CompiledScript, CompileError> {
+    compile_with_options(script, &CompileOptions::default())
+}
+
+/// Compile a script with pseudo opcodes into a plain Bitcoin script.
+///
+/// This runs the full pipeline: code cleanup, conversion into `OP_IF_RETURN_TRUE`, the
+/// reduction, and, if the script has any success site left, the final emit code.
+pub fn compile_with_options(
+    script: &Script,
+    options: &CompileOptions,
+) -> Result<CompiledScript, CompileError> {
+    let Some(cache) = options.cache.as_ref() else {
+        return compile_uncached(script, options);
+    };
+
+    let key = cache_key(script, options);
+    if let Some(mut compiled) = cache.get(&key) {
+        compiled.report.cache_hit = true;
+        return Ok(compiled);
+    }
+
+    let compiled = compile_uncached(script, options)?;
+    cache.put(&key, &compiled);
+    Ok(compiled)
+}
+
+/// Compile independent scripts, such as the leaves of a taptree, with the same options, and
+/// return the results in the order of the scripts.
+///
+/// With the `parallel` feature, the scripts are compiled on all cores. The compiled scripts are
+/// the same as with `compile_with_options` one after the other, whatever the number of
+/// threads. The scripts compiled at the same time share the cache and the `ReductionMemo`,
+/// which is only locked for each lookup and record, so whether one of them finds what another
+/// is adding, and hence `cache_hit` and `CompileReport::incremental`, depends on the timing.
+pub fn compile_many<S: AsRef<Script> + Sync>(
+    scripts: &[S],
+    options: &CompileOptions,
+) -> Vec<Result<CompiledScript, CompileError>> {
+    parallel::map(scripts.iter().collect(), |script| {
+        compile_with_options(script.as_ref(), options)
+    })
+}
+
+fn compile_uncached(
+    script: &Script,
+    options: &CompileOptions,
+) -> Result<CompiledScript, CompileError> {
+    let altstack_available = check_lowering(script, options)?;
+    let (structured_script, dead_sites) = prepare(script)?;
+
+    let site_labels = site_labels(&structured_script);
+
+    let mut warnings: Vec<_> = dead_sites
+        .into_iter()
+        .map(|site| CompileWarning::DeadSuccessSite {
+            index: site.index,
+            label: site.label,
+        })
+        .collect();
+    if options.fall_through == FallThrough::AlwaysFail && can_terminate_normally(&structured_script)
+    {
+        warnings.push(CompileWarning::FallThroughMaySucceed);
+    }
+
+    let stack_cleanup = stack_cleanup(&structured_script, options);
+
+    let mut incremental = IncrementalStats::default();
+    let lowering = lower(
+        structured_script.clone(),
+        options,
+        altstack_available,
+        stack_cleanup,
+        &mut incremental,
+    );
+    let mut lowerings = vec![lowering.report];
+    lowerings.extend(lowering.alternatives);
+    if options.compare_with_nested
+        && !lowerings
+            .iter()
+            .any(|lowering| lowering.strategy == LoweringStrategy::Nested)
+    {
+        // without the memo, so that the comparison does not fill it
+        let nested_options = CompileOptions {
+            strategy: LoweringStrategy::Nested,
+            reduction_memo: None,
+            ..options.clone()
+        };
+        let nested = lower_with_strategy(
+            structured_script,
+            &nested_options,
+            stack_cleanup,
+            &mut IncrementalStats::default(),
+        );
+        lowerings.push(nested.report);
+    }
+    let compiled = lowering.script;
+
+    if let Some(max) = options.max_nesting_depth {
+        if lowerings[0].nesting_depth > max {
+            return Err(CompileError::NestingTooDeep {
+                depth: lowerings[0].nesting_depth,
+                max,
+            });
+        }
+    }
+
+    let mut max_stack_elements = None;
+    if let Some(witness_elements) = options.witness_elements {
+        match check_stack_limit(script, &compiled, witness_elements) {
+            Ok(elements) => max_stack_elements = Some(elements),
+            Err(StackLimitError::Exceeded {
+                elements,
+                input_elements,
+                position,
+                path,
+            }) => {
+                max_stack_elements = Some(elements);
+                warnings.push(CompileWarning::StackLimitExceeded {
+                    elements,
+                    input_elements,
+                    position,
+                    path,
+                });
+            }
+            Err(StackLimitError::UnknownEffect { position, opcode }) => {
+                warnings.push(CompileWarning::StackLimitUnchecked { position, opcode });
+            }
+        }
+    }
+
+    Ok(CompiledScript {
+        report: CompileReport {
+            input_size: script.len(),
+            output_size: compiled.len(),
+            success_sites: site_labels.len(),
+            site_labels,
+            cache_hit: false,
+            incremental,
+            warnings,
+            lowerings,
+            regions: lowering.regions,
+            tails: lowering.tails,
+            stack_cleanup,
+            max_stack_elements,
+        },
+        script: compiled,
+    })
+}
+
+/// Check the script, convert it into a `StructuredScript`, remove the dead code, and run the
+/// code cleanup. The success sites removed as dead code are returned with it.
+pub(crate) fn prepare(script: &Script) -> Result<(StructuredScript, Vec<DeadSite>), CompileError> {
+    check_conditionals(script)?;
+    check_site_labels(script)?;
+
+    let mut structured_script: StructuredScript = script.to_owned().into();
+    let dead_sites = eliminate_dead_code(&mut structured_script);
+    find_op_return_true_cleanup(&mut structured_script);
+    Ok((structured_script, dead_sites))
+}
+
+/// Check that the script can be lowered with the options, and return whether the altstack is
+/// left to the success flag, which both `LoweringStrategy::Altstack` and
+/// `LoweringStrategy::Auto` then rely on.
+pub(crate) fn check_lowering(
+    script: &Script,
+    options: &CompileOptions,
+) -> Result<bool, CompileError> {
+    let altstack_available =
+        check_altstack_unused(script) && options.epilogue.altstack_inputs() == 0;
+    if options.strategy == LoweringStrategy::Altstack && !altstack_available {
+        return Err(CompileError::AltstackInUse);
+    }
+    Ok(altstack_available)
+}
+
+/// A script returned by `prepare`, lowered into a plain Bitcoin script.
+pub(crate) struct Lowering {
+    pub script: ScriptBuf,
+    pub report: LoweringReport,
+    pub tails: Vec<TailChoice>,
+    /// Other lowerings considered for `LoweringStrategy::Auto`.
+    pub alternatives: Vec<LoweringReport>,
+    /// Lowerings chosen for the regions of the script, if it was lowered region by region.
+    pub regions: Vec<LoweringReport>,
+}
+
+/// Lower a script returned by `prepare` into a plain Bitcoin script, where the success code
+/// drops the elements of the main stack with `cleanup`, see `stack_cleanup`.
+/// `altstack_available` is the result of `check_lowering` on the input script.
+pub(crate) fn lower(
+    structured_script: StructuredScript,
+    options: &CompileOptions,
+    altstack_available: bool,
+    cleanup: StackCleanup,
+    incremental: &mut IncrementalStats,
+) -> Lowering {
+    if options.strategy != LoweringStrategy::Auto {
+        return lower_with_strategy(structured_script, options, cleanup, incremental);
+    }
+
+    // the whole script with every strategy, and region by region on the main stack, which is
+    // reported as `Auto`
+    let mut strategies = vec![
+        LoweringStrategy::Nested,
+        LoweringStrategy::FlatGuard,
+        LoweringStrategy::Auto,
+    ];
+    if altstack_available {
+        strategies.push(LoweringStrategy::Altstack);
+    }
+
+    // the strategies are independent, and lowered in parallel
+    let mut lowerings = vec![];
+    for (lowering, stats) in parallel::map(strategies, |strategy| {
+        let mut stats = IncrementalStats::default();
+        let lowering = if strategy == LoweringStrategy::Auto {
+            lower_by_region(structured_script.clone(), options, cleanup, &mut stats)
+        } else {
+            let options = CompileOptions {
+                strategy,
+                ..options.clone()
+            };
+            Some(lower_with_strategy(
+                structured_script.clone(),
+                &options,
+                cleanup,
+                &mut stats,
+            ))
+        };
+        (lowering, stats)
+    }) {
+        lowerings.extend(lowering);
+        incremental.add(stats);
+    }
+
+    let (chosen, _) = lowerings
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, lowering)| (too_deep(&lowering.report, 0, options), lowering.report.cost))
+        .unwrap();
+    let mut lowering = lowerings.remove(chosen);
+    lowering.alternatives = lowerings.into_iter().map(|other| other.report).collect();
+    lowering
+}
+
+/// Whether a lowering, nested in `guards` conditionals, goes beyond
+/// `CompileOptions::max_nesting_depth`. The first of the cheapest lowerings is chosen, among
+/// those within the maximum nesting if any.
+fn too_deep(report: &LoweringReport, guards: usize, options: &CompileOptions) -> bool {
+    options
+        .max_nesting_depth
+        .is_some_and(|max| report.nesting_depth + guards > max)
+}
+
+fn lower_with_strategy(
+    mut structured_script: StructuredScript,
+    options: &CompileOptions,
+    cleanup: StackCleanup,
+    incremental: &mut IncrementalStats,
+) -> Lowering {
+    op_return_true_to_op_if_return_true(&mut structured_script);
+    let emit_result = lower_sites(
+        &mut structured_script,
+        options.strategy,
+        options,
+        incremental,
+    );
+    finish_lowering(structured_script, emit_result, options, cleanup)
+}
+
+/// Lower the top-level regions of the script each with the cheapest of
+/// `LoweringStrategy::Nested` and `LoweringStrategy::FlatGuard`, which both leave the flag on
+/// top of the main stack, so that every region after the first one is guarded by it as the
+/// segments of `crate::flat_guard` are. A script with a single region is left to the
+/// lowerings of the whole script.
+fn lower_by_region(
+    mut structured_script: StructuredScript,
+    options: &CompileOptions,
+    cleanup: StackCleanup,
+    incremental: &mut IncrementalStats,
+) -> Option<Lowering> {
+    op_return_true_to_op_if_return_true(&mut structured_script);
+    let (regions, tail) = split_regions(structured_script);
+    if regions.len() < 2 {
+        return None;
+    }
+
+    let jobs: Vec<_> = regions
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, region)| {
+            [LoweringStrategy::Nested, LoweringStrategy::FlatGuard]
+                .map(|strategy| (i, strategy, region.clone()))
+        })
+        .collect();
+    // the regions are independent, and lowered in parallel
+    let lowered = parallel::map(jobs, |(i, strategy, mut region)| {
+        let mut stats = IncrementalStats::default();
+        if lower_sites(&mut region, strategy, options, &mut stats) == EmitOpIfSuccess::NO {
+            region = StructuredScript::MultiScript(vec![region, script(vec![OP_0])]);
+        }
+
+        // measured as it ends up in the compiled script
+        let mut measured = region.clone();
+        strip_site_labels(&mut measured);
+        hoist_common_code(&mut measured);
+        peephole_optimize(&mut measured);
+        let cost = options.cost_model.cost(&measured);
+        let measured: ScriptBuf = measured.into();
+        let report = LoweringReport {
+            strategy,
+            size: measured.len(),
+            nesting_depth: nesting_depth(&measured),
+            cost,
+        };
+        (i, region, report, stats)
+    });
+
+    let mut chosen: Vec<(StructuredScript, LoweringReport)> = vec![];
+    for (i, region, report, stats) in lowered {
+        incremental.add(stats);
+        // every region but the first one is nested in its guard
+        let key = |report: &LoweringReport| (too_deep(report, i.min(1), options), report.cost);
+        if i == chosen.len() {
+            chosen.push((region, report));
+        } else if key(&report) < key(&chosen[i].1) {
+            chosen[i] = (region, report);
+        }
+    }
+
+    let (regions, reports): (Vec<_>, Vec<_>) = chosen.into_iter().unzip();
+    let structured_script = join_flat_guarded(regions, tail);
+    let mut lowering = finish_lowering(structured_script, EmitOpIfSuccess::YES, options, cleanup);
+    lowering.regions = reports;
+    Some(lowering)
+}
+
+/// Split a script into regions, each of which ends with a top-level child with success sites,
+/// and the code after the last of them.
+fn split_regions(
+    structured_script: StructuredScript,
+) -> (Vec<StructuredScript>, Option<StructuredScript>) {
+    let children = match structured_script.into_node() {
+        Node::MultiScript(vv) => vv,
+        node => vec![node.into()],
+    };
+    let join = |mut vv: Vec<StructuredScript>| {
+        if vv.len() == 1 {
+            vv.pop().unwrap()
+        } else {
+            StructuredScript::MultiScript(vv)
+        }
+    };
+
+    let mut regions = vec![];
+    let mut region = vec![];
+    for child in children {
+        let has_sites = child.contains_pseudo_opcodes();
+        region.push(child);
+        if has_sites {
+            regions.push(join(std::mem::take(&mut region)));
+        }
+    }
+    let tail = (!region.is_empty()).then(|| join(region));
+    (regions, tail)
+}
+
+/// Lower the success sites of a script, after `op_return_true_to_op_if_return_true`, with
+/// `strategy`.
+fn lower_sites(
+    structured_script: &mut StructuredScript,
+    strategy: LoweringStrategy,
+    options: &CompileOptions,
+    incremental: &mut IncrementalStats,
+) -> EmitOpIfSuccess {
+    match (strategy, options.reduction_memo.as_ref()) {
+        (LoweringStrategy::Altstack, _) => lower_with_altstack_flag(structured_script),
+        (LoweringStrategy::FlatGuard, _) => lower_with_flat_guards(structured_script),
+        (LoweringStrategy::Nested, Some(memo)) => {
+            reduce_incremental(structured_script, memo, incremental)
+        }
+        (LoweringStrategy::Nested, None) => reduce(structured_script),
+        (LoweringStrategy::Auto, _) => unreachable!(),
+    }
+}
+
+/// Append the final emit code to a script whose success sites have been lowered, optimize
+/// it, and encode it.
+fn finish_lowering(
+    mut structured_script: StructuredScript,
+    emit_result: EmitOpIfSuccess,
+    options: &CompileOptions,
+    cleanup: StackCleanup,
+) -> Lowering {
+    let tails = optimize_flag_blocks(
+        &mut structured_script,
+        options.max_tail_size(),
+        &options.cost_model,
+    );
+    if emit_result == EmitOpIfSuccess::YES {
+        append_final_emit_script(
+            &mut structured_script,
+            &options.epilogue,
+            options.fall_through,
+            cleanup,
+        );
+    } else if options.fall_through == FallThrough::AlwaysFail
+        && can_terminate_normally(&structured_script)
+    {
+        append_failure_tail(&mut structured_script);
+    }
+    strip_site_labels(&mut structured_script);
+    hoist_common_code(&mut structured_script);
+    peephole_optimize(&mut structured_script);
+
+    let cost = options.cost_model.cost(&structured_script);
+    let script: ScriptBuf = structured_script.into();
+    Lowering {
+        report: LoweringReport {
+            strategy: options.strategy,
+            size: script.len(),
+            nesting_depth: nesting_depth(&script),
+            cost,
+        },
+        script,
+        tails,
+        alternatives: vec![],
+        regions: vec![],
+    }
+}
+
+/// Check that the script decodes and that its conditionals and switches are well formed, so
+/// that the conversion into `StructuredScript` would not panic.
+fn check_conditionals(script: &Script) -> Result<(), CompileError> {
+    enum Block {
+        // whether an OP_ELSE has been seen
+        If(bool),
+        // the number of OP_CASE seen
+        Switch(usize),
+    }
+    let mut open = vec![];
+
+    for inst in script.instructions() {
+        let inst = inst.map_err(|e| CompileError::InvalidScript(e.to_string()))?;
+
+        // the first arm of a switch must start right after OP_SWITCH
+        if matches!(open.last(), Some(Block::Switch(0))) && inst != Instruction::Op(_OP_CASE) {
+            return Err(CompileError::MalformedSwitch);
+        }
+
+        if let Instruction::Op(op) = inst {
+            if op == OP_IF || op == OP_NOTIF {
+                open.push(Block::If(false));
+            } else if op == OP_ELSE {
+                match open.last_mut() {
+                    Some(Block::If(true)) => return Err(CompileError::MultipleElse),
+                    Some(Block::If(seen_else)) => *seen_else = true,
+                    _ => return Err(CompileError::UnbalancedConditional),
+                }
+            } else if op == OP_ENDIF {
+                match open.pop() {
+                    Some(Block::If(_)) => {}
+                    _ => return Err(CompileError::UnbalancedConditional),
+                }
+            } else if op == _OP_SWITCH {
+                open.push(Block::Switch(0));
+            } else if op == _OP_CASE {
+                match open.last_mut() {
+                    Some(Block::Switch(arms)) => *arms += 1,
+                    _ => return Err(CompileError::MalformedSwitch),
+                }
+            } else if op == _OP_ENDSWITCH {
+                match open.pop() {
+                    Some(Block::Switch(arms)) if arms > 0 => {}
+                    _ => return Err(CompileError::MalformedSwitch),
+                }
+            }
+        }
+    }
+
+    match open.last() {
+        None => Ok(()),
+        Some(Block::If(_)) => Err(CompileError::UnbalancedConditional),
+        Some(Block::Switch(_)) => Err(CompileError::MalformedSwitch),
+    }
+}
+
+/// How the success code can drop the elements on the main stack, from the depths at the
+/// success sites of a script returned by `prepare`.
+pub(crate) fn stack_cleanup(
+    structure: &StructuredScript,
+    options: &CompileOptions,
+) -> StackCleanup {
+    let depths = options.witness_elements.and_then(|witness_elements| {
+        analyze_stack_depths(structure)
+            .ok()?
+            .site_depths(witness_elements)
+    });
+    let within_bound = |depth: usize| options.max_stack_depth.is_none_or(|max| depth <= max);
+
+    match (depths, options.max_stack_depth) {
+        (Some(SiteDepths { min, max }), _) if min == max && within_bound(max) => {
+            StackCleanup::Exact(max)
+        }
+        (Some(SiteDepths { max, .. }), _) if within_bound(max) => StackCleanup::Bounded(max),
+        // the full cascade already drops anything the stack can hold
+        (_, Some(max)) if max < MAX_STACK_ELEMENTS => StackCleanup::Guarded(max),
+        _ => StackCleanup::Full,
+    }
+}
+
+/// Deepest nesting of `OP_IF`/`OP_NOTIF` in a script with balanced conditionals.
+fn nesting_depth(script: &Script) -> usize {
+    let mut depth = 0usize;
+    let mut max_depth = 0;
+    for inst in script.instructions().flatten() {
+        if inst == Instruction::Op(OP_IF) || inst == Instruction::Op(OP_NOTIF) {
+            depth += 1;
+            max_depth = max_depth.max(depth);
+        } else if inst == Instruction::Op(OP_ENDIF) {
+            depth = depth.saturating_sub(1);
+        }
+    }
+    max_depth
+}
+
+/// Whether the script can reach its end without an `OP_RETURN` or an `OP_RETURN_TRUE`.
+fn can_terminate_normally(structure: &StructuredScript) -> bool {
+    structure.fold_ref(|structure, children| match structure {
+        StructuredScript::Script(v) => !v.0.iter().any(|inst| {
+            *inst == OwnedInstruction::Op(OP_RETURN)
+                || *inst == OwnedInstruction::Op(_OP_RETURN_TRUE)
+        }),
+        StructuredScript::MultiScript(_) => children.into_iter().all(|res| res),
+        StructuredScript::IfEndIf(_) | StructuredScript::NotIfEndIf(_) => true,
+        StructuredScript::IfElseEndIf(..)
+        | StructuredScript::NotIfElseEndIf(..)
+        | StructuredScript::Switch(_) => children.into_iter().any(|res| res),
+    })
+}
+
+/// Check that every `OP_SITE_LABEL` follows a success pseudo opcode and the push of its label.
+fn check_site_labels(script: &Script) -> Result<(), CompileError> {
+    // the script has been decoded by `check_conditionals`
+    let instructions = script
+        .instructions()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| CompileError::InvalidScript(e.to_string()))?;
+
+    for (i, inst) in instructions.iter().enumerate() {
+        if *inst == Instruction::Op(_OP_SITE_LABEL) {
+            let is_labeled_site = i >= 2
+                && matches!(instructions[i - 1], Instruction::PushBytes(bytes) if !bytes.is_empty())
+                && matches!(instructions[i - 2], Instruction::Op(op) if is_success_site(op));
+            if !is_labeled_site {
+                return Err(CompileError::MisplacedSiteLabel);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compile::{
+        compile, compile_with_options, CompileError, CompileOptions, LoweringStrategy,
+    };
+    use crate::{
+        OP_CASE, OP_ENDSWITCH, OP_IF_RETURN_TRUE, OP_RETURN_TRUE, OP_SITE_LABEL, OP_SWITCH,
+    };
+    use bitcoin::ScriptBuf;
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_compile() {
+        let script = script! {
+            OP_DUP 10001 OP_EQUAL
+            OP_IF
+                OP_RETURN_TRUE
+            OP_ENDIF
+            OP_DROP
+            OP_RETURN
+        };
+
+        let compiled = compile(&script).unwrap();
+        assert_eq!(compiled.report.input_size, script.len());
+        assert_eq!(compiled.report.output_size, compiled.script.len());
+        assert_eq!(compiled.report.success_sites, 1);
+
+        let labeled = script! {
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("hash_mismatch") }
+            OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_RETURN
+        };
+        let compiled = compile(&labeled).unwrap();
+        assert_eq!(
+            compiled.report.site_labels,
+            vec![Some("hash_mismatch".to_string()), None]
+        );
+        assert_eq!(
+            compiled.script,
+            compile(&script! {
+                OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+                OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+                OP_RETURN
+            })
+            .unwrap()
+            .script
+        );
+
+        let no_pseudo_opcodes = script! { OP_DUP OP_EQUAL };
+        let compiled = compile(&no_pseudo_opcodes).unwrap();
+        assert_eq!(compiled.script, no_pseudo_opcodes);
+        assert_eq!(compiled.report.success_sites, 0);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let script = script! { OP_IF OP_RETURN_TRUE };
+        assert_eq!(compile(&script), Err(CompileError::UnbalancedConditional));
+
+        let script = script! { OP_ELSE };
+        assert_eq!(compile(&script), Err(CompileError::UnbalancedConditional));
+
+        let script = script! { OP_IF OP_ELSE OP_ELSE OP_ENDIF };
+        assert_eq!(compile(&script), Err(CompileError::MultipleElse));
+
+        let script = script! { OP_SWITCH OP_DROP OP_CASE OP_ENDSWITCH };
+        assert_eq!(compile(&script), Err(CompileError::MalformedSwitch));
+
+        let script = script! { OP_IF OP_SWITCH OP_CASE OP_ENDIF OP_ENDSWITCH };
+        assert_eq!(compile(&script), Err(CompileError::UnbalancedConditional));
+
+        let script = script! { OP_DUP { OP_SITE_LABEL("hash_mismatch") } };
+        assert_eq!(compile(&script), Err(CompileError::MisplacedSiteLabel));
+
+        let options = CompileOptions {
+            strategy: LoweringStrategy::Altstack,
+            ..Default::default()
+        };
+        let script = script! { OP_TOALTSTACK OP_IF_RETURN_TRUE OP_RETURN };
+        assert_eq!(
+            compile_with_options(&script, &options),
+            Err(CompileError::AltstackInUse)
+        );
+
+        // OP_PUSHBYTES_2 with only one byte following
+        let script = ScriptBuf::from_bytes(vec![0x02, 0x01]);
+        assert!(matches!(
+            compile(&script),
+            Err(CompileError::InvalidScript(_))
+        ));
+    }
+}
@@ -0,0 +1,310 @@
+//! Evaluation of the constants that a script computes without reading the stack.
+//!
+//! The values follow the consensus rules: a number is at most 4 bytes, little-endian, with
+//! the sign in the highest bit of the last byte, and an element is false under CastToBool if
+//! all its bytes are zero, except for a last byte of `0x80`, which is negative zero. A number
+//! that is not minimally encoded is not evaluated, as it fails under MINIMALDATA.
+
+use crate::structured_script::OwnedInstruction;
+use bitcoin::opcodes::all::{
+    OP_0NOTEQUAL, OP_1ADD, OP_1SUB, OP_ABS, OP_ADD, OP_BOOLAND, OP_BOOLOR, OP_EQUAL,
+    OP_GREATERTHAN, OP_GREATERTHANOREQUAL, OP_LESSTHAN, OP_LESSTHANOREQUAL, OP_MAX, OP_MIN,
+    OP_NEGATE, OP_NOT, OP_NUMEQUAL, OP_NUMNOTEQUAL, OP_PUSHBYTES_0, OP_PUSHNUM_1, OP_PUSHNUM_16,
+    OP_PUSHNUM_NEG1, OP_SUB, OP_WITHIN,
+};
+use bitcoin::Opcode;
+
+/// Most instructions read back from a condition to find the constant it computes.
+const MAX_CONSTANT_INSTRUCTIONS: usize = 32;
+
+/// Most bytes of a number read by the arithmetic opcodes.
+const MAX_NUM_SIZE: usize = 4;
+
+/// Whether an element is true, as in `CastToBool`.
+pub fn cast_to_bool(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        None => false,
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+    }
+}
+
+/// Whether a condition of `OP_IF`/`OP_NOTIF` is true, if it is one that MINIMALIF accepts,
+/// which is only empty or `0x01`.
+pub fn minimal_truth(bytes: &[u8]) -> Option<bool> {
+    match bytes {
+        [] => Some(false),
+        [1] => Some(true),
+        _ => None,
+    }
+}
+
+/// Decode a minimally encoded number of at most 4 bytes.
+pub fn decode_script_num(bytes: &[u8]) -> Option<i64> {
+    let (last, rest) = match bytes.split_last() {
+        None => return Some(0),
+        Some(split) => split,
+    };
+    if bytes.len() > MAX_NUM_SIZE {
+        return None;
+    }
+    // the last byte only holds the sign if the one before it needs its highest bit
+    if *last & 0x7f == 0 && rest.last().is_none_or(|b| *b & 0x80 == 0) {
+        return None;
+    }
+
+    let mut magnitude = (*last & 0x7f) as i64;
+    for b in rest.iter().rev() {
+        magnitude = (magnitude << 8) | *b as i64;
+    }
+    Some(if *last & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    })
+}
+
+/// Encode a number minimally.
+pub fn encode_script_num(n: i64) -> Vec<u8> {
+    let mut res = vec![];
+    let mut magnitude = n.unsigned_abs();
+    while magnitude != 0 {
+        res.push((magnitude & 0xff) as u8);
+        magnitude >>= 8;
+    }
+    if let Some(last) = res.last_mut() {
+        if *last & 0x80 != 0 {
+            res.push(if n < 0 { 0x80 } else { 0 });
+        } else if n < 0 {
+            *last |= 0x80;
+        }
+    }
+    res
+}
+
+/// The element pushed by an instruction, if it is a push.
+pub fn pushed_value(inst: &OwnedInstruction) -> Option<Vec<u8>> {
+    match inst {
+        OwnedInstruction::PushBytes(v) => Some(v.clone()),
+        OwnedInstruction::Op(op) if *op == OP_PUSHBYTES_0 => Some(vec![]),
+        OwnedInstruction::Op(op) if *op == OP_PUSHNUM_NEG1 => Some(encode_script_num(-1)),
+        OwnedInstruction::Op(op)
+            if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) =>
+        {
+            Some(encode_script_num(
+                (op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as i64,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Find the longest suffix of the instructions that pushes a single constant without reading
+/// the stack, such as `2 3 OP_ADD 5 OP_EQUAL`, and return where it starts and the constant.
+pub fn constant_suffix(instructions: &[OwnedInstruction]) -> Option<(usize, Vec<u8>)> {
+    let mut res = None;
+    let min_start = instructions.len().saturating_sub(MAX_CONSTANT_INSTRUCTIONS);
+    for start in (min_start..instructions.len()).rev() {
+        if pushed_value(&instructions[start]).is_none() && !is_evaluated(&instructions[start]) {
+            break;
+        }
+        if let Some(mut stack) = evaluate(&instructions[start..]) {
+            if stack.len() == 1 {
+                res = Some((start, stack.pop().unwrap()));
+            }
+        }
+    }
+    res
+}
+
+/// Run the instructions on an empty stack, and return the stack, or `None` if they read
+/// below it, fail, or use an opcode that is not evaluated.
+pub fn evaluate(instructions: &[OwnedInstruction]) -> Option<Vec<Vec<u8>>> {
+    let mut stack: Vec<Vec<u8>> = vec![];
+    for inst in instructions {
+        if let Some(value) = pushed_value(inst) {
+            stack.push(value);
+            continue;
+        }
+        let OwnedInstruction::Op(op) = inst else {
+            return None;
+        };
+        let op = *op;
+
+        if op == OP_EQUAL {
+            let b = stack.pop()?;
+            let a = stack.pop()?;
+            stack.push(bool_value(a == b));
+        } else if let Some(f) = unary(op) {
+            let a = decode_script_num(&stack.pop()?)?;
+            stack.push(encode_script_num(f(a)));
+        } else if let Some(f) = binary(op) {
+            let b = decode_script_num(&stack.pop()?)?;
+            let a = decode_script_num(&stack.pop()?)?;
+            stack.push(encode_script_num(f(a, b)));
+        } else if op == OP_WITHIN {
+            let max = decode_script_num(&stack.pop()?)?;
+            let min = decode_script_num(&stack.pop()?)?;
+            let a = decode_script_num(&stack.pop()?)?;
+            stack.push(bool_value(min <= a && a < max));
+        } else {
+            return None;
+        }
+    }
+    Some(stack)
+}
+
+fn is_evaluated(inst: &OwnedInstruction) -> bool {
+    match inst {
+        OwnedInstruction::Op(op) => {
+            *op == OP_EQUAL || *op == OP_WITHIN || unary(*op).is_some() || binary(*op).is_some()
+        }
+        OwnedInstruction::PushBytes(_) => false,
+    }
+}
+
+fn bool_value(b: bool) -> Vec<u8> {
+    encode_script_num(b as i64)
+}
+
+fn unary(op: Opcode) -> Option<fn(i64) -> i64> {
+    let f: fn(i64) -> i64 = match op {
+        OP_1ADD => |a| a + 1,
+        OP_1SUB => |a| a - 1,
+        OP_NEGATE => |a| -a,
+        OP_ABS => |a| a.abs(),
+        OP_NOT => |a| (a == 0) as i64,
+        OP_0NOTEQUAL => |a| (a != 0) as i64,
+        _ => return None,
+    };
+    Some(f)
+}
+
+fn binary(op: Opcode) -> Option<fn(i64, i64) -> i64> {
+    let f: fn(i64, i64) -> i64 = match op {
+        OP_ADD => |a, b| a + b,
+        OP_SUB => |a, b| a - b,
+        OP_BOOLAND => |a, b| (a != 0 && b != 0) as i64,
+        OP_BOOLOR => |a, b| (a != 0 || b != 0) as i64,
+        OP_NUMEQUAL => |a, b| (a == b) as i64,
+        OP_NUMNOTEQUAL => |a, b| (a != b) as i64,
+        OP_LESSTHAN => |a, b| (a < b) as i64,
+        OP_GREATERTHAN => |a, b| (a > b) as i64,
+        OP_LESSTHANOREQUAL => |a, b| (a <= b) as i64,
+        OP_GREATERTHANOREQUAL => |a, b| (a >= b) as i64,
+        OP_MIN => |a, b| a.min(b),
+        OP_MAX => |a, b| a.max(b),
+        _ => return None,
+    };
+    Some(f)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::constant::{
+        cast_to_bool, constant_suffix, decode_script_num, encode_script_num, evaluate,
+        minimal_truth,
+    };
+    use crate::structured_script::{Node, OwnedInstruction, StructuredScript};
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    fn instructions(structure: StructuredScript) -> Vec<OwnedInstruction> {
+        match structure.into_node() {
+            Node::Script(v) => v.0,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_cast_to_bool() {
+        assert!(!cast_to_bool(&[]));
+        assert!(!cast_to_bool(&[0x00]));
+        assert!(!cast_to_bool(&[0x00, 0x00]));
+        assert!(!cast_to_bool(&[0x80]));
+        assert!(!cast_to_bool(&[0x00, 0x00, 0x80]));
+        assert!(cast_to_bool(&[0x01]));
+        assert!(cast_to_bool(&[0x80, 0x00]));
+        assert!(cast_to_bool(&[0x00, 0x01, 0x80]));
+    }
+
+    #[test]
+    fn test_minimal_truth() {
+        assert_eq!(minimal_truth(&[]), Some(false));
+        assert_eq!(minimal_truth(&[0x01]), Some(true));
+        // true or false under CastToBool, but rejected by MINIMALIF
+        assert_eq!(minimal_truth(&[0x00]), None);
+        assert_eq!(minimal_truth(&[0x80]), None);
+        assert_eq!(minimal_truth(&[0x02]), None);
+        assert_eq!(minimal_truth(&[0x01, 0x00]), None);
+    }
+
+    #[test]
+    fn test_script_num() {
+        for n in [
+            0,
+            1,
+            -1,
+            16,
+            127,
+            128,
+            -128,
+            255,
+            256,
+            -32768,
+            0x7fffffff,
+            -0x7fffffff,
+        ] {
+            let bytes = encode_script_num(n);
+            assert!(bytes.len() <= 4);
+            assert_eq!(decode_script_num(&bytes), Some(n));
+        }
+        assert_eq!(encode_script_num(128), vec![0x80, 0x00]);
+        assert_eq!(encode_script_num(-128), vec![0x80, 0x80]);
+        assert_eq!(encode_script_num(-1), vec![0x81]);
+
+        // not minimally encoded, or too long
+        assert_eq!(decode_script_num(&[0x00]), None);
+        assert_eq!(decode_script_num(&[0x80]), None);
+        assert_eq!(decode_script_num(&[0x01, 0x00]), None);
+        assert_eq!(decode_script_num(&[0x01, 0x02, 0x03, 0x04, 0x05]), None);
+    }
+
+    #[test]
+    fn test_constant_suffix() {
+        let test_script = script! {
+            OP_DUP 2 3 OP_ADD 5 OP_NUMEQUAL
+        };
+        let insts = instructions(test_script.into());
+        assert_eq!(constant_suffix(&insts), Some((1, vec![1])));
+
+        let test_script = script! {
+            OP_DUP 7 OP_1SUB 0 10 OP_WITHIN OP_NOT
+        };
+        let insts = instructions(test_script.into());
+        assert_eq!(constant_suffix(&insts), Some((1, vec![])));
+
+        // the comparison reads the element below the constant
+        let test_script = script! {
+            OP_DUP 5 OP_EQUAL
+        };
+        let insts = instructions(test_script.into());
+        assert_eq!(constant_suffix(&insts), None);
+
+        // a number that is not minimally encoded is left to the interpreter
+        let test_script = script! {
+            { vec![0x05, 0x00] } 5 OP_NUMEQUAL
+        };
+        let insts = instructions(test_script.into());
+        assert_eq!(constant_suffix(&insts), None);
+        assert_eq!(evaluate(&insts[..2]), Some(vec![vec![0x05, 0x00], vec![5]]));
+        assert_eq!(evaluate(&insts), None);
+
+        // but not by OP_EQUAL, which compares the bytes
+        let test_script = script! {
+            { vec![0x05, 0x00] } 5 OP_EQUAL
+        };
+        let insts = instructions(test_script.into());
+        assert_eq!(constant_suffix(&insts), Some((0, vec![])));
+    }
+}
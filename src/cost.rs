@@ -0,0 +1,239 @@
+//! Cost model of a `StructuredScript`, used to choose between lowerings.
+//!
+//! The size is the exact number of bytes of the encoded script, as written by
+//! `ScriptBuf::from`, computed without encoding it. The executed opcodes are counted along
+//! each way through the conditionals, where every instruction counts as one, including the
+//! pushes and the `OP_IF`/`OP_ELSE`/`OP_ENDIF` that the interpreter goes through, and the
+//! instructions of a branch that is not taken do not count.
+
+use crate::structured_script::{OwnedInstruction, StructuredScript, WalkEvent};
+use bitcoin::script::Builder;
+
+/// Weights of the size and the executed opcodes in the cost of a script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CostModel {
+    /// Weight of a byte of the encoded script.
+    pub size_weight: u64,
+    /// Weight of an opcode executed on the way through the script with the most of them.
+    pub opcode_weight: u64,
+}
+
+impl Default for CostModel {
+    /// Only the size counts, which is what a Taproot leaf pays for.
+    fn default() -> Self {
+        Self {
+            size_weight: 1,
+            opcode_weight: 0,
+        }
+    }
+}
+
+impl CostModel {
+    pub fn cost(&self, structure: &StructuredScript) -> u64 {
+        self.cost_of_sequence(std::slice::from_ref(structure))
+    }
+
+    /// Cost of the structures one after the other, as in a `MultiScript`.
+    pub fn cost_of_sequence(&self, structures: &[StructuredScript]) -> u64 {
+        let size: usize = structures.iter().map(encoded_size).sum();
+        let mut cost = self.size_weight * size as u64;
+        // the counts are only needed if they are weighted
+        if self.opcode_weight != 0 {
+            let executed = structures
+                .iter()
+                .map(executed_opcodes)
+                .fold(ExecutedOpcodes::default(), ExecutedOpcodes::then);
+            cost += self.opcode_weight * executed.max as u64;
+        }
+        cost
+    }
+}
+
+/// Fewest and most opcodes executed on a way through a script.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecutedOpcodes {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl ExecutedOpcodes {
+    fn exact(count: usize) -> Self {
+        Self {
+            min: count,
+            max: count,
+        }
+    }
+
+    fn then(self, other: Self) -> Self {
+        Self {
+            min: self.min + other.min,
+            max: self.max + other.max,
+        }
+    }
+
+    fn or(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+}
+
+/// Size of the encoded script in bytes.
+pub fn encoded_size(structure: &StructuredScript) -> usize {
+    // the size is the sum of what every node adds around its children
+    structure
+        .walk()
+        .map(|event| match event {
+            WalkEvent::Enter(StructuredScript::Script(v)) => v.0.iter().map(instruction_size).sum(),
+            // OP_IF/OP_NOTIF and OP_ENDIF
+            WalkEvent::Enter(StructuredScript::IfEndIf(_) | StructuredScript::NotIfEndIf(_)) => 2,
+            // and OP_ELSE
+            WalkEvent::Enter(
+                StructuredScript::IfElseEndIf(..) | StructuredScript::NotIfElseEndIf(..),
+            ) => 3,
+            WalkEvent::Enter(StructuredScript::Switch(arms)) => {
+                let len = arms.len();
+                (0..len)
+                    .map(|i| {
+                        if i != len - 1 {
+                            // OP_DUP <i> OP_NUMEQUAL OP_IF OP_DROP <arm> OP_ELSE, and its OP_ENDIF
+                            6 + index_size(i)
+                        } else {
+                            // <i> OP_NUMEQUALVERIFY <arm>
+                            1 + index_size(i)
+                        }
+                    })
+                    .sum()
+            }
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Fewest and most opcodes executed on a way through the script.
+pub fn executed_opcodes(structure: &StructuredScript) -> ExecutedOpcodes {
+    structure.fold_ref(|structure, children| match structure {
+        StructuredScript::Script(v) => ExecutedOpcodes::exact(v.0.len()),
+        StructuredScript::MultiScript(_) => children
+            .into_iter()
+            .fold(ExecutedOpcodes::default(), ExecutedOpcodes::then),
+        StructuredScript::IfEndIf(_) | StructuredScript::NotIfEndIf(_) => {
+            ExecutedOpcodes::exact(2).then(children[0].or(ExecutedOpcodes::default()))
+        }
+        StructuredScript::IfElseEndIf(..) | StructuredScript::NotIfElseEndIf(..) => {
+            ExecutedOpcodes::exact(3).then(children[0].or(children[1]))
+        }
+        StructuredScript::Switch(_) => {
+            let len = children.len();
+            // every OP_ENDIF is reached, and the arms before the selected one are skipped
+            // through OP_DUP <i> OP_NUMEQUAL OP_IF OP_ELSE
+            let mut skipped = len.saturating_sub(1);
+            let mut ways: Option<ExecutedOpcodes> = None;
+            for (i, arm) in children.into_iter().enumerate() {
+                let selected = if i != len - 1 {
+                    // OP_DUP <i> OP_NUMEQUAL OP_IF OP_DROP <arm> OP_ELSE
+                    6
+                } else {
+                    // <i> OP_NUMEQUALVERIFY <arm>
+                    2
+                };
+                let way = ExecutedOpcodes::exact(skipped + selected).then(arm);
+                ways = Some(ways.map_or(way, |ways| ways.or(way)));
+                skipped += 5;
+            }
+            ways.unwrap_or_default()
+        }
+    })
+}
+
+fn instruction_size(inst: &OwnedInstruction) -> usize {
+    match inst {
+        OwnedInstruction::Op(_) => 1,
+        OwnedInstruction::PushBytes(v) => match v.len() {
+            0 => 1,
+            len @ 1..=75 => 1 + len,
+            len @ 76..=255 => 2 + len,
+            len => 3 + len,
+        },
+    }
+}
+
+fn index_size(i: usize) -> usize {
+    Builder::new().push_int(i as i64).into_script().len()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cost::{encoded_size, executed_opcodes, CostModel, ExecutedOpcodes};
+    use crate::structured_script::StructuredScript;
+    use crate::{OP_CASE, OP_ENDSWITCH, OP_SWITCH};
+    use bitcoin::ScriptBuf;
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_encoded_size() {
+        let test_script = script! {
+            OP_DUP 10001 OP_EQUAL
+            OP_IF
+                { vec![0u8; 80] } OP_DROP
+            OP_ELSE
+                OP_NOTIF OP_NOP1 OP_ENDIF
+            OP_ENDIF
+            OP_SWITCH
+            for _ in 0..20 {
+                OP_CASE OP_NOP2
+            }
+            OP_ENDSWITCH
+            { vec![1u8; 300] } OP_DROP
+        };
+        let script: StructuredScript = test_script.into();
+        assert_eq!(encoded_size(&script), ScriptBuf::from(script).len());
+    }
+
+    #[test]
+    fn test_executed_opcodes() {
+        let test_script = script! {
+            OP_DUP
+            OP_IF
+                OP_NOP1 OP_NOP2
+            OP_ENDIF
+            OP_IF
+                OP_NOP1
+            OP_ELSE
+                OP_NOP1 OP_NOP2 OP_NOP3
+            OP_ENDIF
+        };
+        let script: StructuredScript = test_script.into();
+        // OP_DUP, 2 for the first conditional, 3 for the second one, and the branches
+        assert_eq!(
+            executed_opcodes(&script),
+            ExecutedOpcodes { min: 7, max: 11 }
+        );
+
+        let test_script = script! {
+            OP_SWITCH
+            OP_CASE OP_NOP1
+            OP_CASE OP_NOP1 OP_NOP2
+            OP_CASE
+            OP_ENDSWITCH
+        };
+        let script: StructuredScript = test_script.into();
+        // the first arm skips the others through their OP_ENDIF only
+        assert_eq!(
+            executed_opcodes(&script),
+            ExecutedOpcodes { min: 9, max: 15 }
+        );
+
+        let cost_model = CostModel {
+            size_weight: 2,
+            opcode_weight: 3,
+        };
+        assert_eq!(
+            cost_model.cost(&script),
+            2 * encoded_size(&script) as u64 + 3 * 15
+        );
+    }
+}
@@ -0,0 +1,439 @@
+//! Removal of the code that cannot be reached.
+//!
+//! The code after an `OP_RETURN`, an `OP_RETURN_TRUE`, an `OP_RETURN_RESULT`, or an
+//! `OP_VERIFY` of a false constant is never executed, nor is the code after a conditional all
+//! of whose branches end this way. A conditional on a constant only ever takes one of its
+//! branches, and an `OP_IF_RETURN_TRUE` on a false constant never succeeds. The constants are
+//! computed by `crate::constant`, and those of `OP_IF`/`OP_NOTIF` and of the success sites,
+//! which are lowered into them, are only folded when they are exactly 0 or 1, as any other
+//! value would fail under MINIMALIF.
+//!
+//! The success sites that are removed this way are returned, so that they can be reported.
+
+use crate::constant::{cast_to_bool, constant_suffix, minimal_truth};
+use crate::site_label::{is_success_site, label_len, site_labels};
+use crate::structured_script::{Node, OwnedInstruction, OwnedInstructions, StructuredScript};
+use crate::{_OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE, _OP_RETURN_RESULT, _OP_RETURN_TRUE};
+use bitcoin::opcodes::all::{OP_RETURN, OP_VERIFY};
+use bitcoin::ScriptBuf;
+
+/// A success site that cannot succeed, because it is never reached or its condition is a
+/// constant that does not trigger it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadSite {
+    /// Position of the site among the success sites of the input script.
+    pub index: usize,
+    pub label: Option<String>,
+}
+
+/// Remove the code that cannot be reached, and return the success sites removed with it.
+pub fn eliminate_dead_code(structure: &mut StructuredScript) -> Vec<DeadSite> {
+    let mut dead = vec![];
+    let mut changed = false;
+    eliminate(structure, &mut 0, &mut dead, &mut changed);
+    dead.sort_by_key(|site| site.index);
+
+    // the branches that replace their conditional are parsed again into the structure
+    if changed {
+        *structure = ScriptBuf::from(structure.clone()).into();
+    }
+    dead
+}
+
+/// A node whose children are being visited by `eliminate`.
+struct Frame {
+    structure: StructuredScript,
+    /// The children left to visit, the next one last.
+    children: Vec<StructuredScript>,
+    /// The children visited, with whether they never reach their end.
+    done: Vec<(StructuredScript, bool)>,
+    /// Number of sites to skip after the child being visited, which are those of the branch
+    /// not taken of a folded conditional.
+    skipped: usize,
+}
+
+/// Remove the dead code in the structure, where `next` is the index of the next success
+/// site, and return whether the structure never reaches its end.
+fn eliminate(
+    structure: &mut StructuredScript,
+    next: &mut usize,
+    dead: &mut Vec<DeadSite>,
+    changed: &mut bool,
+) -> bool {
+    let mut stack: Vec<Frame> = vec![];
+    let mut visit = Some(std::mem::take(structure));
+    let mut value = None;
+    loop {
+        if let Some(mut current) = visit.take() {
+            if let StructuredScript::Script(v) = &mut current {
+                let terminates = eliminate_instructions(v, next, dead, changed);
+                value = Some((current, terminates));
+            } else {
+                let mut children = current.take_children();
+                children.reverse();
+                stack.push(Frame {
+                    structure: current,
+                    children,
+                    done: vec![],
+                    skipped: 0,
+                });
+            }
+        }
+
+        let Some(frame) = stack.last_mut() else {
+            let (result, terminates) = value.unwrap();
+            *structure = result;
+            return terminates;
+        };
+        let is_sequence = matches!(frame.structure, StructuredScript::MultiScript(_));
+
+        if let Some((child, terminates)) = value.take() {
+            frame.done.push((child, terminates));
+            if is_sequence {
+                *next += frame.skipped;
+                frame.skipped = 0;
+                if terminates {
+                    for v in frame.children.drain(..).rev() {
+                        remove_structure(&v, next, dead);
+                        *changed = true;
+                    }
+                }
+            }
+        }
+
+        if let Some(mut child) = frame.children.pop() {
+            if is_sequence {
+                if let Some((before, _)) = frame.done.last_mut() {
+                    if let Some(skipped) = fold_constant_condition(before, &mut child, next, dead) {
+                        *changed = true;
+                        frame.skipped = skipped;
+                    }
+                }
+            }
+            visit = Some(child);
+            continue;
+        }
+
+        let Frame {
+            mut structure,
+            done,
+            ..
+        } = stack.pop().unwrap();
+        let terminates = match structure {
+            StructuredScript::MultiScript(_) => done.last().is_some_and(|(_, t)| *t),
+            StructuredScript::IfEndIf(_) | StructuredScript::NotIfEndIf(_) => false,
+            _ => done.iter().all(|(_, t)| *t),
+        };
+        structure.set_children(done.into_iter().map(|(v, _)| v).collect());
+        value = Some((structure, terminates));
+    }
+}
+
+/// `eliminate` on the instructions of a script.
+fn eliminate_instructions(
+    v: &mut OwnedInstructions,
+    next: &mut usize,
+    dead: &mut Vec<DeadSite>,
+    changed: &mut bool,
+) -> bool {
+    let mut res = vec![];
+    let mut i = 0;
+    while i < v.0.len() {
+        let inst = &v.0[i];
+        let label_end = i + 1 + label_len(&v.0, i + 1);
+        let condition = match inst {
+            OwnedInstruction::Op(op) if *op == OP_VERIFY => {
+                constant_suffix(&res).map(|(start, value)| (start, cast_to_bool(&value)))
+            }
+            // the site fails on any other constant under MINIMALIF, once lowered
+            OwnedInstruction::Op(op)
+                if *op == _OP_IF_RETURN_TRUE || *op == _OP_NOTIF_RETURN_TRUE =>
+            {
+                constant_suffix(&res)
+                    .and_then(|(start, value)| Some((start, minimal_truth(&value)?)))
+            }
+            _ => None,
+        };
+
+        let terminates = match (inst, condition) {
+            (OwnedInstruction::Op(op), _)
+                if [OP_RETURN, _OP_RETURN_TRUE, _OP_RETURN_RESULT].contains(op) =>
+            {
+                true
+            }
+            (OwnedInstruction::Op(op), Some((start, truth))) if *op == OP_VERIFY => {
+                if truth {
+                    // a no-op
+                    res.truncate(start);
+                    *changed = true;
+                    i = label_end;
+                    continue;
+                }
+                true
+            }
+            (OwnedInstruction::Op(op), Some((start, truth))) => {
+                res.truncate(start);
+                *changed = true;
+                if truth == (*op == _OP_IF_RETURN_TRUE) {
+                    // the site always succeeds
+                    *next += 1;
+                    res.push(OwnedInstruction::Op(_OP_RETURN_TRUE));
+                    res.extend_from_slice(&v.0[i + 1..label_end]);
+                    remove(&v.0[label_end..], next, dead);
+                    v.0 = res;
+                    return true;
+                }
+                // the site never succeeds
+                remove(&v.0[i..label_end], next, dead);
+                i = label_end;
+                continue;
+            }
+            _ => false,
+        };
+
+        if matches!(inst, OwnedInstruction::Op(op) if is_success_site(*op)) {
+            *next += 1;
+        }
+        res.extend_from_slice(&v.0[i..label_end]);
+        if terminates {
+            if label_end < v.0.len() {
+                remove(&v.0[label_end..], next, dead);
+                *changed = true;
+            }
+            v.0 = res;
+            return true;
+        }
+        i = label_end;
+    }
+    v.0 = res;
+    false
+}
+
+/// If the structure is a conditional on a constant pushed at the end of the script before it,
+/// remove the constant, report the sites of the branch that is not taken, and replace the
+/// structure with the branch that is taken. Return the number of sites to skip after it.
+fn fold_constant_condition(
+    before: &mut StructuredScript,
+    structure: &mut StructuredScript,
+    next: &mut usize,
+    dead: &mut Vec<DeadSite>,
+) -> Option<usize> {
+    let StructuredScript::Script(before) = before else {
+        return None;
+    };
+    let (start, value) = constant_suffix(&before.0)?;
+    let truth = minimal_truth(&value)?;
+    let is_conditional = matches!(
+        structure,
+        StructuredScript::IfEndIf(_)
+            | StructuredScript::NotIfEndIf(_)
+            | StructuredScript::IfElseEndIf(..)
+            | StructuredScript::NotIfElseEndIf(..)
+    );
+    if !is_conditional {
+        return None;
+    }
+
+    // the sites of the first branch come before those of the second one
+    let not_taken_first = matches!(
+        (&*structure, truth),
+        (StructuredScript::IfElseEndIf(..), false) | (StructuredScript::NotIfElseEndIf(..), true)
+    );
+    let empty = StructuredScript::default;
+    let (taken, not_taken) = match std::mem::take(structure).into_node() {
+        Node::IfEndIf(v) if truth => (v, empty()),
+        Node::IfEndIf(v) => (empty(), v),
+        Node::NotIfEndIf(v) if truth => (empty(), v),
+        Node::NotIfEndIf(v) => (v, empty()),
+        Node::IfElseEndIf(v1, v2) if truth => (v1, v2),
+        Node::IfElseEndIf(v1, v2) => (v2, v1),
+        Node::NotIfElseEndIf(v1, v2) if truth => (v2, v1),
+        Node::NotIfElseEndIf(v1, v2) => (v1, v2),
+        _ => unreachable!(),
+    };
+    before.0.truncate(start);
+    *structure = taken;
+
+    if not_taken_first {
+        remove_structure(&not_taken, next, dead);
+        Some(0)
+    } else {
+        // the sites of the taken branch are counted when it is visited, so the ones of the
+        // other branch come after them
+        let mut after = *next + site_labels(structure).len();
+        remove_structure(&not_taken, &mut after, dead);
+        Some(site_labels(&not_taken).len())
+    }
+}
+
+fn remove(instructions: &[OwnedInstruction], next: &mut usize, dead: &mut Vec<DeadSite>) {
+    let removed = StructuredScript::Script(OwnedInstructions(instructions.to_vec()));
+    remove_structure(&removed, next, dead);
+}
+
+fn remove_structure(removed: &StructuredScript, next: &mut usize, dead: &mut Vec<DeadSite>) {
+    for label in site_labels(removed) {
+        dead.push(DeadSite {
+            index: *next,
+            label,
+        });
+        *next += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dead_code::{eliminate_dead_code, DeadSite};
+    use crate::structured_script::StructuredScript;
+    use crate::{OP_IF_RETURN_TRUE, OP_NOTIF_RETURN_TRUE, OP_RETURN_TRUE, OP_SITE_LABEL};
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_unreachable_code() {
+        let script = script! {
+            OP_DUP
+            OP_IF
+                OP_NOP1
+                OP_RETURN
+                OP_DUP OP_IF_RETURN_TRUE { OP_SITE_LABEL("after return") }
+            OP_ELSE
+                0 OP_VERIFY
+                OP_NOP2
+            OP_ENDIF
+            OP_DUP OP_IF_RETURN_TRUE
+            OP_NOP3
+        };
+
+        let mut structured_script = StructuredScript::from(script);
+        let dead = eliminate_dead_code(&mut structured_script);
+
+        // both branches fail, so the code after the conditional is dead too
+        let expected_script = script! {
+            OP_DUP
+            OP_IF
+                OP_NOP1
+                OP_RETURN
+            OP_ELSE
+                0 OP_VERIFY
+            OP_ENDIF
+        };
+
+        let expected = StructuredScript::from(expected_script);
+        assert_eq!(expected, structured_script);
+        assert_eq!(
+            dead,
+            vec![
+                DeadSite {
+                    index: 0,
+                    label: Some("after return".to_string()),
+                },
+                DeadSite {
+                    index: 1,
+                    label: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_conditions() {
+        let script = script! {
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+            0
+            OP_IF
+                OP_DUP OP_IF_RETURN_TRUE { OP_SITE_LABEL("never") }
+            OP_ELSE
+                OP_NOP1
+                OP_DUP OP_NOTIF_RETURN_TRUE
+            OP_ENDIF
+            0 OP_IF_RETURN_TRUE
+            1 OP_NOTIF_RETURN_TRUE { OP_SITE_LABEL("never either") }
+            1
+            OP_NOTIF
+                OP_NOP2
+            OP_ENDIF
+            2
+            OP_IF
+                OP_NOP3
+            OP_ENDIF
+            1 OP_IF_RETURN_TRUE
+            OP_NOP4
+        };
+
+        let mut structured_script = StructuredScript::from(script);
+        let dead = eliminate_dead_code(&mut structured_script);
+
+        // 2 is not folded, as OP_IF fails on it under MINIMALIF
+        let expected_script = script! {
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_NOP1
+            OP_DUP OP_NOTIF_RETURN_TRUE
+            2
+            OP_IF
+                OP_NOP3
+            OP_ENDIF
+            OP_RETURN_TRUE
+        };
+
+        let expected = StructuredScript::from(expected_script);
+        assert_eq!(expected, structured_script);
+        assert_eq!(
+            dead,
+            vec![
+                DeadSite {
+                    index: 1,
+                    label: Some("never".to_string()),
+                },
+                DeadSite {
+                    index: 3,
+                    label: None,
+                },
+                DeadSite {
+                    index: 4,
+                    label: Some("never either".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_taken_branch_first() {
+        let script = script! {
+            1
+            OP_IF
+                OP_DUP OP_IF_RETURN_TRUE
+                OP_RETURN_TRUE
+            OP_ELSE
+                OP_DUP OP_IF_RETURN_TRUE { OP_SITE_LABEL("else") }
+            OP_ENDIF
+            OP_DUP OP_IF_RETURN_TRUE { OP_SITE_LABEL("after") }
+        };
+
+        let mut structured_script = StructuredScript::from(script);
+        let dead = eliminate_dead_code(&mut structured_script);
+
+        let expected_script = script! {
+            OP_DUP OP_IF_RETURN_TRUE
+            OP_RETURN_TRUE
+        };
+
+        let expected = StructuredScript::from(expected_script);
+        assert_eq!(expected, structured_script);
+        // the sites of the branch that is not taken come before the ones after it
+        assert_eq!(
+            dead,
+            vec![
+                DeadSite {
+                    index: 2,
+                    label: Some("else".to_string()),
+                },
+                DeadSite {
+                    index: 3,
+                    label: Some("after".to_string()),
+                },
+            ]
+        );
+    }
+}
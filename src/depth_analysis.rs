@@ -0,0 +1,653 @@
+//! Static analysis of the depths of the stacks through a `StructuredScript`.
+//!
+//! The depths are followed as ranges through every way the conditionals can take, with the
+//! effect of each opcode from `opcode_stack_effect`, and the ranges of the branches are joined
+//! at the `OP_ENDIF` of a conditional or the `OP_ENDSWITCH` of a switch. The main stack is
+//! counted relative to the number of witness elements, which are on it when the script
+//! starts, so a negative depth means that the code has consumed some of them. The altstack
+//! starts empty.
+//!
+//! The points of the script are the positions of its instructions as written, including the
+//! `OP_IF`/`OP_NOTIF`/`OP_ELSE`/`OP_ENDIF` of the conditionals and the
+//! `OP_SWITCH`/`OP_CASE`/`OP_ENDSWITCH` of the switches, with one more point for the end.
+
+use crate::site_label::{is_success_site, label_len};
+use crate::stack_effect::{opcode_stack_effect, StackEffectError};
+use crate::structured_script::{OwnedInstruction, StructuredScript, WalkEvent};
+use crate::{_OP_RETURN_RESULT, _OP_RETURN_TRUE, _OP_SWITCH};
+use bitcoin::opcodes::all::{OP_IF, OP_IFDUP, OP_NOTIF, OP_RETURN};
+use bitcoin::Opcode;
+
+/// Fewest and most elements on a stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthRange {
+    pub min: isize,
+    pub max: isize,
+}
+
+impl DepthRange {
+    pub fn exact(depth: isize) -> Self {
+        Self {
+            min: depth,
+            max: depth,
+        }
+    }
+
+    fn join(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+}
+
+/// Depths of the main stack, relative to the number of witness elements, of the altstack, and
+/// of both together, at a point of the script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthBounds {
+    pub main: DepthRange,
+    pub alt: DepthRange,
+    pub total: DepthRange,
+}
+
+impl DepthBounds {
+    fn join(self, other: Self) -> Self {
+        Self {
+            main: self.main.join(other.main),
+            alt: self.alt.join(other.alt),
+            total: self.total.join(other.total),
+        }
+    }
+}
+
+/// The branch taken at a conditional or a switch on the way to a point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchTaken {
+    /// Position of the `OP_IF`/`OP_NOTIF` or the `OP_SWITCH`.
+    pub position: usize,
+    /// 0 for the first branch of a conditional and 1 for the one after `OP_ELSE`, or the index
+    /// of the arm of a switch.
+    pub branch: usize,
+}
+
+/// Fewest and most elements on the main stack when a success site succeeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SiteDepths {
+    pub min: usize,
+    pub max: usize,
+}
+
+/// An instruction that needs more elements than a stack may have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Underflow {
+    pub position: usize,
+    pub opcode: Opcode,
+    /// Fewest witness elements with which the main stack has enough elements there, or `None`
+    /// if it is the altstack that may not have enough, whatever the witness.
+    pub witness_needed: Option<usize>,
+}
+
+/// Result of `analyze_stack_depths`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackAnalysis {
+    /// Depths before the instruction at every position, and at the end of the script at the
+    /// last position, or `None` where the code is not reached.
+    pub points: Vec<Option<DepthBounds>>,
+    /// The instructions that need witness elements, or that may underflow the altstack, in
+    /// the order of the script.
+    pub underflows: Vec<Underflow>,
+    /// The innermost branch around every position, from which the paths are built.
+    enclosing: Vec<Option<BranchTaken>>,
+    /// Positions of the success sites that are reached, with the number of elements their
+    /// condition or result takes from the main stack.
+    sites: Vec<(usize, isize)>,
+}
+
+impl StackAnalysis {
+    /// Fewest witness elements with which no instruction underflows the main stack.
+    pub fn witness_needed(&self) -> usize {
+        self.underflows
+            .iter()
+            .filter_map(|underflow| underflow.witness_needed)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The instructions that may underflow a stack when the script starts with this many
+    /// witness elements.
+    pub fn underflows_with(&self, witness_elements: usize) -> Vec<&Underflow> {
+        self.underflows
+            .iter()
+            .filter(|underflow| {
+                underflow
+                    .witness_needed
+                    .is_none_or(|needed| needed > witness_elements)
+            })
+            .collect()
+    }
+
+    /// Depths of the main stack at the success sites, after the condition of the site is
+    /// consumed, when the script starts with this many witness elements.
+    ///
+    /// This is `None` if no success site is reached, or if a stack may underflow on the way.
+    pub fn site_depths(&self, witness_elements: usize) -> Option<SiteDepths> {
+        if !self.underflows_with(witness_elements).is_empty() {
+            return None;
+        }
+        let witness_elements = witness_elements as isize;
+        self.sites
+            .iter()
+            .map(|(position, pops)| {
+                let main = self.points[*position].unwrap().main;
+                SiteDepths {
+                    min: (main.min + witness_elements - pops) as usize,
+                    max: (main.max + witness_elements - pops) as usize,
+                }
+            })
+            .reduce(|depths1, depths2| SiteDepths {
+                min: depths1.min.min(depths2.min),
+                max: depths1.max.max(depths2.max),
+            })
+    }
+
+    /// The branches taken to reach the point at `position`, outermost first. The
+    /// `OP_ELSE`/`OP_ENDIF` of a conditional and the `OP_CASE`/`OP_ENDSWITCH` of a switch are
+    /// on the same path as its `OP_IF`/`OP_NOTIF` or its `OP_SWITCH`.
+    pub fn path(&self, position: usize) -> Vec<BranchTaken> {
+        let mut path = vec![];
+        let mut enclosing = self.enclosing[position];
+        while let Some(taken) = enclosing {
+            path.push(taken);
+            enclosing = self.enclosing[taken.position];
+        }
+        path.reverse();
+        path
+    }
+
+    fn push_point(&mut self, depths: Option<DepthBounds>, enclosing: Option<BranchTaken>) {
+        self.points.push(depths);
+        self.enclosing.push(enclosing);
+    }
+}
+
+/// A conditional or a switch whose branches are being followed.
+struct Branches {
+    position: usize,
+    /// Whether an `OP_CASE` comes before every branch, and not only an `OP_ELSE` before the
+    /// second one.
+    switch: bool,
+    /// Index of the branch being followed.
+    branch: usize,
+    /// The depths at the start of every branch.
+    start: Option<DepthBounds>,
+    /// The depths at the end of the branches followed so far.
+    end: Option<DepthBounds>,
+}
+
+/// Follow the ranges of the depths of the stacks through the script.
+///
+/// The code after an `OP_RETURN`, an `OP_RETURN_TRUE` or an `OP_RETURN_RESULT` is not reached.
+/// This fails with `StackEffectError::UnknownEffect` at a reached opcode whose effect is not
+/// known, see `opcode_stack_effect`, except for `OP_IFDUP`, which pushes back 1 or 2 elements.
+pub fn analyze_stack_depths(
+    structure: &StructuredScript,
+) -> Result<StackAnalysis, StackEffectError> {
+    let mut analysis = StackAnalysis {
+        points: vec![],
+        underflows: vec![],
+        enclosing: vec![],
+        sites: vec![],
+    };
+    let mut depths = Some(DepthBounds {
+        main: DepthRange::exact(0),
+        alt: DepthRange::exact(0),
+        total: DepthRange::exact(0),
+    });
+    // for every node being walked, whether it is a conditional or a switch, whose branches
+    // are in `conditionals`
+    let mut open: Vec<bool> = vec![];
+    let mut conditionals: Vec<Branches> = vec![];
+
+    for event in structure.walk() {
+        match event {
+            WalkEvent::Enter(structure) => {
+                if open.last() == Some(&true) {
+                    let (branches, outer) = conditionals.split_last().unwrap();
+                    // the OP_ELSE or the OP_CASE before the branch
+                    if branches.switch || branches.branch > 0 {
+                        analysis.push_point(depths, innermost(outer));
+                    }
+                    depths = branches.start;
+                }
+                let conditional = match structure {
+                    StructuredScript::Script(v) => {
+                        depths = analysis.follow(&v.0, depths, innermost(&conditionals))?;
+                        false
+                    }
+                    StructuredScript::MultiScript(_) => false,
+                    // the condition or the selector is consumed before the branch runs
+                    _ => {
+                        let position = analysis.points.len();
+                        let opcode = match structure {
+                            StructuredScript::IfEndIf(_) | StructuredScript::IfElseEndIf(..) => {
+                                OP_IF
+                            }
+                            StructuredScript::Switch(_) => _OP_SWITCH,
+                            _ => OP_NOTIF,
+                        };
+                        analysis.push_point(depths, innermost(&conditionals));
+                        depths = analysis.apply(depths, position, opcode, Effect::main(1, 0));
+                        conditionals.push(Branches {
+                            position,
+                            switch: opcode == _OP_SWITCH,
+                            branch: 0,
+                            start: depths,
+                            end: None,
+                        });
+                        true
+                    }
+                };
+                open.push(conditional);
+            }
+            WalkEvent::Leave(structure) => {
+                if open.pop().unwrap() {
+                    let branches = conditionals.pop().unwrap();
+                    // the OP_ENDIF or the OP_ENDSWITCH
+                    analysis.push_point(depths, innermost(&conditionals));
+                    depths = match structure {
+                        // the missing branch does nothing
+                        StructuredScript::IfEndIf(_) | StructuredScript::NotIfEndIf(_) => {
+                            join(branches.end, branches.start)
+                        }
+                        _ => branches.end,
+                    };
+                }
+                if open.last() == Some(&true) {
+                    let branches = conditionals.last_mut().unwrap();
+                    branches.end = join(branches.end, depths);
+                    branches.branch += 1;
+                }
+            }
+        }
+    }
+
+    analysis.push_point(depths, None);
+    Ok(analysis)
+}
+
+/// Effect of an instruction, with a range of elements pushed on the main stack.
+#[derive(Clone, Copy)]
+struct Effect {
+    pops: usize,
+    pushes: (usize, usize),
+    alt_pops: usize,
+    alt_pushes: usize,
+}
+
+impl Effect {
+    fn main(pops: usize, pushes: usize) -> Self {
+        Self {
+            pops,
+            pushes: (pushes, pushes),
+            alt_pops: 0,
+            alt_pushes: 0,
+        }
+    }
+}
+
+impl StackAnalysis {
+    /// Follow the depths through the instructions of a script.
+    fn follow(
+        &mut self,
+        instructions: &[OwnedInstruction],
+        mut depths: Option<DepthBounds>,
+        enclosing: Option<BranchTaken>,
+    ) -> Result<Option<DepthBounds>, StackEffectError> {
+        let mut i = 0;
+        while i < instructions.len() {
+            let position = self.points.len();
+            self.push_point(depths, enclosing);
+            let opcode = match &instructions[i] {
+                // the code that is not reached has no effect, whatever its opcodes
+                _ if depths.is_none() => {
+                    i += 1;
+                    continue;
+                }
+                OwnedInstruction::PushBytes(_) => {
+                    depths = depths.map(|mut depths| {
+                        depths.main.min += 1;
+                        depths.main.max += 1;
+                        depths.total.min += 1;
+                        depths.total.max += 1;
+                        depths
+                    });
+                    i += 1;
+                    continue;
+                }
+                OwnedInstruction::Op(opcode) => *opcode,
+            };
+
+            if opcode == _OP_RETURN_TRUE {
+                self.sites.push((position, 0));
+            } else if is_success_site(opcode) {
+                self.sites.push((position, 1));
+            }
+            let effect = if opcode == OP_RETURN || opcode == _OP_RETURN_TRUE {
+                None
+            } else if is_success_site(opcode) {
+                // the condition of OP_IF_RETURN_TRUE/OP_NOTIF_RETURN_TRUE, or the result of
+                // OP_RETURN_RESULT
+                Some(Effect::main(1, 0))
+            } else if opcode == OP_IFDUP {
+                Some(Effect {
+                    pops: 1,
+                    pushes: (1, 2),
+                    alt_pops: 0,
+                    alt_pushes: 0,
+                })
+            } else {
+                let effect = opcode_stack_effect(opcode)
+                    .ok_or(StackEffectError::UnknownEffect { position, opcode })?;
+                Some(Effect {
+                    pops: effect.pops,
+                    pushes: (effect.pushes, effect.pushes),
+                    alt_pops: effect.alt_pops,
+                    alt_pushes: effect.alt_pushes,
+                })
+            };
+            depths = match effect {
+                Some(effect) => self.apply(depths, position, opcode, effect),
+                None => None,
+            };
+            if opcode == _OP_RETURN_RESULT {
+                depths = None;
+            }
+
+            i += 1;
+            if is_success_site(opcode) {
+                // the label of a site does not reach the stack
+                let label_len = label_len(instructions, i);
+                for _ in 0..label_len {
+                    self.push_point(depths, enclosing);
+                }
+                i += label_len;
+            }
+        }
+        Ok(depths)
+    }
+
+    /// Apply the effect of the instruction at `position`, and record where it may underflow.
+    fn apply(
+        &mut self,
+        depths: Option<DepthBounds>,
+        position: usize,
+        opcode: Opcode,
+        effect: Effect,
+    ) -> Option<DepthBounds> {
+        let DepthBounds {
+            main,
+            mut alt,
+            mut total,
+        } = depths?;
+        let mut underflow = |witness_needed| {
+            self.underflows.push(Underflow {
+                position,
+                opcode,
+                witness_needed,
+            })
+        };
+
+        let pops = effect.pops as isize;
+        if pops > 0 && main.min < pops {
+            underflow(Some((pops - main.min) as usize));
+        }
+        // the ways with fewer elements on the altstack fail there
+        let alt_pops = effect.alt_pops as isize;
+        if alt.min < alt_pops {
+            underflow(None);
+            if alt.max < alt_pops {
+                return None;
+            }
+            total.min = total.min.max(main.min + alt_pops);
+            alt.min = alt_pops;
+        }
+        let change = effect.alt_pushes as isize - alt_pops - pops;
+
+        Some(DepthBounds {
+            main: DepthRange {
+                min: main.min - pops + effect.pushes.0 as isize,
+                max: main.max - pops + effect.pushes.1 as isize,
+            },
+            alt: DepthRange {
+                min: alt.min - alt_pops + effect.alt_pushes as isize,
+                max: alt.max - alt_pops + effect.alt_pushes as isize,
+            },
+            total: DepthRange {
+                min: total.min + change + effect.pushes.0 as isize,
+                max: total.max + change + effect.pushes.1 as isize,
+            },
+        })
+    }
+}
+
+/// The branch being followed at the innermost conditional.
+fn innermost(conditionals: &[Branches]) -> Option<BranchTaken> {
+    conditionals.last().map(|branches| BranchTaken {
+        position: branches.position,
+        branch: branches.branch,
+    })
+}
+
+/// The depths after either of two ways, where `None` is a way that is not taken.
+fn join(depths1: Option<DepthBounds>, depths2: Option<DepthBounds>) -> Option<DepthBounds> {
+    match (depths1, depths2) {
+        (Some(depths1), Some(depths2)) => Some(depths1.join(depths2)),
+        (depths1, depths2) => depths1.or(depths2),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::depth_analysis::{
+        analyze_stack_depths, BranchTaken, DepthBounds, DepthRange, SiteDepths, Underflow,
+    };
+    use crate::structured_script::StructuredScript;
+    use crate::{
+        _OP_IF_RETURN_TRUE, _OP_SWITCH, OP_CASE, OP_ENDSWITCH, OP_IF_RETURN_TRUE, OP_RETURN_TRUE,
+        OP_SITE_LABEL, OP_SWITCH,
+    };
+    use bitcoin::opcodes::all::{OP_DROP, OP_DUP, OP_FROMALTSTACK, OP_NOTIF};
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    fn range((min, max): (isize, isize)) -> DepthRange {
+        DepthRange { min, max }
+    }
+
+    fn depths(
+        main: (isize, isize),
+        alt: (isize, isize),
+        total: (isize, isize),
+    ) -> Option<DepthBounds> {
+        Some(DepthBounds {
+            main: range(main),
+            alt: range(alt),
+            total: range(total),
+        })
+    }
+
+    #[test]
+    fn test_analyze_conditional() {
+        let script: StructuredScript = script! {
+            OP_DUP
+            OP_IF
+                OP_DROP OP_FROMALTSTACK
+            OP_ELSE
+                1 OP_IFDUP OP_TOALTSTACK
+            OP_ENDIF
+            OP_RETURN_TRUE
+        }
+        .into();
+        let analysis = analyze_stack_depths(&script).unwrap();
+        assert_eq!(
+            analysis.points,
+            vec![
+                depths((0, 0), (0, 0), (0, 0)),
+                depths((1, 1), (0, 0), (1, 1)),
+                depths((0, 0), (0, 0), (0, 0)),
+                depths((-1, -1), (0, 0), (-1, -1)),
+                // the first branch always fails at OP_FROMALTSTACK
+                None,
+                depths((0, 0), (0, 0), (0, 0)),
+                depths((1, 1), (0, 0), (1, 1)),
+                depths((1, 2), (0, 0), (1, 2)),
+                depths((0, 1), (1, 1), (1, 2)),
+                depths((0, 1), (1, 1), (1, 2)),
+                None,
+            ]
+        );
+
+        assert_eq!(
+            analysis.underflows,
+            vec![
+                Underflow {
+                    position: 0,
+                    opcode: OP_DUP,
+                    witness_needed: Some(1),
+                },
+                Underflow {
+                    position: 2,
+                    opcode: OP_DROP,
+                    witness_needed: Some(1),
+                },
+                Underflow {
+                    position: 3,
+                    opcode: OP_FROMALTSTACK,
+                    witness_needed: None,
+                },
+            ]
+        );
+        assert_eq!(analysis.witness_needed(), 1);
+        assert_eq!(analysis.underflows_with(1), vec![&analysis.underflows[2]]);
+        assert_eq!(
+            analysis.path(3),
+            vec![BranchTaken {
+                position: 1,
+                branch: 0
+            }]
+        );
+        assert_eq!(analysis.path(4), vec![]);
+    }
+
+    #[test]
+    fn test_analyze_switch() {
+        let script: StructuredScript = script! {
+            OP_SWITCH
+            OP_CASE
+                OP_DROP
+            OP_CASE
+                1 OP_IF_RETURN_TRUE { OP_SITE_LABEL("site") }
+            OP_ENDSWITCH
+            OP_NOTIF
+                1
+            OP_ENDIF
+        }
+        .into();
+        let analysis = analyze_stack_depths(&script).unwrap();
+        let main: Vec<_> = analysis
+            .points
+            .iter()
+            .map(|depths| {
+                let depths = depths.unwrap();
+                assert_eq!(depths.alt, range((0, 0)));
+                assert_eq!(depths.total, depths.main);
+                (depths.main.min, depths.main.max)
+            })
+            .collect();
+        assert_eq!(
+            main,
+            vec![
+                (0, 0),
+                (-1, -1),
+                (-1, -1),
+                (-2, -2),
+                (-1, -1),
+                (0, 0),
+                // the label does not reach the stack
+                (-1, -1),
+                (-1, -1),
+                (-1, -1),
+                (-2, -1),
+                (-3, -2),
+                (-2, -1),
+                (-3, -1),
+            ]
+        );
+
+        let underflows: Vec<_> = analysis
+            .underflows
+            .iter()
+            .map(|underflow| {
+                (
+                    underflow.position,
+                    underflow.opcode,
+                    analysis.path(underflow.position),
+                    underflow.witness_needed,
+                )
+            })
+            .collect();
+        let arm = |branch| BranchTaken {
+            position: 0,
+            branch,
+        };
+        assert_eq!(
+            underflows,
+            vec![
+                (0, _OP_SWITCH, vec![], Some(1)),
+                (2, OP_DROP, vec![arm(0)], Some(2)),
+                (5, _OP_IF_RETURN_TRUE, vec![arm(1)], Some(1)),
+                (9, OP_NOTIF, vec![], Some(3)),
+            ]
+        );
+        assert_eq!(analysis.witness_needed(), 3);
+        assert!(analysis.underflows_with(3).is_empty());
+    }
+
+    #[test]
+    fn test_site_depths() {
+        let script: StructuredScript = script! {
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("first") }
+            OP_DUP
+            OP_IF
+                OP_DROP
+                OP_RETURN_TRUE
+            OP_ELSE
+                OP_DUP OP_DROP
+            OP_ENDIF
+            OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_RETURN
+        }
+        .into();
+        let analysis = analyze_stack_depths(&script).unwrap();
+        // two elements of the witness at the first and the last site, and one at the second
+        assert_eq!(analysis.site_depths(2), Some(SiteDepths { min: 1, max: 2 }));
+        // the first OP_DUP may underflow
+        assert_eq!(analysis.site_depths(0), None);
+
+        let script: StructuredScript = script! {
+            OP_IF
+                OP_DUP
+            OP_ENDIF
+            OP_IF_RETURN_TRUE
+        }
+        .into();
+        let analysis = analyze_stack_depths(&script).unwrap();
+        assert_eq!(analysis.site_depths(2), Some(SiteDepths { min: 0, max: 1 }));
+
+        // no success site is reached
+        let script: StructuredScript = script! { OP_DROP OP_RETURN }.into();
+        let analysis = analyze_stack_depths(&script).unwrap();
+        assert_eq!(analysis.site_depths(1), None);
+    }
+}
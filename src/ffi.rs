@@ -0,0 +1,219 @@
+//! C ABI for embedding the compiler in non-Rust services.
+//!
+//! The header `include/fraud_proof_compiler.h` is generated from this module by cbindgen
+//! (see `cbindgen.toml`), and `ffi/test_compile.c` exercises it.
+
+use crate::compile::{compile, CompileReport};
+use bitcoin::Script;
+use std::cell::RefCell;
+use std::ffi::{c_char, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+/// Status codes returned by the functions of the C API.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FpcStatus {
+    /// The call succeeded.
+    FpcOk = 0,
+    /// A required pointer argument is null.
+    FpcInvalidArgument = 1,
+    /// The script cannot be compiled, see `fpc_last_error_message`.
+    FpcCompileError = 2,
+    /// The compiler panicked, see `fpc_last_error_message`.
+    FpcInternalError = 3,
+}
+
+/// A byte buffer owned by the library, to be released with `fpc_buffer_free`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FpcBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// Statistics of a compilation.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FpcCompileStats {
+    /// Size of the input script in bytes.
+    pub input_size: usize,
+    /// Size of the compiled script in bytes.
+    pub output_size: usize,
+    /// Number of success sites that survive the code cleanup.
+    pub success_sites: usize,
+}
+
+impl From<&CompileReport> for FpcCompileStats {
+    fn from(report: &CompileReport) -> Self {
+        Self {
+            input_size: report.input_size,
+            output_size: report.output_size,
+            success_sites: report.success_sites,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // the message comes from Display impls and never contains a NUL byte, but be safe
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+fn clear_last_error() {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+}
+
+/// Compile the script at `script`/`script_len` and write the compiled script into `out`.
+///
+/// On success, `out` owns a buffer that must be released with `fpc_buffer_free`, and `stats`,
+/// if not null, receives the statistics of the compilation. On failure, `out` is set to an
+/// empty buffer and the reason can be retrieved with `fpc_last_error_message`.
+///
+/// # Safety
+///
+/// `script` must point to `script_len` readable bytes (it may be null if `script_len` is 0),
+/// `out` must be a valid pointer, and `stats` must be either null or a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn fpc_compile(
+    script: *const u8,
+    script_len: usize,
+    out: *mut FpcBuffer,
+    stats: *mut FpcCompileStats,
+) -> FpcStatus {
+    clear_last_error();
+
+    if out.is_null() || (script.is_null() && script_len != 0) {
+        set_last_error("a required pointer argument is null".to_string());
+        return FpcStatus::FpcInvalidArgument;
+    }
+    *out = FpcBuffer {
+        data: ptr::null_mut(),
+        len: 0,
+    };
+
+    let bytes = if script_len == 0 {
+        &[][..]
+    } else {
+        std::slice::from_raw_parts(script, script_len)
+    };
+
+    let res = catch_unwind(AssertUnwindSafe(|| compile(Script::from_bytes(bytes))));
+    match res {
+        Ok(Ok(compiled)) => {
+            if !stats.is_null() {
+                *stats = FpcCompileStats::from(&compiled.report);
+            }
+
+            let boxed = compiled.script.into_bytes().into_boxed_slice();
+            let len = boxed.len();
+            *out = FpcBuffer {
+                data: Box::into_raw(boxed) as *mut u8,
+                len,
+            };
+            FpcStatus::FpcOk
+        }
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            FpcStatus::FpcCompileError
+        }
+        Err(panic) => {
+            let message = if let Some(s) = panic.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = panic.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            set_last_error(format!("internal compiler error: {}", message));
+            FpcStatus::FpcInternalError
+        }
+    }
+}
+
+/// Release a buffer returned by the library. Releasing an empty buffer is a no-op.
+///
+/// # Safety
+///
+/// `buffer` must have been returned by this library and not been released before.
+#[no_mangle]
+pub unsafe extern "C" fn fpc_buffer_free(buffer: FpcBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.data,
+            buffer.len,
+        )));
+    }
+}
+
+/// Return the error message of the last failed call on this thread, or null if the last call
+/// succeeded.
+///
+/// The string is owned by the library and stays valid until the next call into the library
+/// on the same thread.
+#[no_mangle]
+pub extern "C" fn fpc_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|e| match e.borrow().as_ref() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Return the version of the compiler as a static NUL-terminated string.
+#[no_mangle]
+pub extern "C" fn fpc_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ffi::{
+        fpc_buffer_free, fpc_compile, fpc_last_error_message, FpcBuffer, FpcCompileStats, FpcStatus,
+    };
+    use crate::OP_RETURN_TRUE;
+    use bitcoin_script::{define_pushable, script};
+    use std::ffi::CStr;
+    use std::ptr;
+
+    define_pushable!();
+
+    #[test]
+    fn test_ffi_compile() {
+        let script = script! {
+            OP_IF
+                OP_RETURN_TRUE
+            OP_ENDIF
+            OP_RETURN
+        };
+        let bytes = script.as_bytes();
+
+        let mut out = FpcBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        };
+        let mut stats = FpcCompileStats::default();
+        let status = unsafe { fpc_compile(bytes.as_ptr(), bytes.len(), &mut out, &mut stats) };
+        assert_eq!(status, FpcStatus::FpcOk);
+        assert!(fpc_last_error_message().is_null());
+        assert_eq!(stats.input_size, bytes.len());
+        assert_eq!(stats.output_size, out.len);
+        assert_eq!(stats.success_sites, 1);
+        unsafe { fpc_buffer_free(out) };
+
+        let malformed = script! { OP_IF OP_RETURN_TRUE };
+        let bytes = malformed.as_bytes();
+        let mut out = FpcBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        };
+        let status = unsafe { fpc_compile(bytes.as_ptr(), bytes.len(), &mut out, ptr::null_mut()) };
+        assert_eq!(status, FpcStatus::FpcCompileError);
+        assert!(out.data.is_null());
+        let message = unsafe { CStr::from_ptr(fpc_last_error_message()) };
+        assert!(message.to_str().unwrap().contains("unbalanced"));
+    }
+}
@@ -1,80 +1,330 @@
-use crate::structured_script::StructuredScript;
-use bitcoin::ScriptBuf;
+use crate::compile::CompileError;
+use crate::stack_effect::{simulate_stack_depth, StackDepth, MAX_STACK_ELEMENTS};
+use crate::structured_script::{OwnedInstruction, OwnedInstructions, StructuredScript};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::opcodes::all::{
+    OP_CHECKSIG, OP_CLTV, OP_DROP, OP_ENDIF, OP_EQUAL, OP_FROMALTSTACK, OP_NOTIF, OP_PUSHNUM_1,
+    OP_RETURN, OP_SHA256, OP_VERIFY,
+};
+use bitcoin::script::Builder;
+use bitcoin::{absolute, ScriptBuf, XOnlyPublicKey};
 use bitcoin_script::{define_pushable, script};
 
+/// Code that runs in the success branch, after the stack cleanup, to decide whether the
+/// success is accepted.
+///
+/// When the epilogue starts, the main stack is empty, and the altstack is as the user's
+/// script left it at the success site. The inputs of the epilogue, such as a signature or a
+/// preimage, are therefore expected on the altstack: the user's script moves them there with
+/// `OP_TOALTSTACK` before any success site can be reached, and the epilogue takes the top
+/// `altstack_inputs` elements with `OP_FROMALTSTACK`.
+///
+/// To keep the cleanstack rule, the epilogue must leave exactly one element on the main
+/// stack, which is the verdict. This is checked when the epilogue is created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuccessEpilogue {
+    script: ScriptBuf,
+    altstack_inputs: usize,
+}
+
+impl Default for SuccessEpilogue {
+    /// Accept any success.
+    fn default() -> Self {
+        Self {
+            script: Builder::new().push_opcode(OP_PUSHNUM_1).into_script(),
+            altstack_inputs: 0,
+        }
+    }
+}
+
+impl SuccessEpilogue {
+    /// Create an epilogue that takes `altstack_inputs` elements from the altstack, and check
+    /// that it leaves exactly one element on the main stack.
+    pub fn new(script: ScriptBuf, altstack_inputs: usize) -> Result<Self, CompileError> {
+        let start = StackDepth {
+            main: 0,
+            alt: altstack_inputs,
+        };
+        let end = simulate_stack_depth(&script, start)
+            .map_err(|e| CompileError::InvalidEpilogue(e.to_string()))?;
+        if end.main != 1 {
+            return Err(CompileError::InvalidEpilogue(format!(
+                "the epilogue leaves {} elements on the stack instead of 1",
+                end.main
+            )));
+        }
+
+        Ok(Self {
+            script,
+            altstack_inputs,
+        })
+    }
+
+    /// Accept the success only with a signature for `pk`, taken from the altstack.
+    pub fn checksig(pk: &XOnlyPublicKey) -> Self {
+        let script = Builder::new()
+            .push_opcode(OP_FROMALTSTACK)
+            .push_x_only_key(pk)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        Self::new(script, 1).unwrap()
+    }
+
+    /// Accept the success only with the preimage of `hash`, taken from the altstack.
+    pub fn sha256_preimage(hash: sha256::Hash) -> Self {
+        let script = Builder::new()
+            .push_opcode(OP_FROMALTSTACK)
+            .push_opcode(OP_SHA256)
+            .push_slice(hash.to_byte_array())
+            .push_opcode(OP_EQUAL)
+            .into_script();
+        Self::new(script, 1).unwrap()
+    }
+
+    /// Accept the success only once `lock_time` has passed.
+    pub fn timelock(lock_time: absolute::LockTime) -> Self {
+        let script = Builder::new()
+            .push_lock_time(lock_time)
+            .push_opcode(OP_CLTV)
+            .push_opcode(OP_DROP)
+            .push_opcode(OP_PUSHNUM_1)
+            .into_script();
+        Self::new(script, 0).unwrap()
+    }
+
+    /// Accept the success only if both epilogues accept it. `self` takes its inputs from the
+    /// altstack first.
+    pub fn and(self, other: SuccessEpilogue) -> Self {
+        let mut script = self.script.into_bytes();
+        script.push(OP_VERIFY.to_u8());
+        script.extend_from_slice(other.script.as_bytes());
+        Self::new(
+            ScriptBuf::from_bytes(script),
+            self.altstack_inputs + other.altstack_inputs,
+        )
+        .unwrap()
+    }
+
+    pub fn script(&self) -> &ScriptBuf {
+        &self.script
+    }
+
+    pub fn altstack_inputs(&self) -> usize {
+        self.altstack_inputs
+    }
+}
+
+/// How the success code drops the elements left on the main stack before the epilogue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StackCleanup {
+    /// Drop any number of elements, up to the stack limit, with a cascade of `OP_DEPTH`
+    /// checks.
+    #[default]
+    Full,
+    /// Drop exactly this many elements, which is the depth at every success site.
+    Exact(usize),
+    /// Drop up to this many elements, with the levels of the cascade that they need.
+    Bounded(usize),
+    /// Fail if there are more than this many elements, which could not be proved, and drop
+    /// them as with `Bounded`.
+    Guarded(usize),
+}
+
 pub fn final_emit_code() -> ScriptBuf {
+    final_emit_code_with_epilogue(&SuccessEpilogue::default(), StackCleanup::Full)
+}
+
+/// The success logic, guarded by the flag on the stack.
+pub fn final_emit_code_with_epilogue(
+    epilogue: &SuccessEpilogue,
+    cleanup: StackCleanup,
+) -> ScriptBuf {
     define_pushable!();
 
     script! {
         OP_IF
-            OP_DEPTH 512 OP_GREATERTHANOREQUAL OP_IF
-                for _ in 0..256 {
-                    OP_2DROP
-                }
-            OP_ENDIF
+            { success_code(epilogue, cleanup) }
+        OP_ENDIF
+    }
+}
 
-            OP_DEPTH 256 OP_GREATERTHANOREQUAL OP_IF
-                for _ in 0..128 {
-                    OP_2DROP
-                }
-            OP_ENDIF
+/// Drop everything on the main stack, and run the epilogue.
+fn success_code(epilogue: &SuccessEpilogue, cleanup: StackCleanup) -> ScriptBuf {
+    define_pushable!();
 
-            OP_DEPTH 128 OP_GREATERTHANOREQUAL OP_IF
-                for _ in 0..64 {
-                    OP_2DROP
-                }
-            OP_ENDIF
+    let drop_code = match cleanup {
+        StackCleanup::Full => cascade(MAX_STACK_ELEMENTS),
+        StackCleanup::Exact(depth) => script! {
+            for _ in 0..depth / 2 {
+                OP_2DROP
+            }
+            if depth % 2 == 1 {
+                OP_DROP
+            }
+        },
+        StackCleanup::Bounded(max) => cascade(max),
+        StackCleanup::Guarded(max) => script! {
+            OP_DEPTH { max } OP_LESSTHANOREQUAL OP_VERIFY
+            { cascade(max) }
+        },
+    };
 
-            OP_DEPTH 64 OP_GREATERTHANOREQUAL OP_IF
-                for _ in 0..32 {
-                    OP_2DROP
-                }
-            OP_ENDIF
+    script! {
+        { drop_code }
+        { epilogue.script().clone() }
+    }
+}
 
-            OP_DEPTH 32 OP_GREATERTHANOREQUAL OP_IF
-                for _ in 0..16 {
-                    OP_2DROP
-                }
-            OP_ENDIF
+/// Drop up to `max` elements: every level drops half of the elements it checks for, from the
+/// highest power of two up to `max`.
+fn cascade(max: usize) -> ScriptBuf {
+    define_pushable!();
 
-            OP_DEPTH 16 OP_GREATERTHANOREQUAL OP_IF
-                for _ in 0..8 {
-                    OP_2DROP
-                }
-            OP_ENDIF
+    let levels = (1..usize::BITS)
+        .map(|k| 1usize << k)
+        .take_while(|level| *level <= max)
+        .collect::<Vec<_>>();
 
-            OP_DEPTH 8 OP_GREATERTHANOREQUAL OP_IF
-                for _ in 0..4 {
+    script! {
+        for level in levels.into_iter().rev() {
+            OP_DEPTH { level } OP_GREATERTHANOREQUAL OP_IF
+                for _ in 0..level / 2 {
                     OP_2DROP
                 }
             OP_ENDIF
+        }
 
-            OP_DEPTH 4 OP_GREATERTHANOREQUAL OP_IF
-                OP_2DROP OP_2DROP
-            OP_ENDIF
-
-            OP_DEPTH 2 OP_GREATERTHANOREQUAL OP_IF
-                OP_2DROP
-            OP_ENDIF
-
+        if max > 0 {
             OP_DEPTH OP_IF
                 OP_DROP
             OP_ENDIF
-
-            OP_TRUE
-        OP_ENDIF
+        }
     }
 }
 
-pub fn append_final_emit_script(structure: &mut StructuredScript) {
-    let final_emit_code: StructuredScript = final_emit_code().into();
+/// What happens when the script terminates normally, without going through any success site.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FallThrough {
+    /// The outcome depends on what the script leaves on the stack.
+    #[default]
+    UserDefined,
+    /// The script always fails.
+    AlwaysFail,
+}
+
+pub fn append_final_emit_script(
+    structure: &mut StructuredScript,
+    epilogue: &SuccessEpilogue,
+    fall_through: FallThrough,
+    cleanup: StackCleanup,
+) {
+    let final_emit_code: StructuredScript = match fall_through {
+        FallThrough::UserDefined => final_emit_code_with_epilogue(epilogue, cleanup).into(),
+        // the flag can be the condition of a site, passed up as is, so it is checked by a
+        // conditional like with `UserDefined`, where MINIMALIF rejects a flag other than 0 or
+        // 1, and not by OP_VERIFY, which would accept any true value
+        FallThrough::AlwaysFail => {
+            let mut code = vec![OP_NOTIF.to_u8(), OP_RETURN.to_u8(), OP_ENDIF.to_u8()];
+            code.extend_from_slice(success_code(epilogue, cleanup).as_bytes());
+            ScriptBuf::from_bytes(code).into()
+        }
+    };
+    append_structure(structure, final_emit_code);
+}
+
+/// Make a script without any success site fail on normal termination.
+pub fn append_failure_tail(structure: &mut StructuredScript) {
+    let tail = StructuredScript::Script(OwnedInstructions(vec![OwnedInstruction::Op(OP_RETURN)]));
+    append_structure(structure, tail);
+}
 
+fn append_structure(structure: &mut StructuredScript, tail: StructuredScript) {
     match structure {
         StructuredScript::MultiScript(vv) => {
-            vv.push(final_emit_code);
+            vv.push(tail);
         }
         _ => {
-            *structure = StructuredScript::MultiScript(vec![structure.clone(), final_emit_code]);
+            let body = std::mem::take(structure);
+            *structure = StructuredScript::MultiScript(vec![body, tail]);
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::compile::CompileError;
+    use crate::final_emit::{
+        final_emit_code, final_emit_code_with_epilogue, StackCleanup, SuccessEpilogue,
+    };
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_epilogue() {
+        assert!(final_emit_code()
+            .as_bytes()
+            .ends_with(&script! { 1 OP_ENDIF }.to_bytes()));
+
+        let epilogue = SuccessEpilogue::sha256_preimage(sha256::Hash::hash(b"preimage")).and(
+            SuccessEpilogue::sha256_preimage(sha256::Hash::hash(b"other")),
+        );
+        assert_eq!(epilogue.altstack_inputs(), 2);
+
+        // leaves the signature on the stack
+        let script = script! { OP_FROMALTSTACK OP_DUP OP_DUP OP_CHECKSIG };
+        assert!(matches!(
+            SuccessEpilogue::new(script.clone(), 1),
+            Err(CompileError::InvalidEpilogue(_))
+        ));
+
+        // takes more inputs than declared
+        let script = script! { OP_FROMALTSTACK OP_FROMALTSTACK OP_EQUAL };
+        assert!(matches!(
+            SuccessEpilogue::new(script.clone(), 1),
+            Err(CompileError::InvalidEpilogue(_))
+        ));
+        assert!(SuccessEpilogue::new(script, 2).is_ok());
+    }
+
+    #[test]
+    fn test_stack_cleanup() {
+        let epilogue = SuccessEpilogue::default();
+
+        // the full cascade handles the 1000 elements of the stack limit
+        assert_eq!(
+            final_emit_code_with_epilogue(&epilogue, StackCleanup::Bounded(1000)),
+            final_emit_code()
+        );
+
+        assert_eq!(
+            final_emit_code_with_epilogue(&epilogue, StackCleanup::Exact(3)),
+            script! { OP_IF OP_2DROP OP_DROP 1 OP_ENDIF }
+        );
+
+        assert_eq!(
+            final_emit_code_with_epilogue(&epilogue, StackCleanup::Guarded(3)),
+            script! {
+                OP_IF
+                    OP_DEPTH 3 OP_LESSTHANOREQUAL OP_VERIFY
+                    OP_DEPTH 2 OP_GREATERTHANOREQUAL OP_IF OP_2DROP OP_ENDIF
+                    OP_DEPTH OP_IF OP_DROP OP_ENDIF
+                    1
+                OP_ENDIF
+            }
+        );
+
+        assert_eq!(
+            final_emit_code_with_epilogue(&epilogue, StackCleanup::Bounded(7)),
+            script! {
+                OP_IF
+                    OP_DEPTH 4 OP_GREATERTHANOREQUAL OP_IF OP_2DROP OP_2DROP OP_ENDIF
+                    OP_DEPTH 2 OP_GREATERTHANOREQUAL OP_IF OP_2DROP OP_ENDIF
+                    OP_DEPTH OP_IF OP_DROP OP_ENDIF
+                    1
+                OP_ENDIF
+            }
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! Removal of the flag blocks emitted by `reduce`.
+//!
+//! When a conditional may succeed, `reduce` merges its flag with the code after it through a
+//! flag block `OP_IF 1 OP_ELSE <tail> OP_ENDIF`, where the tail is the code after it followed
+//! by its own flag, or `OP_IF 1 OP_ENDIF` when the two levels of flags are merged. The block
+//! can move into every branch of the conditional right before it, and it disappears where a
+//! branch ends with a constant flag: `0 <block>` is the tail, and `1 <block>` is `1`.
+//!
+//! The block is only moved when this works for every branch and lowers the cost of the
+//! script under the `CostModel`, which by default is its size in encoded bytes. When several
+//! branches end with 0, this duplicates the tail into each of them, which is limited to tails
+//! of at most `max_tail_size` bytes.
+
+use crate::cost::{encoded_size, CostModel};
+use crate::structured_script::{Node, OwnedInstruction, OwnedInstructions, StructuredScript};
+use bitcoin::opcodes::all::{OP_PUSHBYTES_0, OP_PUSHNUM_1};
+
+/// Default maximum size of a tail duplicated into several branches, in bytes.
+pub const DEFAULT_MAX_TAIL_SIZE: usize = 16;
+
+/// A flag block with a tail that could be duplicated into the branches before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TailChoice {
+    /// Size of the tail in bytes.
+    pub size: usize,
+    /// Number of branches that end with 0, into which the tail would be duplicated.
+    pub branches: usize,
+    /// Whether the tail was duplicated, or the flag threaded through the block.
+    pub duplicated: bool,
+}
+
+/// Remove the flag blocks that can be absorbed by the conditional before them, and return
+/// the choices made for the blocks with a tail, in the order they were considered.
+pub fn optimize_flag_blocks(
+    structure: &mut StructuredScript,
+    max_tail_size: usize,
+    cost_model: &CostModel,
+) -> Vec<TailChoice> {
+    let mut choices = vec![];
+    // bottom up, inner blocks first, so that the branches end with their constant flags
+    *structure = std::mem::take(structure).fold(|node| match node {
+        Node::MultiScript(mut vv) => {
+            absorb_flag_blocks(&mut vv, max_tail_size, cost_model, &mut choices);
+            StructuredScript::MultiScript(vv)
+        }
+        node => node.into(),
+    });
+    choices
+}
+
+fn absorb_flag_blocks(
+    vv: &mut Vec<StructuredScript>,
+    max_tail_size: usize,
+    cost_model: &CostModel,
+    choices: &mut Vec<TailChoice>,
+) {
+    let mut i = 1;
+    while i < vv.len() {
+        let (Some(tail), Some(zeros)) = (flag_block_tail(&vv[i]), zero_ends(&vv[i - 1])) else {
+            i += 1;
+            continue;
+        };
+
+        let size = encoded_size(&tail);
+        let mut absorbed = vv[i - 1].clone();
+        absorb_flag_block(&mut absorbed, &tail);
+        let duplicated = (zeros <= 1 || size <= max_tail_size)
+            && cost_model.cost(&absorbed) < cost_model.cost_of_sequence(&vv[i - 1..=i]);
+        if size > 0 {
+            choices.push(TailChoice {
+                size,
+                branches: zeros,
+                duplicated,
+            });
+        }
+
+        if duplicated {
+            vv[i - 1] = absorbed;
+            vv.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// The tail of a flag block, which is empty for `OP_IF 1 OP_ENDIF`, or `None` if the
+/// structure is not a flag block.
+fn flag_block_tail(structure: &StructuredScript) -> Option<StructuredScript> {
+    let one = StructuredScript::Script(OwnedInstructions(vec![OwnedInstruction::Op(OP_PUSHNUM_1)]));
+    match structure {
+        StructuredScript::IfEndIf(v) if **v == one => {
+            Some(StructuredScript::Script(OwnedInstructions(vec![])))
+        }
+        StructuredScript::IfElseEndIf(v1, v2) if **v1 == one => Some((**v2).clone()),
+        _ => None,
+    }
+}
+
+/// Number of ways through the structure that end with a constant 0, or `None` if some way
+/// does not end with a constant flag.
+fn zero_ends(structure: &StructuredScript) -> Option<usize> {
+    let mut zeros = 0;
+    // the ends left to look at
+    let mut stack = vec![structure];
+    while let Some(structure) = stack.pop() {
+        match structure {
+            StructuredScript::Script(v) => match v.0.last() {
+                Some(OwnedInstruction::Op(op)) if *op == OP_PUSHBYTES_0 => zeros += 1,
+                Some(OwnedInstruction::Op(op)) if *op == OP_PUSHNUM_1 => {}
+                _ => return None,
+            },
+            StructuredScript::MultiScript(vv) => stack.push(vv.last()?),
+            // the missing branch does not push a flag
+            StructuredScript::IfEndIf(_) | StructuredScript::NotIfEndIf(_) => return None,
+            StructuredScript::IfElseEndIf(v1, v2) | StructuredScript::NotIfElseEndIf(v1, v2) => {
+                stack.push(v1);
+                stack.push(v2);
+            }
+            StructuredScript::Switch(arms) => stack.extend(arms),
+        }
+    }
+    Some(zeros)
+}
+
+/// Apply the flag block with the tail to the constant flags at the end of the structure,
+/// for which `zero_ends` must be some.
+fn absorb_flag_block(structure: &mut StructuredScript, tail: &StructuredScript) {
+    // the ends left to apply the block to
+    let mut stack = vec![structure];
+    while let Some(structure) = stack.pop() {
+        match structure {
+            StructuredScript::Script(v) => {
+                if v.0.last() == Some(&OwnedInstruction::Op(OP_PUSHBYTES_0)) {
+                    v.0.pop();
+                    match tail {
+                        StructuredScript::Script(tail) => v.0.extend_from_slice(&tail.0),
+                        tail => {
+                            let v = std::mem::take(structure);
+                            *structure = StructuredScript::MultiScript(vec![v, tail.clone()])
+                        }
+                    }
+                }
+            }
+            StructuredScript::MultiScript(vv) => stack.push(vv.last_mut().unwrap()),
+            StructuredScript::IfEndIf(_) | StructuredScript::NotIfEndIf(_) => unreachable!(),
+            StructuredScript::IfElseEndIf(v1, v2) | StructuredScript::NotIfElseEndIf(v1, v2) => {
+                stack.push(v1);
+                stack.push(v2);
+            }
+            StructuredScript::Switch(arms) => stack.extend(arms.iter_mut()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compile::compile;
+    use crate::cost::CostModel;
+    use crate::flag_block::{optimize_flag_blocks, TailChoice, DEFAULT_MAX_TAIL_SIZE};
+    use crate::reduce::reduce;
+    use crate::structured_script::StructuredScript;
+    use crate::OP_IF_RETURN_TRUE;
+    use bitcoin::ScriptBuf;
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_optimize_flag_blocks() {
+        let test_script = script! {
+            OP_NOP1
+            OP_IF
+                OP_NOP2
+                OP_IF_RETURN_TRUE
+                OP_NOP3
+                OP_IF_RETURN_TRUE
+                OP_NOP4
+                OP_IF_RETURN_TRUE
+                OP_NOP5
+            OP_ENDIF
+            OP_NOP6
+        };
+        let mut script: StructuredScript = test_script.clone().into();
+        reduce(&mut script);
+        let reduced_size = ScriptBuf::from(script.clone()).len();
+
+        let choices =
+            optimize_flag_blocks(&mut script, DEFAULT_MAX_TAIL_SIZE, &CostModel::default());
+        assert_eq!(
+            choices,
+            vec![TailChoice {
+                size: 2,
+                branches: 2,
+                duplicated: true
+            }]
+        );
+
+        let expected_script = script! {
+            OP_NOP1
+            OP_IF
+                OP_NOP2
+                OP_IF
+                    1
+                OP_ELSE
+                    OP_NOP3
+                    OP_IF
+                        1
+                    OP_ELSE
+                        OP_NOP4
+                        OP_IF
+                            1
+                        OP_ELSE
+                            OP_NOP5
+                            OP_NOP6
+                            0
+                        OP_ENDIF
+                    OP_ENDIF
+                OP_ENDIF
+            OP_ELSE
+                OP_NOP6
+                0
+            OP_ENDIF
+        };
+        let expected: StructuredScript = expected_script.into();
+        assert_eq!(expected, script);
+
+        // three flag blocks, the flags they dropped, and the tail duplicated into two branches
+        assert_eq!(ScriptBuf::from(script).len(), reduced_size - 14);
+
+        // with no duplication, the last flag block stays
+        let mut script: StructuredScript = test_script.into();
+        reduce(&mut script);
+        let choices = optimize_flag_blocks(&mut script, 0, &CostModel::default());
+        assert!(!choices[0].duplicated);
+        assert_eq!(ScriptBuf::from(script).len(), reduced_size - 10);
+
+        // the conditional before the flag block has no OP_ELSE, so the block stays
+        let test_script = script! {
+            OP_IF
+                OP_NOP1
+            OP_ENDIF
+            OP_IF
+                1
+            OP_ENDIF
+        };
+        let mut script: StructuredScript = test_script.clone().into();
+        optimize_flag_blocks(&mut script, DEFAULT_MAX_TAIL_SIZE, &CostModel::default());
+        assert_eq!(StructuredScript::from(test_script), script);
+    }
+
+    #[test]
+    fn test_tail_choice_weights() {
+        let test_script = script! {
+            OP_IF
+                OP_NOP1
+                OP_IF_RETURN_TRUE
+                OP_NOP2
+            OP_ELSE
+                OP_NOP3
+                OP_IF_RETURN_TRUE
+                OP_NOP4
+            OP_ENDIF
+            for _ in 0..7 {
+                OP_NOP5
+            }
+        };
+        let mut reduced: StructuredScript = test_script.into();
+        reduce(&mut reduced);
+
+        // a tail of 8 bytes duplicated into two branches costs more bytes than threading
+        let mut script = reduced.clone();
+        let choices =
+            optimize_flag_blocks(&mut script, DEFAULT_MAX_TAIL_SIZE, &CostModel::default());
+        assert_eq!(
+            choices,
+            vec![TailChoice {
+                size: 8,
+                branches: 2,
+                duplicated: false
+            }]
+        );
+
+        // but it skips `0 OP_IF OP_ELSE OP_ENDIF` on the way through the tail
+        let cost_model = CostModel {
+            size_weight: 1,
+            opcode_weight: 1,
+        };
+        let mut script = reduced;
+        let choices = optimize_flag_blocks(&mut script, DEFAULT_MAX_TAIL_SIZE, &cost_model);
+        assert!(choices[0].duplicated);
+    }
+
+    #[test]
+    fn test_flag_block_sizes() {
+        // a chain of success sites, as in a gadget with many checks
+        let script = script! {
+            OP_IF
+                for i in 0..20 {
+                    OP_DUP { 10000 + i } OP_EQUAL OP_IF_RETURN_TRUE
+                }
+                OP_DROP
+            OP_ENDIF
+            OP_RETURN
+        };
+        // 858 bytes without the optimization, 763 bytes without duplicating the tails
+        assert_eq!(compile(&script).unwrap().script.len(), 759);
+
+        // sites around a conditional without any
+        let script = script! {
+            OP_DUP OP_IF_RETURN_TRUE
+            OP_IF
+                OP_NOP1
+            OP_ENDIF
+            OP_IF_RETURN_TRUE
+            OP_RETURN
+        };
+        // 590 bytes without the optimization
+        assert_eq!(compile(&script).unwrap().script.len(), 585);
+    }
+}
@@ -0,0 +1,152 @@
+//! Lowering of the success sites with flat guards on the main stack.
+//!
+//! `reduce` nests the code after a success site into the branch where the site did not
+//! succeed, so the nesting grows with the number of sites. Here, the flag of a site stays on
+//! top of the main stack, and the code after it is split into segments, each of which is
+//! guarded by the flag:
+//!
+//! ```text
+//! <segment 0> OP_IF 1 OP_ELSE 0 OP_ENDIF
+//! OP_DUP OP_NOTIF
+//!     OP_DROP <segment 1> OP_IF 1 OP_ELSE 0 OP_ENDIF
+//! OP_ENDIF
+//! ...
+//! OP_DUP OP_NOTIF
+//!     OP_DROP <tail> 0
+//! OP_ENDIF
+//! ```
+//!
+//! A segment drops the flag before it runs, so the user's code sees the same stack as in the
+//! input script. The segments of a sequence do not nest, and the nesting only grows by one
+//! guard for every conditional with success sites around it.
+//!
+//! The flag of a site is its condition, made minimal by `OP_IF 1 OP_ELSE 0 OP_ENDIF` so that
+//! a condition that `OP_IF` would reject under MINIMALIF still fails. The peephole rules
+//! remove it when the condition is known to be 0 or 1.
+
+use crate::reduce::EmitOpIfSuccess;
+use crate::segment::{join_sequences, lower_segments, script, SiteFlag};
+use crate::structured_script::StructuredScript;
+use crate::{_OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE};
+use bitcoin::opcodes::all::{OP_DROP, OP_DUP, OP_PUSHNUM_1};
+use bitcoin::opcodes::OP_0;
+use bitcoin::Opcode;
+
+/// Lower the success sites, after `op_return_true_to_op_if_return_true`, and leave the flag
+/// on top of the main stack at the end, as `reduce` does.
+pub fn lower_with_flat_guards(structure: &mut StructuredScript) -> EmitOpIfSuccess {
+    if !structure.contains_pseudo_opcodes() {
+        return EmitOpIfSuccess::NO;
+    }
+
+    *structure = lower_segments::<FlatGuard>(std::mem::take(structure));
+    EmitOpIfSuccess::YES
+}
+
+/// Join scripts whose success sites have been lowered one by one, each of which leaves the
+/// flag on top of the main stack, into one where every script but the first one is guarded
+/// like a segment, followed by `tail`, which has no success site.
+pub(crate) fn join_flat_guarded(
+    lowered: Vec<StructuredScript>,
+    tail: Option<StructuredScript>,
+) -> StructuredScript {
+    join_sequences::<FlatGuard>(lowered, tail)
+}
+
+/// The flag on top of the main stack, left by every sequence and every conditional with
+/// success sites.
+struct FlatGuard;
+
+impl SiteFlag for FlatGuard {
+    /// The condition of the site turned into the flag.
+    fn site(op: Opcode) -> StructuredScript {
+        if op == _OP_IF_RETURN_TRUE {
+            minimal_flag(OP_PUSHNUM_1, OP_0)
+        } else if op == _OP_NOTIF_RETURN_TRUE {
+            minimal_flag(OP_0, OP_PUSHNUM_1)
+        } else {
+            script(vec![OP_PUSHNUM_1])
+        }
+    }
+
+    fn guard(segments: &mut Vec<StructuredScript>, mut segment: Vec<StructuredScript>) {
+        segment.insert(0, script(vec![OP_DROP]));
+        segments.push(script(vec![OP_DUP]));
+        segments.push(StructuredScript::NotIfEndIf(Box::new(
+            StructuredScript::MultiScript(segment),
+        )));
+    }
+
+    fn no_success() -> Option<StructuredScript> {
+        Some(script(vec![OP_0]))
+    }
+}
+
+/// `OP_IF <if_true> OP_ELSE <if_false> OP_ENDIF`.
+fn minimal_flag(if_true: Opcode, if_false: Opcode) -> StructuredScript {
+    StructuredScript::IfElseEndIf(
+        Box::new(script(vec![if_true])),
+        Box::new(script(vec![if_false])),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::flat_guard::lower_with_flat_guards;
+    use crate::reduce::EmitOpIfSuccess;
+    use crate::structured_script::StructuredScript;
+    use crate::{OP_IF_RETURN_TRUE, OP_NOTIF_RETURN_TRUE};
+    use bitcoin::ScriptBuf;
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_lower_with_flat_guards() {
+        let test_script = script! {
+            OP_NOP1
+            OP_IF_RETURN_TRUE
+            OP_NOP2
+            OP_IF
+                OP_NOP3
+                OP_NOTIF_RETURN_TRUE
+                OP_NOP4
+            OP_ENDIF
+            OP_NOP5
+        };
+        let mut script: StructuredScript = test_script.into();
+        assert_eq!(lower_with_flat_guards(&mut script), EmitOpIfSuccess::YES);
+
+        let expected = script! {
+            OP_NOP1
+            OP_IF 1 OP_ELSE 0 OP_ENDIF
+            OP_DUP
+            OP_NOTIF
+                OP_DROP
+                OP_NOP2
+                OP_IF
+                    OP_NOP3
+                    OP_IF 0 OP_ELSE 1 OP_ENDIF
+                    OP_DUP
+                    OP_NOTIF
+                        OP_DROP
+                        OP_NOP4
+                        0
+                    OP_ENDIF
+                OP_ELSE
+                    0
+                OP_ENDIF
+            OP_ENDIF
+            OP_DUP
+            OP_NOTIF
+                OP_DROP
+                OP_NOP5
+                0
+            OP_ENDIF
+        };
+        assert_eq!(ScriptBuf::from(script), expected);
+
+        let mut script: StructuredScript = script! { OP_NOP1 OP_IF OP_NOP2 OP_ENDIF }.into();
+        assert_eq!(lower_with_flat_guards(&mut script), EmitOpIfSuccess::NO);
+    }
+}
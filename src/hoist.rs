@@ -0,0 +1,349 @@
+//! Hoisting of the code that all the branches of a conditional have in common.
+//!
+//! The lowering often ends both branches of an `OP_IF ... OP_ELSE ... OP_ENDIF` with the same
+//! code, such as the same flag or the same user code, which then only needs to be written once
+//! after the `OP_ENDIF`. The same holds for the arms of a switch.
+//!
+//! A common prefix is only moved before the conditional if it does not touch the main stack,
+//! such as `OP_NOP1`, since the condition is still on the stack before `OP_IF`. This excludes
+//! `OP_CODESEPARATOR`, which changes what the signatures after it commit to.
+
+use crate::stack_effect::{opcode_stack_effect, StackEffect};
+use crate::structured_script::{Node, OwnedInstruction, OwnedInstructions, StructuredScript};
+use bitcoin::opcodes::all::OP_CODESEPARATOR;
+
+/// Hoist the code common to the branches out of every conditional and switch, innermost
+/// first, and return the number of instructions and conditionals hoisted.
+pub fn hoist_common_code(structure: &mut StructuredScript) -> usize {
+    let (hoisted, count) = std::mem::take(structure).fold_regions(|node| {
+        let mut count: usize = node.children().iter().map(|(_, count)| count).sum();
+        let mut structure = StructuredScript::from(node.map(|(v, _)| v));
+        count += hoist_node(&mut structure);
+        (structure, count)
+    });
+    *structure = hoisted;
+    count
+}
+
+/// Hoist the code common to the branches of a conditional or a switch, whose inner
+/// conditionals have been hoisted already, and return the number of items hoisted.
+fn hoist_node(structure: &mut StructuredScript) -> usize {
+    let (prefix, suffix) = match structure {
+        StructuredScript::IfElseEndIf(v1, v2) | StructuredScript::NotIfElseEndIf(v1, v2) => {
+            let mut branches = [std::mem::take(&mut **v1), std::mem::take(&mut **v2)];
+            let hoisted = hoist(&mut branches);
+            let [b1, b2] = branches;
+            **v1 = b1;
+            **v2 = b2;
+            hoisted
+        }
+        StructuredScript::Switch(arms) if arms.len() > 1 => hoist(arms),
+        _ => (vec![], vec![]),
+    };
+    if prefix.is_empty() && suffix.is_empty() {
+        return 0;
+    }
+    let count = prefix.len() + suffix.len();
+
+    let mut items = prefix;
+    items.push(drop_empty_branch(std::mem::take(structure)));
+    items.extend(suffix);
+    *structure = from_items(items);
+    count
+}
+
+/// An instruction or a conditional of a branch.
+#[derive(PartialEq)]
+enum Item<'a> {
+    Instruction(&'a OwnedInstruction),
+    Conditional(&'a StructuredScript),
+}
+
+/// Remove the common prefix and suffix from the branches, and return them.
+fn hoist(branches: &mut [StructuredScript]) -> (Vec<StructuredScript>, Vec<StructuredScript>) {
+    let (prefix_len, suffix_len) = {
+        let items: Vec<Vec<Item>> = branches.iter().map(items).collect();
+        let min_len = items.iter().map(Vec::len).min().unwrap_or(0);
+        // the other branches are compared with the first one, which is not compared with
+        // itself, as that would walk through all of its conditionals
+        let (first, others) = items.split_first().unwrap();
+
+        let mut prefix_len = 0;
+        while prefix_len < min_len
+            && is_stack_neutral(&first[prefix_len])
+            && others.iter().all(|v| v[prefix_len] == first[prefix_len])
+        {
+            prefix_len += 1;
+        }
+        let mut suffix_len = 0;
+        while prefix_len + suffix_len < min_len
+            && others
+                .iter()
+                .all(|v| v[v.len() - 1 - suffix_len] == first[first.len() - 1 - suffix_len])
+        {
+            suffix_len += 1;
+        }
+        (prefix_len, suffix_len)
+    };
+    if prefix_len == 0 && suffix_len == 0 {
+        return (vec![], vec![]);
+    }
+
+    // the branches are taken apart, and the common code is kept from the first one
+    let mut prefix = vec![];
+    let mut suffix = vec![];
+    for (i, branch) in branches.iter_mut().enumerate() {
+        let mut items = to_items(std::mem::take(branch));
+        let common_suffix = items.split_off(items.len() - suffix_len);
+        let rest = items.split_off(prefix_len);
+        if i == 0 {
+            prefix = items;
+            suffix = common_suffix;
+        }
+        *branch = from_items(rest);
+    }
+    (prefix, suffix)
+}
+
+/// Turn a conditional with an empty branch into one without `OP_ELSE`.
+fn drop_empty_branch(structure: StructuredScript) -> StructuredScript {
+    let is_empty =
+        |v: &StructuredScript| matches!(v, StructuredScript::Script(v) if v.0.is_empty());
+    match structure.into_node() {
+        Node::IfElseEndIf(v1, v2) if is_empty(&v2) => Node::IfEndIf(v1),
+        Node::IfElseEndIf(v1, v2) if is_empty(&v1) => Node::NotIfEndIf(v2),
+        Node::NotIfElseEndIf(v1, v2) if is_empty(&v2) => Node::NotIfEndIf(v1),
+        Node::NotIfElseEndIf(v1, v2) if is_empty(&v1) => Node::IfEndIf(v2),
+        node => node,
+    }
+    .into()
+}
+
+/// Whether an item can be executed before the condition of `OP_IF` is removed from the stack
+/// instead of after.
+fn is_stack_neutral(item: &Item) -> bool {
+    match item {
+        Item::Instruction(OwnedInstruction::Op(op)) => {
+            *op != OP_CODESEPARATOR
+                && opcode_stack_effect(*op)
+                    == Some(StackEffect {
+                        pops: 0,
+                        pushes: 0,
+                        alt_pops: 0,
+                        alt_pushes: 0,
+                    })
+        }
+        _ => false,
+    }
+}
+
+/// The instructions and the conditionals of the structure, in order.
+fn items(structure: &StructuredScript) -> Vec<Item<'_>> {
+    let mut items = vec![];
+    // the sequences left, the next one last
+    let mut stack = vec![structure];
+    while let Some(structure) = stack.pop() {
+        match structure {
+            StructuredScript::Script(v) => items.extend(v.0.iter().map(Item::Instruction)),
+            StructuredScript::MultiScript(vv) => stack.extend(vv.iter().rev()),
+            structure => items.push(Item::Conditional(structure)),
+        }
+    }
+    items
+}
+
+/// Same as `items`, taking the structure apart, with each instruction as a `Script` of its
+/// own.
+fn to_items(structure: StructuredScript) -> Vec<StructuredScript> {
+    let mut items = vec![];
+    let mut stack = vec![structure];
+    while let Some(structure) = stack.pop() {
+        match structure.into_node() {
+            Node::Script(v) => items.extend(
+                v.0.into_iter()
+                    .map(|inst| StructuredScript::Script(OwnedInstructions(vec![inst]))),
+            ),
+            Node::MultiScript(vv) => stack.extend(vv.into_iter().rev()),
+            node => items.push(node.into()),
+        }
+    }
+    items
+}
+
+/// The structure of the items, with the instructions next to each other merged.
+fn from_items(items: Vec<StructuredScript>) -> StructuredScript {
+    let mut res: Vec<StructuredScript> = vec![];
+    for item in items {
+        match (res.last_mut(), item.into_node()) {
+            (Some(StructuredScript::Script(last)), Node::Script(v)) => last.0.extend(v.0),
+            (_, item) => res.push(item.into()),
+        }
+    }
+
+    match res.len() {
+        0 => StructuredScript::Script(OwnedInstructions(vec![])),
+        1 => res.pop().unwrap(),
+        _ => StructuredScript::MultiScript(res),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hoist::hoist_common_code;
+    use crate::structured_script::StructuredScript;
+    use crate::test_util::Xorshift64;
+    use crate::{OP_CASE, OP_ENDSWITCH, OP_SWITCH};
+    use bitcoin::ScriptBuf;
+    use bitcoin_script::{define_pushable, script};
+    use bitcoin_scriptexec::execute_script_with_witness;
+
+    define_pushable!();
+
+    fn hoist(script: ScriptBuf) -> (ScriptBuf, usize) {
+        let mut structure: StructuredScript = script.into();
+        let count = hoist_common_code(&mut structure);
+        (structure.into(), count)
+    }
+
+    #[test]
+    fn test_hoist_common_code() {
+        let script = script! {
+            OP_IF
+                OP_NOP1 OP_DUP 0
+            OP_ELSE
+                OP_NOP2 OP_DUP 0
+            OP_ENDIF
+        };
+        let expected = script! {
+            OP_IF OP_NOP1 OP_ELSE OP_NOP2 OP_ENDIF
+            OP_DUP 0
+        };
+        assert_eq!(hoist(script), (expected, 2));
+
+        // OP_DROP would remove the condition instead if it was moved before OP_IF
+        let script = script! {
+            OP_NOTIF
+                OP_NOP1 OP_DROP OP_NOP2
+            OP_ELSE
+                OP_NOP1 OP_DROP OP_NOP3
+            OP_ENDIF
+        };
+        let expected = script! {
+            OP_NOP1
+            OP_NOTIF OP_DROP OP_NOP2 OP_ELSE OP_DROP OP_NOP3 OP_ENDIF
+        };
+        assert_eq!(hoist(script), (expected, 1));
+
+        // a branch left empty is dropped
+        let script = script! {
+            OP_IF
+                0
+            OP_ELSE
+                OP_NOP1 0
+            OP_ENDIF
+            OP_IF
+                OP_DUP
+                OP_IF OP_NOP1 OP_ENDIF
+            OP_ELSE
+                OP_IF OP_NOP1 OP_ENDIF
+            OP_ENDIF
+        };
+        let expected = script! {
+            OP_NOTIF OP_NOP1 OP_ENDIF
+            0
+            OP_IF OP_DUP OP_ENDIF
+            OP_IF OP_NOP1 OP_ENDIF
+        };
+        assert_eq!(hoist(script), (expected, 2));
+
+        // and the same for the arms of a switch
+        let script = script! {
+            OP_SWITCH
+            OP_CASE OP_NOP1 OP_2DROP
+            OP_CASE OP_NOP2 OP_2DROP
+            OP_CASE OP_2DROP
+            OP_ENDSWITCH
+        };
+        let expected = script! {
+            OP_SWITCH
+            OP_CASE OP_NOP1
+            OP_CASE OP_NOP2
+            OP_CASE
+            OP_ENDSWITCH
+            OP_2DROP
+        };
+        let expected = ScriptBuf::from(StructuredScript::from(expected));
+        assert_eq!(hoist(script), (expected, 1));
+    }
+
+    /// A generator of scripts with conditionals on the bits on the altstack, which update a
+    /// number on the main stack, and often end their branches with the same code.
+    struct Generator(Xorshift64);
+
+    impl Generator {
+        fn code(&mut self) -> ScriptBuf {
+            match self.0.next(5) {
+                0 => script! { OP_1ADD },
+                1 => script! { 3 OP_SUB },
+                2 => script! { OP_DUP OP_ADD },
+                3 => script! { OP_NEGATE },
+                _ => script! { OP_NOP1 },
+            }
+        }
+
+        fn block(&mut self, depth: usize) -> ScriptBuf {
+            let prefix = self.code();
+            let suffix = self.code();
+            let mut branch = || {
+                let nested = depth > 0 && self.0.next(2) == 0;
+                script! {
+                    if self.0.next(2) == 0 { { prefix.clone() } }
+                    { self.code() }
+                    if nested { { self.block(depth - 1) } }
+                    if self.0.next(3) != 0 { { suffix.clone() } }
+                }
+            };
+            let (then_branch, else_branch) = (branch(), branch());
+            script! {
+                OP_FROMALTSTACK
+                OP_IF { then_branch } OP_ELSE { else_branch } OP_ENDIF
+            }
+        }
+    }
+
+    #[test]
+    fn test_hoist_execution() {
+        const BITS: usize = 6;
+
+        let mut generator = Generator(Xorshift64::new(0x2545f4914f6cdd1d));
+        let mut total = 0;
+        for _ in 0..20 {
+            let body = script! {
+                { generator.block(2) }
+                { generator.block(2) }
+            };
+            let (hoisted, count) = hoist(body.clone());
+            total += count;
+            assert!(hoisted.len() <= body.len());
+
+            for bits in 0..1u32 << BITS {
+                let witness: Vec<Vec<u8>> = (0..BITS)
+                    .map(|i| if bits >> i & 1 == 1 { vec![1] } else { vec![] })
+                    .collect();
+                for target in -24..=24i64 {
+                    let run = |body: &ScriptBuf| {
+                        let script = script! {
+                            for _ in 0..BITS { OP_TOALTSTACK }
+                            0
+                            { body.clone() }
+                            { target } OP_NUMEQUAL
+                        };
+                        execute_script_with_witness(script, witness.clone()).success
+                    };
+                    assert_eq!(run(&body), run(&hoisted));
+                }
+            }
+        }
+        assert!(total > 20);
+    }
+}
@@ -0,0 +1,336 @@
+//! Reuse of reductions across compilations.
+//!
+//! The reduction of a conditional only depends on the conditional itself, not on the code
+//! around it, so it can be memoized by the hash of the subtree. When one gadget of a large
+//! script is edited, the conditionals that are not affected are taken from the memo instead
+//! of being reduced again. Subtrees without any pseudo opcode are left unchanged by the
+//! reduction and are skipped altogether.
+
+use crate::reduce::{reduce_with_memo, EmitOpIfSuccess, Lookup, ReduceMemo};
+use crate::structured_script::{StructuredScript, WalkEvent};
+use bitcoin::hashes::sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IncrementalStats {
+    /// Subtrees without any pseudo opcode, which are skipped by the reduction.
+    pub pseudo_free_subtrees: usize,
+    /// Conditionals whose reduction is taken from the memo.
+    pub reused_subtrees: usize,
+    /// Conditionals that are reduced and added to the memo.
+    pub reduced_subtrees: usize,
+}
+
+impl IncrementalStats {
+    pub(crate) fn add(&mut self, other: IncrementalStats) {
+        self.pseudo_free_subtrees += other.pseudo_free_subtrees;
+        self.reused_subtrees += other.reused_subtrees;
+        self.reduced_subtrees += other.reduced_subtrees;
+    }
+}
+
+/// Reductions of conditionals, keyed by the hash of the subtree before the reduction.
+#[derive(Debug, Default)]
+pub struct ReductionMemo {
+    entries: Mutex<HashMap<sha256::Hash, (StructuredScript, EmitOpIfSuccess)>>,
+}
+
+impl ReductionMemo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Same as `reduce`, but reusing and recording the reduction of conditionals in `memo`.
+pub fn reduce_incremental(
+    structure: &mut StructuredScript,
+    memo: &ReductionMemo,
+    stats: &mut IncrementalStats,
+) -> EmitOpIfSuccess {
+    let mut context = MemoContext {
+        memo,
+        stats,
+        branchings: branchings(structure),
+        next: 0,
+    };
+    reduce_with_memo(structure, &mut context)
+}
+
+/// A conditional or a switch of the input of the reduction.
+struct Branching {
+    hash: sha256::Hash,
+    has_pseudo_opcodes: bool,
+    /// Number of conditionals and switches inside it.
+    inner: usize,
+}
+
+fn is_branching(structure: &StructuredScript) -> bool {
+    !matches!(
+        structure,
+        StructuredScript::Script(_) | StructuredScript::MultiScript(_)
+    )
+}
+
+/// The conditionals and switches of the structure in the order of the script, which is the
+/// order in which the reduction looks them up, with their hashes computed bottom up at once.
+fn branchings(structure: &StructuredScript) -> Vec<Branching> {
+    // the position of each of them in the order of the script, in the order they are left,
+    // which is the one in which `fold_ref` visits them
+    let mut positions = vec![];
+    let mut entered = vec![];
+    for event in structure.walk() {
+        match event {
+            WalkEvent::Enter(v) if is_branching(v) => entered.push(positions.len() + entered.len()),
+            WalkEvent::Leave(v) if is_branching(v) => positions.push(entered.pop().unwrap()),
+            _ => {}
+        }
+    }
+
+    let mut branchings: Vec<Option<Branching>> = positions.iter().map(|_| None).collect();
+    let mut positions = positions.into_iter();
+    structure.fold_ref(|v, children: Vec<(sha256::Hash, bool, usize)>| {
+        let hashes: Vec<_> = children.iter().map(|(hash, _, _)| *hash).collect();
+        let hash = v.node_hash(&hashes);
+        let has_pseudo_opcodes = match v {
+            StructuredScript::Script(_) => v.contains_pseudo_opcodes(),
+            _ => children.iter().any(|(_, has, _)| *has),
+        };
+        let mut inner = children.iter().map(|(_, _, inner)| inner).sum();
+        if is_branching(v) {
+            branchings[positions.next().unwrap()] = Some(Branching {
+                hash,
+                has_pseudo_opcodes,
+                inner,
+            });
+            inner += 1;
+        }
+        (hash, has_pseudo_opcodes, inner)
+    });
+    branchings.into_iter().map(Option::unwrap).collect()
+}
+
+/// The memo is only locked for each lookup and record, so that reductions sharing it run at
+/// the same time.
+struct MemoContext<'a> {
+    memo: &'a ReductionMemo,
+    stats: &'a mut IncrementalStats,
+    branchings: Vec<Branching>,
+    /// The next conditional or switch of the input to be looked up. The ones inside a subtree
+    /// that is not reduced are skipped with it.
+    next: usize,
+}
+
+impl MemoContext<'_> {
+    /// The next conditional or switch after a sequence, if the sequence has no pseudo opcode.
+    /// Only the scripts of the sequence are read, the conditionals and switches in it are
+    /// taken from `branchings`.
+    fn skip_pseudo_free(&self, vv: &[StructuredScript]) -> Option<usize> {
+        let mut next = self.next;
+        // the sequences left, the innermost one last
+        let mut stack = vec![vv.iter()];
+        while let Some(sequence) = stack.last_mut() {
+            let Some(v) = sequence.next() else {
+                stack.pop();
+                continue;
+            };
+            match v {
+                StructuredScript::Script(_) if v.contains_pseudo_opcodes() => return None,
+                StructuredScript::Script(_) => {}
+                StructuredScript::MultiScript(vv) => stack.push(vv.iter()),
+                _ => {
+                    let branching = &self.branchings[next];
+                    if branching.has_pseudo_opcodes {
+                        return None;
+                    }
+                    next += 1 + branching.inner;
+                }
+            }
+        }
+        Some(next)
+    }
+
+    /// Look up the next conditional or switch of the input.
+    fn lookup_branching(&mut self) -> Lookup {
+        let branching = &self.branchings[self.next];
+        let after = self.next + 1 + branching.inner;
+        if !branching.has_pseudo_opcodes {
+            self.stats.pseudo_free_subtrees += 1;
+            self.next = after;
+            return Lookup::Unchanged;
+        }
+
+        let key = branching.hash;
+        if let Some((reduced, emit_result)) = self.memo.entries.lock().unwrap().get(&key) {
+            self.stats.reused_subtrees += 1;
+            self.next = after;
+            return Lookup::Reduced(reduced.clone(), *emit_result);
+        }
+        self.next += 1;
+        Lookup::Reduce(Some(key))
+    }
+}
+
+impl ReduceMemo for MemoContext<'_> {
+    fn lookup(&mut self, structure: &StructuredScript) -> Lookup {
+        let pseudo_free = match structure {
+            StructuredScript::Script(_) => !structure.contains_pseudo_opcodes(),
+            StructuredScript::MultiScript(vv) => match self.skip_pseudo_free(vv) {
+                Some(next) => {
+                    self.next = next;
+                    true
+                }
+                None => false,
+            },
+            _ => return self.lookup_branching(),
+        };
+        if pseudo_free {
+            self.stats.pseudo_free_subtrees += 1;
+            return Lookup::Unchanged;
+        }
+        Lookup::Reduce(None)
+    }
+
+    fn record(
+        &mut self,
+        key: sha256::Hash,
+        reduced: &StructuredScript,
+        emit_result: EmitOpIfSuccess,
+    ) {
+        self.memo
+            .entries
+            .lock()
+            .unwrap()
+            .insert(key, (reduced.clone(), emit_result));
+        self.stats.reduced_subtrees += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compile::{compile, compile_with_options, CompileOptions};
+    use crate::incremental::ReductionMemo;
+    use crate::{
+        OP_CASE, OP_ENDSWITCH, OP_IF_RETURN_TRUE, OP_NOTIF_RETURN_TRUE, OP_RETURN_TRUE, OP_SWITCH,
+    };
+    use bitcoin::ScriptBuf;
+    use bitcoin_script::{define_pushable, script};
+    use std::sync::Arc;
+
+    define_pushable!();
+
+    fn gadget(i: u32) -> ScriptBuf {
+        script! {
+            OP_DUP { i } OP_EQUAL
+            OP_IF
+                OP_DUP OP_SIZE OP_NIP
+                { 32 + i } OP_EQUAL OP_IF_RETURN_TRUE
+                OP_RETURN_TRUE
+            OP_ENDIF
+            OP_DUP OP_SHA256 OP_DROP
+        }
+    }
+
+    #[test]
+    fn test_incremental_reduction() {
+        let original = script! {
+            for i in 0..5 {
+                { gadget(i) }
+            }
+            OP_RETURN
+        };
+        let edited = script! {
+            for i in [0, 1, 2, 100, 4] {
+                { gadget(i) }
+            }
+            OP_RETURN
+        };
+
+        let options = CompileOptions {
+            reduction_memo: Some(Arc::new(ReductionMemo::new())),
+            ..Default::default()
+        };
+
+        let first = compile_with_options(&original, &options).unwrap();
+        assert_eq!(first.script, compile(&original).unwrap().script);
+        assert_eq!(first.report.incremental.reused_subtrees, 0);
+        assert_eq!(first.report.incremental.reduced_subtrees, 5);
+        assert!(first.report.incremental.pseudo_free_subtrees > 0);
+
+        let second = compile_with_options(&original, &options).unwrap();
+        assert_eq!(second.script, first.script);
+        assert_eq!(second.report.incremental.reduced_subtrees, 0);
+
+        let third = compile_with_options(&edited, &options).unwrap();
+        assert_eq!(third.script, compile(&edited).unwrap().script);
+        assert_eq!(third.report.incremental.reduced_subtrees, 1);
+        assert_eq!(third.report.incremental.reused_subtrees, 4);
+    }
+
+    #[test]
+    fn test_incremental_nested_reduction() {
+        // conditionals with sites around ones without, and a switch, so that the lookups skip
+        // the subtrees that are left as they are
+        let nested = |i: u32| {
+            script! {
+                OP_DUP { i } OP_EQUAL
+                OP_IF
+                    OP_DUP OP_IF OP_DUP OP_DROP OP_ELSE OP_DUP OP_SHA256 OP_DROP OP_ENDIF
+                    OP_DUP
+                    OP_SWITCH
+                    OP_CASE
+                        { 32 + i } OP_EQUAL OP_IF_RETURN_TRUE
+                    OP_CASE
+                        OP_DUP OP_IF OP_DROP OP_ENDIF
+                    OP_CASE
+                        OP_DUP OP_IF
+                            { 64 + i } OP_EQUAL OP_NOTIF_RETURN_TRUE
+                        OP_ELSE
+                            OP_DROP
+                        OP_ENDIF
+                    OP_ENDSWITCH
+                OP_ENDIF
+                OP_DUP OP_NOTIF OP_DUP OP_DROP OP_ENDIF
+            }
+        };
+        let original = script! {
+            for i in 0..3 {
+                { nested(i) }
+            }
+            OP_RETURN
+        };
+        let edited = script! {
+            for i in [0, 100, 2] {
+                { nested(i) }
+            }
+            OP_RETURN
+        };
+
+        let options = CompileOptions {
+            reduction_memo: Some(Arc::new(ReductionMemo::new())),
+            ..Default::default()
+        };
+
+        let first = compile_with_options(&original, &options).unwrap();
+        assert_eq!(first.script, compile(&original).unwrap().script);
+        assert_eq!(first.report.incremental.reused_subtrees, 0);
+
+        let second = compile_with_options(&original, &options).unwrap();
+        assert_eq!(second.script, first.script);
+        assert_eq!(second.report.incremental.reduced_subtrees, 0);
+        assert_eq!(second.report.incremental.reused_subtrees, 3);
+
+        let third = compile_with_options(&edited, &options).unwrap();
+        assert_eq!(third.script, compile(&edited).unwrap().script);
+        assert_eq!(third.report.incremental.reused_subtrees, 2);
+        assert!(third.report.incremental.reduced_subtrees > 0);
+    }
+}
@@ -1,13 +1,26 @@
 use crate::code_cleanup::find_op_return_true_cleanup;
-use crate::final_emit::append_final_emit_script;
+use crate::compile::{
+    compile, compile_many, compile_with_options, CompileError, CompileOptions, CompileWarning,
+    LoweringStrategy,
+};
+use crate::depth_analysis::BranchTaken;
+use crate::final_emit::{append_final_emit_script, FallThrough, StackCleanup, SuccessEpilogue};
+use crate::flag_block::TailChoice;
+use crate::incremental::ReductionMemo;
 use crate::op_return_true_to_op_if_return_true::op_return_true_to_op_if_return_true;
 use crate::reduce::{reduce, EmitOpIfSuccess};
 use crate::structured_script::StructuredScript;
-use crate::{OP_IF_RETURN_TRUE, OP_RETURN_TRUE};
+use crate::{
+    OP_CASE, OP_ENDSWITCH, OP_IF_RETURN_TRUE, OP_NOTIF_RETURN_TRUE, OP_RETURN_RESULT,
+    OP_RETURN_TRUE, OP_SITE_LABEL, OP_SWITCH,
+};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::opcodes::all::OP_CAT;
 use bitcoin::ScriptBuf;
 use bitcoin_script::{define_pushable, script};
 use bitcoin_scriptexec::execute_script_with_witness;
-use bitcoin_scriptexec::ExecError::OpReturn;
+use bitcoin_scriptexec::ExecError::{EqualVerify, MinimalIf, OpReturn, StackSize};
+use std::sync::Arc;
 
 define_pushable!();
 
@@ -49,7 +62,12 @@ fn test_success() {
     let emit = reduce(&mut structured_script);
     assert_eq!(emit, EmitOpIfSuccess::YES);
 
-    append_final_emit_script(&mut structured_script);
+    append_final_emit_script(
+        &mut structured_script,
+        &SuccessEpilogue::default(),
+        FallThrough::UserDefined,
+        StackCleanup::Full,
+    );
 
     let script: ScriptBuf = structured_script.into();
     let res = execute_script_with_witness(script.clone(), vec![vec![0x11, 0x27]]);
@@ -66,3 +84,886 @@ fn test_success() {
     assert_eq!(res.success, false);
     assert_eq!(res.error, Some(OpReturn));
 }
+
+#[test]
+fn test_notif_success() {
+    let script = script! {
+        OP_DUP 10001 OP_NUMNOTEQUAL OP_NOTIF_RETURN_TRUE
+        OP_DUP 10002 OP_NUMNOTEQUAL
+        OP_NOTIF
+            OP_RETURN_TRUE
+        OP_ENDIF
+        OP_DUP 10003 OP_NUMNOTEQUAL OP_NOTIF_RETURN_TRUE
+        OP_RETURN
+    };
+
+    let script = compile(&script).unwrap().script;
+
+    for witness in [vec![0x11, 0x27], vec![0x12, 0x27], vec![0x13, 0x27]] {
+        let res = execute_script_with_witness(script.clone(), vec![witness]);
+        assert!(res.success);
+    }
+
+    let res = execute_script_with_witness(script, vec![vec![0x14, 0x27]]);
+    assert!(!res.success);
+    assert_eq!(res.error, Some(OpReturn));
+}
+
+#[test]
+fn test_return_result() {
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DUP 10002 OP_GREATERTHAN
+        OP_IF
+            // the verdict is whether the element is 10004
+            OP_DUP 10004 OP_EQUAL
+            OP_RETURN_RESULT
+        OP_ENDIF
+        OP_RETURN
+    };
+
+    let script = compile(&script).unwrap().script;
+
+    let res = execute_script_with_witness(script.clone(), vec![vec![0x11, 0x27]]);
+    assert!(res.success);
+
+    let res = execute_script_with_witness(script.clone(), vec![vec![0x14, 0x27]]);
+    assert!(res.success);
+
+    let res = execute_script_with_witness(script.clone(), vec![vec![0x13, 0x27]]);
+    assert!(!res.success);
+    // `OP_EQUAL OP_VERIFY` is merged by the peephole optimization
+    assert_eq!(res.error, Some(EqualVerify));
+
+    let res = execute_script_with_witness(script, vec![vec![0x12, 0x27]]);
+    assert!(!res.success);
+    assert_eq!(res.error, Some(OpReturn));
+}
+
+#[test]
+fn test_epilogue() {
+    // the preimage is moved to the altstack before any success site
+    let script = script! {
+        OP_TOALTSTACK
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+
+    let options = CompileOptions {
+        epilogue: SuccessEpilogue::sha256_preimage(sha256::Hash::hash(b"preimage")),
+        ..Default::default()
+    };
+    let script = compile_with_options(&script, &options).unwrap().script;
+
+    let res =
+        execute_script_with_witness(script.clone(), vec![vec![0x11, 0x27], b"preimage".to_vec()]);
+    assert!(res.success);
+
+    let res = execute_script_with_witness(script, vec![vec![0x11, 0x27], b"other".to_vec()]);
+    assert!(!res.success);
+}
+
+#[test]
+fn test_fall_through() {
+    // without any success site firing, the script ends with a true element on the stack
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DROP 1
+    };
+
+    let user_defined = compile(&script).unwrap();
+    assert!(user_defined.report.warnings.is_empty());
+    let res = execute_script_with_witness(user_defined.script, vec![vec![0x12, 0x27]]);
+    assert!(res.success);
+
+    let options = CompileOptions {
+        fall_through: FallThrough::AlwaysFail,
+        ..Default::default()
+    };
+    let always_fail = compile_with_options(&script, &options).unwrap();
+    assert_eq!(
+        always_fail.report.warnings,
+        vec![CompileWarning::FallThroughMaySucceed]
+    );
+
+    let res = execute_script_with_witness(always_fail.script.clone(), vec![vec![0x12, 0x27]]);
+    assert!(!res.success);
+    let res = execute_script_with_witness(always_fail.script, vec![vec![0x11, 0x27]]);
+    assert!(res.success);
+
+    // a script without success sites, which already ends with OP_RETURN
+    let script = script! { OP_DROP OP_RETURN };
+    let compiled = compile_with_options(&script, &options).unwrap();
+    assert!(compiled.report.warnings.is_empty());
+    assert_eq!(compiled.script, script);
+    // the condition of a trailing site is passed up as the flag, which MINIMALIF only
+    // accepts as 0 or 1 in both modes
+    let script = script! { OP_DUP OP_IF_RETURN_TRUE };
+    for fall_through in [FallThrough::UserDefined, FallThrough::AlwaysFail] {
+        let options = CompileOptions {
+            fall_through,
+            ..Default::default()
+        };
+        let compiled = compile_with_options(&script, &options).unwrap();
+        let res = execute_script_with_witness(compiled.script.clone(), vec![vec![0x01]]);
+        assert!(res.success);
+        let res = execute_script_with_witness(compiled.script, vec![vec![0x02]]);
+        assert!(!res.success);
+        assert_eq!(res.error, Some(MinimalIf));
+    }
+}
+
+#[test]
+fn test_dead_code() {
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("first") }
+        0
+        OP_IF
+            OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("guarded") }
+        OP_ENDIF
+        OP_DUP 10003 OP_EQUAL OP_IF_RETURN_TRUE
+        0 OP_VERIFY
+        OP_DUP 10004 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("unreachable") }
+        OP_RETURN
+    };
+    let live = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("first") }
+        OP_DUP 10003 OP_EQUAL OP_IF_RETURN_TRUE
+        0 OP_VERIFY
+    };
+
+    let compiled = compile(&script).unwrap();
+    assert_eq!(
+        compiled.report.warnings,
+        vec![
+            CompileWarning::DeadSuccessSite {
+                index: 1,
+                label: Some("guarded".to_string()),
+            },
+            CompileWarning::DeadSuccessSite {
+                index: 3,
+                label: Some("unreachable".to_string()),
+            },
+        ]
+    );
+    assert_eq!(
+        compiled.report.site_labels,
+        vec![Some("first".to_string()), None]
+    );
+    assert_eq!(compiled.script, compile(&live).unwrap().script);
+
+    // 10001 to 10004
+    for low in 0x11..0x15u8 {
+        let res = execute_script_with_witness(compiled.script.clone(), vec![vec![low, 0x27]]);
+        assert_eq!(res.success, low == 0x11 || low == 0x13);
+    }
+}
+
+#[test]
+fn test_constant_folding() {
+    // negative zero is false under CastToBool, but a site fails on it under MINIMALIF, so it
+    // is kept as it is instead of being folded
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        { vec![0x80] } OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+    let compiled = compile(&script).unwrap();
+    assert!(compiled.report.warnings.is_empty());
+    let res = execute_script_with_witness(compiled.script.clone(), vec![vec![0x12, 0x27]]);
+    assert!(!res.success);
+    assert_eq!(res.error, Some(MinimalIf));
+    let res = execute_script_with_witness(compiled.script, vec![vec![0x11, 0x27]]);
+    assert!(res.success);
+
+    // a constant site fails the same whether the constant is right before it or not
+    for strategy in [
+        LoweringStrategy::Nested,
+        LoweringStrategy::Altstack,
+        LoweringStrategy::FlatGuard,
+    ] {
+        let options = CompileOptions {
+            strategy,
+            ..Default::default()
+        };
+        for script in [
+            script! { 2 OP_IF_RETURN_TRUE OP_RETURN },
+            script! { 2 OP_NOP1 OP_IF_RETURN_TRUE OP_RETURN },
+        ] {
+            let compiled = compile_with_options(&script, &options).unwrap();
+            let res = execute_script_with_witness(compiled.script, vec![]);
+            assert!(!res.success);
+            assert_eq!(res.error, Some(MinimalIf));
+        }
+    }
+
+    // the comparison of constants selects the branch
+    let script = script! {
+        2 3 OP_ADD 5 OP_NUMEQUAL
+        OP_IF
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_ELSE
+            OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_ENDIF
+        OP_RETURN
+    };
+    let folded = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+    let compiled = compile(&script).unwrap();
+    assert_eq!(compiled.script, compile(&folded).unwrap().script);
+    assert_eq!(compiled.report.success_sites, 1);
+    for (low, success) in [(0x11, true), (0x12, false)] {
+        let res = execute_script_with_witness(compiled.script.clone(), vec![vec![low, 0x27]]);
+        assert_eq!(res.success, success);
+    }
+}
+
+#[test]
+fn test_common_code() {
+    // both branches end with the same check, which is written once after the OP_ENDIF
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DUP 10002 OP_EQUAL
+        OP_IF
+            OP_1ADD OP_SHA256 OP_DUP { vec![0x12; 32] } OP_EQUAL OP_IF_RETURN_TRUE
+        OP_ELSE
+            OP_1SUB OP_SHA256 OP_DUP { vec![0x12; 32] } OP_EQUAL OP_IF_RETURN_TRUE
+        OP_ENDIF
+        OP_RETURN
+    };
+    let hoisted = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DUP 10002 OP_EQUAL
+        OP_IF OP_1ADD OP_ELSE OP_1SUB OP_ENDIF
+        OP_SHA256 OP_DUP { vec![0x12; 32] } OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+    let compiled = compile(&script).unwrap();
+    assert_eq!(compiled.script, compile(&hoisted).unwrap().script);
+
+    for witness in [vec![0x11, 0x27], vec![0x12, 0x27], vec![0x13, 0x27]] {
+        let res = execute_script_with_witness(compiled.script.clone(), vec![witness.clone()]);
+        assert_eq!(res.success, witness == vec![0x11, 0x27]);
+    }
+}
+
+#[test]
+fn test_switch() {
+    // the disputed step is selected by the top element of the witness
+    let script = script! {
+        OP_SWITCH
+        OP_CASE
+            10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_CASE
+            OP_DUP 10002 OP_EQUAL OP_IF OP_RETURN_TRUE OP_ENDIF
+            OP_DROP
+        OP_CASE
+            10003 OP_EQUAL OP_NOTIF_RETURN_TRUE
+        OP_ENDSWITCH
+        OP_RETURN
+    };
+
+    let script = compile(&script).unwrap().script;
+
+    for (value, step, success) in [
+        (0x11, 0, true),
+        (0x12, 0, false),
+        (0x12, 1, true),
+        (0x11, 1, false),
+        (0x11, 2, true),
+        (0x13, 2, false),
+        (0x11, 3, false),
+    ] {
+        let step = if step == 0 { vec![] } else { vec![step] };
+        let res = execute_script_with_witness(script.clone(), vec![vec![value, 0x27], step]);
+        assert_eq!(res.success, success);
+    }
+}
+
+#[test]
+fn test_flag_blocks() {
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DUP 10005 OP_LESSTHAN
+        OP_IF
+            OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_DUP 10003 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_DUP 10004 OP_EQUAL OP_NOTIF_RETURN_TRUE
+        OP_ELSE
+            OP_DUP 10006 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_ENDIF
+        OP_DUP 10007 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+
+    // the script as lowered before the flag blocks are removed
+    let mut structured_script: StructuredScript = script.clone().into();
+    find_op_return_true_cleanup(&mut structured_script);
+    op_return_true_to_op_if_return_true(&mut structured_script);
+    assert_eq!(reduce(&mut structured_script), EmitOpIfSuccess::YES);
+    append_final_emit_script(
+        &mut structured_script,
+        &SuccessEpilogue::default(),
+        FallThrough::UserDefined,
+        StackCleanup::Full,
+    );
+    let reference: ScriptBuf = structured_script.into();
+
+    let compiled = compile(&script).unwrap().script;
+    assert!(compiled.len() < reference.len());
+
+    for i in 10000..10009u32 {
+        let witness = vec![i.to_le_bytes()[..2].to_vec()];
+        let expected = execute_script_with_witness(reference.clone(), witness.clone());
+        let res = execute_script_with_witness(compiled.clone(), witness);
+        assert_eq!(res.success, expected.success);
+        assert_eq!(res.error, expected.error);
+    }
+}
+
+#[test]
+fn test_tail_duplication() {
+    // a short tail after a conditional whose branches both have code after a success site
+    let script = script! {
+        OP_DUP 10005 OP_LESSTHAN
+        OP_IF
+            OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_DUP 10003 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_1SUB
+        OP_ELSE
+            OP_DUP 10006 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_1ADD
+        OP_ENDIF
+        OP_DROP
+        OP_RETURN
+    };
+
+    let duplicated = compile(&script).unwrap();
+    assert_eq!(
+        duplicated.report.tails,
+        vec![TailChoice {
+            size: 3,
+            branches: 2,
+            duplicated: true
+        }]
+    );
+    let duplicated = duplicated.script;
+
+    // the flag threaded through the tail instead
+    let options = CompileOptions {
+        max_tail_size: Some(0),
+        ..Default::default()
+    };
+    let threaded = compile_with_options(&script, &options).unwrap().script;
+    assert!(duplicated.len() < threaded.len());
+
+    for i in 10000..10008u32 {
+        let witness = vec![i.to_le_bytes()[..2].to_vec()];
+        let expected = execute_script_with_witness(threaded.clone(), witness.clone());
+        let res = execute_script_with_witness(duplicated.clone(), witness);
+        assert_eq!(res.success, expected.success);
+        assert_eq!(res.error, expected.error);
+    }
+}
+
+#[test]
+fn test_altstack_strategy() {
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DUP 10010 OP_LESSTHAN
+        OP_IF
+            for i in 2..6 {
+                OP_DUP { 10000 + i } OP_EQUAL OP_IF_RETURN_TRUE
+            }
+            // the depth is the same with both strategies
+            OP_DEPTH 1 OP_EQUAL OP_NOTIF_RETURN_TRUE
+        OP_ELSE
+            OP_DUP 10011 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_ENDIF
+        OP_DUP 10006 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+
+    let nested = compile(&script).unwrap();
+    let options = CompileOptions {
+        strategy: LoweringStrategy::Altstack,
+        ..Default::default()
+    };
+    let altstack = compile_with_options(&script, &options).unwrap();
+    // the script is only lowered once unless the comparison is asked for
+    assert_eq!(altstack.report.lowerings.len(), 1);
+
+    let options = CompileOptions {
+        strategy: LoweringStrategy::Altstack,
+        compare_with_nested: true,
+        ..Default::default()
+    };
+    let compared = compile_with_options(&script, &options).unwrap();
+    assert_eq!(compared.script, altstack.script);
+    assert_eq!(compared.report.lowerings[0], altstack.report.lowerings[0]);
+
+    let lowerings = &compared.report.lowerings;
+    assert_eq!(lowerings.len(), 2);
+    assert_eq!(lowerings[0].strategy, LoweringStrategy::Altstack);
+    assert_eq!(lowerings[0].size, altstack.script.len());
+    assert_eq!(lowerings[1].strategy, LoweringStrategy::Nested);
+    assert_eq!(lowerings[1].size, nested.script.len());
+    assert_eq!(lowerings[1], nested.report.lowerings[0]);
+    assert!(lowerings[0].nesting_depth < lowerings[1].nesting_depth);
+
+    for i in 10000..10013u32 {
+        let witness = vec![i.to_le_bytes()[..2].to_vec()];
+        let expected = execute_script_with_witness(nested.script.clone(), witness.clone());
+        let res = execute_script_with_witness(altstack.script.clone(), witness);
+        assert_eq!(res.success, expected.success);
+    }
+
+    // with an extra element, the OP_DEPTH check fails, which is a success site
+    let witness = vec![vec![], vec![0x09, 0x27]];
+    assert!(execute_script_with_witness(nested.script, witness.clone()).success);
+    assert!(execute_script_with_witness(altstack.script, witness).success);
+}
+
+#[test]
+fn test_flat_guard_strategy() {
+    let script = script! {
+        OP_DUP 10010 OP_LESSTHAN
+        OP_IF
+            for i in 0..10 {
+                OP_DUP { 10000 + i } OP_EQUAL OP_IF_RETURN_TRUE
+            }
+        OP_ELSE
+            OP_DUP 10011 OP_EQUAL OP_NOTIF_RETURN_TRUE
+        OP_ENDIF
+        OP_DUP 10012 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+
+    let options = CompileOptions {
+        max_nesting_depth: Some(6),
+        ..Default::default()
+    };
+    assert!(matches!(
+        compile_with_options(&script, &options),
+        Err(CompileError::NestingTooDeep { max: 6, .. })
+    ));
+
+    let nested = compile(&script).unwrap();
+    let options = CompileOptions {
+        strategy: LoweringStrategy::FlatGuard,
+        max_nesting_depth: Some(6),
+        compare_with_nested: true,
+        ..Default::default()
+    };
+    let flat_guard = compile_with_options(&script, &options).unwrap();
+
+    let lowerings = &flat_guard.report.lowerings;
+    assert_eq!(lowerings[0].strategy, LoweringStrategy::FlatGuard);
+    assert_eq!(lowerings[1], nested.report.lowerings[0]);
+    // the final emit code nests two levels
+    assert_eq!(lowerings[0].nesting_depth, 2);
+    assert_eq!(lowerings[1].nesting_depth, 10);
+
+    for i in 10000..10014u32 {
+        let witness = vec![i.to_le_bytes()[..2].to_vec()];
+        let expected = execute_script_with_witness(nested.script.clone(), witness.clone());
+        let res = execute_script_with_witness(flat_guard.script.clone(), witness);
+        assert_eq!(res.success, expected.success);
+    }
+}
+
+#[test]
+fn test_auto_strategy() {
+    // a region where the sites are in a sequence, and one with a single site
+    let region = script! {
+        OP_DUP 10010 OP_LESSTHAN
+        OP_IF
+            for i in 0..10 {
+                OP_DUP { 10000 + i } OP_EQUAL OP_IF_RETURN_TRUE
+            }
+            OP_1ADD
+        OP_ELSE
+            OP_DUP 10011 OP_EQUAL OP_NOTIF_RETURN_TRUE
+            OP_1SUB
+        OP_ENDIF
+    };
+    let script = script! {
+        { region.clone() }
+        OP_DUP 10012 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+    let nested = compile(&script).unwrap();
+
+    let options = CompileOptions {
+        strategy: LoweringStrategy::Auto,
+        ..Default::default()
+    };
+    let auto = compile_with_options(&script, &options).unwrap();
+
+    // every strategy is considered, and the smallest one is kept
+    let lowerings = &auto.report.lowerings;
+    assert_eq!(lowerings.len(), 4);
+    assert_eq!(lowerings[0].size, auto.script.len());
+    assert_eq!(lowerings[0].cost, lowerings[0].size as u64);
+    assert!(lowerings
+        .iter()
+        .all(|other| other.cost >= lowerings[0].cost));
+    assert!(lowerings.contains(&nested.report.lowerings[0]));
+
+    // the shallowest one is kept when the others are too deep
+    let options = CompileOptions {
+        strategy: LoweringStrategy::Auto,
+        max_nesting_depth: Some(6),
+        ..Default::default()
+    };
+    let shallow = compile_with_options(&script, &options).unwrap();
+    assert_ne!(
+        shallow.report.lowerings[0].strategy,
+        LoweringStrategy::Nested
+    );
+    assert!(shallow.report.lowerings[0].nesting_depth <= 6);
+
+    // a region after which `OP_NOTIF` saves an `OP_NOT` is lowered with `Nested`, while the
+    // other one is too deep for it
+    let mixed = script! {
+        { region.clone() }
+        OP_DUP OP_SIZE OP_NOTIF_RETURN_TRUE
+        OP_DUP 10012 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+    let mixed = compile_with_options(&mixed, &options).unwrap();
+    let lowerings = &mixed.report.lowerings;
+    assert_eq!(lowerings[0].strategy, LoweringStrategy::Auto);
+    assert!(lowerings[0].nesting_depth <= 6);
+    assert!(lowerings
+        .iter()
+        .filter(|other| other.nesting_depth <= 6)
+        .all(|other| other.cost >= lowerings[0].cost));
+    let strategies: Vec<_> = mixed
+        .report
+        .regions
+        .iter()
+        .map(|region| region.strategy)
+        .collect();
+    assert_eq!(
+        strategies,
+        vec![LoweringStrategy::FlatGuard, LoweringStrategy::Nested]
+    );
+
+    // the altstack is checked on the input, as for an explicit strategy, even where the code
+    // that uses it is dead
+    let dead_altstack = script! {
+        { script.clone() }
+        OP_TOALTSTACK
+    };
+    let options = CompileOptions {
+        strategy: LoweringStrategy::Altstack,
+        ..Default::default()
+    };
+    assert_eq!(
+        compile_with_options(&dead_altstack, &options),
+        Err(CompileError::AltstackInUse)
+    );
+    let options = CompileOptions {
+        strategy: LoweringStrategy::Auto,
+        ..Default::default()
+    };
+    let compiled = compile_with_options(&dead_altstack, &options).unwrap();
+    assert!(compiled
+        .report
+        .lowerings
+        .iter()
+        .all(|lowering| lowering.strategy != LoweringStrategy::Altstack));
+
+    for i in 10000..10014u32 {
+        let witness = vec![i.to_le_bytes()[..2].to_vec()];
+        let expected = execute_script_with_witness(nested.script.clone(), witness.clone());
+        for compiled in [&auto, &shallow, &mixed] {
+            let res = execute_script_with_witness(compiled.script.clone(), witness.clone());
+            assert_eq!(res.success, expected.success);
+        }
+    }
+}
+
+#[test]
+fn test_exact_drop() {
+    // the witness is a value and a step, and the stack holds the same two elements at every
+    // success site
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_OVER
+        OP_IF
+            OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_ELSE
+            OP_DUP 10003 OP_EQUAL OP_NOTIF_RETURN_TRUE
+        OP_ENDIF
+        OP_RETURN
+    };
+    let full = compile(&script).unwrap();
+    assert_eq!(full.report.stack_cleanup, StackCleanup::Full);
+
+    let witnesses = [(0x11, 0), (0x12, 1), (0x12, 0), (0x13, 0), (0x13, 1)];
+    for strategy in [
+        LoweringStrategy::Nested,
+        LoweringStrategy::Altstack,
+        LoweringStrategy::FlatGuard,
+    ] {
+        let options = CompileOptions {
+            strategy,
+            witness_elements: Some(2),
+            ..Default::default()
+        };
+        let exact = compile_with_options(&script, &options).unwrap();
+        assert_eq!(exact.report.stack_cleanup, StackCleanup::Exact(2));
+        // most of the cascade is gone
+        assert!(exact.script.len() + 500 < full.script.len());
+
+        for (value, step) in witnesses {
+            let witness = vec![vec![step], vec![value, 0x27]];
+            let expected = execute_script_with_witness(full.script.clone(), witness.clone());
+            let res = execute_script_with_witness(exact.script.clone(), witness);
+            assert_eq!(res.success, expected.success);
+        }
+    }
+
+    // the sites at different depths only need the levels up to the deepest one
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DUP OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DROP OP_DUP 10003 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+    let options = CompileOptions {
+        witness_elements: Some(1),
+        ..Default::default()
+    };
+    let bounded = compile_with_options(&script, &options).unwrap();
+    assert_eq!(bounded.report.stack_cleanup, StackCleanup::Bounded(2));
+
+    for value in 0x10..0x14 {
+        let res = execute_script_with_witness(bounded.script.clone(), vec![vec![value, 0x27]]);
+        assert_eq!(res.success, (0x11..0x14).contains(&value));
+    }
+
+    // with more witness elements than declared, some are left on the stack
+    let witness = vec![vec![], vec![], vec![0x12, 0x27]];
+    let res = execute_script_with_witness(bounded.script, witness);
+    assert!(!res.success);
+}
+
+#[test]
+fn test_bounded_epilogue() {
+    // without the number of witness elements the depth cannot be proved
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IFDUP OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+    let full = compile(&script).unwrap();
+
+    let options = CompileOptions {
+        max_stack_depth: Some(4),
+        ..Default::default()
+    };
+    let guarded = compile_with_options(&script, &options).unwrap();
+    assert_eq!(guarded.report.stack_cleanup, StackCleanup::Guarded(4));
+    assert!(guarded.script.len() + 500 < full.script.len());
+
+    for extra in 0..5 {
+        let mut witness = vec![vec![]; extra];
+        witness.push(vec![0x11, 0x27]);
+        let res = execute_script_with_witness(full.script.clone(), witness.clone());
+        assert!(res.success);
+        // the site leaves the element, OP_IFDUP's copy, and the extra elements
+        let res = execute_script_with_witness(guarded.script.clone(), witness);
+        assert_eq!(res.success, extra + 2 <= 4);
+    }
+
+    // a bound that the analysis proves needs no check
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DUP OP_DUP 10002 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_RETURN
+    };
+    let options = CompileOptions {
+        witness_elements: Some(1),
+        max_stack_depth: Some(4),
+        ..Default::default()
+    };
+    let compiled = compile_with_options(&script, &options).unwrap();
+    assert_eq!(compiled.report.stack_cleanup, StackCleanup::Bounded(2));
+
+    // and one that it disproves is checked
+    let options = CompileOptions {
+        witness_elements: Some(1),
+        max_stack_depth: Some(1),
+        ..Default::default()
+    };
+    let compiled = compile_with_options(&script, &options).unwrap();
+    assert_eq!(compiled.report.stack_cleanup, StackCleanup::Guarded(1));
+}
+
+#[test]
+fn test_deep_nesting() {
+    // a thread with the default stack size, which a recursive traversal would overflow
+    std::thread::spawn(|| {
+        let depth = 100_000;
+        let script = script! {
+            for _ in 0..depth {
+                OP_DUP OP_IF
+            }
+            OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+            for _ in 0..depth {
+                OP_ENDIF
+            }
+            OP_DROP OP_RETURN
+        };
+
+        let options = CompileOptions {
+            strategy: LoweringStrategy::Auto,
+            witness_elements: Some(1),
+            ..Default::default()
+        };
+        let compiled = compile_with_options(&script, &options).unwrap();
+        assert_eq!(compiled.report.success_sites, 1);
+        assert_eq!(compiled.report.lowerings.len(), 3);
+        assert!(compiled
+            .report
+            .lowerings
+            .iter()
+            .all(|lowering| lowering.nesting_depth >= depth));
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_compile_many() {
+    // leaves with top-level regions without success sites around their sites
+    let mut leaves: Vec<ScriptBuf> = (0..16u32)
+        .map(|i| {
+            script! {
+                for j in 0..4 {
+                    OP_DUP { j } OP_EQUAL
+                    OP_IF OP_NOP1 0 OP_ELSE OP_NOP2 0 OP_ENDIF
+                    OP_DROP
+                }
+                OP_DUP { 10000 + i } OP_EQUAL OP_IF_RETURN_TRUE
+                OP_DUP OP_IF OP_NOP3 OP_ENDIF
+                OP_DUP { 20000 + i } OP_EQUAL OP_NOTIF_RETURN_TRUE
+                OP_RETURN
+            }
+        })
+        .collect();
+    leaves.push(script! { OP_IF });
+
+    let options = CompileOptions {
+        strategy: LoweringStrategy::Auto,
+        ..Default::default()
+    };
+    let expected: Vec<_> = leaves
+        .iter()
+        .map(|leaf| compile_with_options(leaf, &options))
+        .collect();
+    assert_eq!(compile_many(&leaves, &options), expected);
+    assert_eq!(
+        expected.last(),
+        Some(&Err(CompileError::UnbalancedConditional))
+    );
+
+    // the same output on any number of threads
+    #[cfg(feature = "parallel")]
+    for threads in [1, 2, 8] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        assert_eq!(pool.install(|| compile_many(&leaves, &options)), expected);
+    }
+
+    // the leaves share a memo of their reductions, whose reuse depends on the timing
+    let memo_options = CompileOptions {
+        reduction_memo: Some(Arc::new(ReductionMemo::new())),
+        ..options.clone()
+    };
+    let compiled = compile_many(&leaves, &memo_options);
+    for (compiled, expected) in compiled.iter().zip(expected.iter()) {
+        assert_eq!(
+            compiled.as_ref().map(|compiled| &compiled.script),
+            expected.as_ref().map(|expected| &expected.script)
+        );
+    }
+}
+
+#[test]
+fn test_stack_limit() {
+    // the script holds 1000 elements with the witness before the second site, just within the
+    // limit
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DUP 0 OP_GREATERTHAN
+        OP_IF
+            for _ in 0..999 {
+                OP_DUP
+            }
+            OP_ADD 20004 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("deep") }
+        OP_ENDIF
+        OP_RETURN
+    };
+    let witness = vec![vec![0x12, 0x27]];
+
+    let options = CompileOptions {
+        witness_elements: Some(1),
+        ..Default::default()
+    };
+    let nested = compile_with_options(&script, &options).unwrap();
+    assert_eq!(nested.report.max_stack_elements, Some(1000));
+    assert!(nested.report.warnings.is_empty());
+    let res = execute_script_with_witness(nested.script, witness.clone());
+    assert!(res.success);
+
+    // the flag on the altstack is one element too many
+    let options = CompileOptions {
+        strategy: LoweringStrategy::Altstack,
+        witness_elements: Some(1),
+        ..Default::default()
+    };
+    let altstack = compile_with_options(&script, &options).unwrap();
+    assert_eq!(altstack.report.max_stack_elements, Some(1001));
+    assert_eq!(
+        altstack.report.warnings,
+        vec![CompileWarning::StackLimitExceeded {
+            elements: 1001,
+            input_elements: 1000,
+            position: 1007,
+            path: vec![BranchTaken {
+                position: 7,
+                branch: 0
+            }],
+        }]
+    );
+    let res = execute_script_with_witness(altstack.script, witness);
+    assert!(!res.success);
+    assert_eq!(res.error, Some(StackSize));
+
+    // nothing is checked without the size of the witness
+    let compiled = compile(&script).unwrap();
+    assert_eq!(compiled.report.max_stack_elements, None);
+
+    // the limit cannot be checked past an opcode whose effect is not known
+    let script = script! {
+        OP_DUP 10001 OP_EQUAL OP_IF_RETURN_TRUE
+        OP_DUP OP_CAT
+        OP_RETURN
+    };
+    let compiled = compile_with_options(&script, &options).unwrap();
+    assert_eq!(compiled.report.max_stack_elements, None);
+    assert_eq!(
+        compiled.report.warnings,
+        vec![CompileWarning::StackLimitUnchecked {
+            position: 5,
+            opcode: OP_CAT
+        }]
+    );
+}
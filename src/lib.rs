@@ -1,4 +1,8 @@
-use bitcoin::opcodes::all::{OP_RETURN_199, OP_RETURN_200};
+use bitcoin::opcodes::all::{
+    OP_RETURN_199, OP_RETURN_200, OP_RETURN_201, OP_RETURN_202, OP_RETURN_203, OP_RETURN_204,
+    OP_RETURN_205, OP_RETURN_206,
+};
+use bitcoin::script::{Builder, PushBytesBuf};
 use bitcoin::{Opcode, ScriptBuf};
 
 pub mod structured_script;
@@ -9,17 +13,92 @@ pub mod op_return_true_to_op_if_return_true;
 
 pub mod reduce;
 
+pub mod altstack_flag;
+
+pub mod flat_guard;
+
+pub mod flag_block;
+
+pub mod peephole;
+
+pub mod hoist;
+
 pub mod final_emit;
 
+pub mod compile;
+
+pub mod cache;
+
+pub mod incremental;
+
+pub mod ffi;
+
+pub mod site_label;
+
+pub mod attribution;
+
+pub mod stack_effect;
+
+pub mod cost;
+
+pub mod constant;
+
+pub mod dead_code;
+
+pub mod depth_analysis;
+
+pub mod stack_limit;
+
+mod parallel;
+
+mod segment;
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod integration_test;
 
+#[cfg(test)]
+mod test_util;
+
 #[allow(non_snake_case)]
 pub const _OP_RETURN_TRUE: Opcode = OP_RETURN_199;
 
 #[allow(non_snake_case)]
 pub const _OP_IF_RETURN_TRUE: Opcode = OP_RETURN_200;
 
+#[allow(non_snake_case)]
+pub const _OP_NOTIF_RETURN_TRUE: Opcode = OP_RETURN_201;
+
+#[allow(non_snake_case)]
+pub const _OP_RETURN_RESULT: Opcode = OP_RETURN_202;
+
+#[allow(non_snake_case)]
+pub const _OP_SITE_LABEL: Opcode = OP_RETURN_203;
+
+#[allow(non_snake_case)]
+pub const _OP_SWITCH: Opcode = OP_RETURN_204;
+
+#[allow(non_snake_case)]
+pub const _OP_CASE: Opcode = OP_RETURN_205;
+
+#[allow(non_snake_case)]
+pub const _OP_ENDSWITCH: Opcode = OP_RETURN_206;
+
+/// Whether the opcode is one of the pseudo opcodes that the compiler rewrites.
+pub fn is_pseudo_opcode(opcode: Opcode) -> bool {
+    [
+        _OP_RETURN_TRUE,
+        _OP_IF_RETURN_TRUE,
+        _OP_NOTIF_RETURN_TRUE,
+        _OP_RETURN_RESULT,
+        _OP_SITE_LABEL,
+        _OP_SWITCH,
+        _OP_CASE,
+        _OP_ENDSWITCH,
+    ]
+    .contains(&opcode)
+}
+
 #[allow(non_snake_case)]
 pub fn OP_RETURN_TRUE() -> ScriptBuf {
     ScriptBuf::from_bytes(vec![OP_RETURN_199.to_u8()])
@@ -29,3 +108,43 @@ pub fn OP_RETURN_TRUE() -> ScriptBuf {
 pub fn OP_IF_RETURN_TRUE() -> ScriptBuf {
     ScriptBuf::from_bytes(vec![OP_RETURN_200.to_u8()])
 }
+
+#[allow(non_snake_case)]
+pub fn OP_NOTIF_RETURN_TRUE() -> ScriptBuf {
+    ScriptBuf::from_bytes(vec![OP_RETURN_201.to_u8()])
+}
+
+#[allow(non_snake_case)]
+pub fn OP_RETURN_RESULT() -> ScriptBuf {
+    ScriptBuf::from_bytes(vec![OP_RETURN_202.to_u8()])
+}
+
+/// Label the success pseudo opcode right before it, such as
+/// `OP_RETURN_TRUE { OP_SITE_LABEL("hash_mismatch") }`, see `crate::site_label`.
+#[allow(non_snake_case)]
+pub fn OP_SITE_LABEL(label: &str) -> ScriptBuf {
+    assert!(!label.is_empty(), "a site label cannot be empty");
+    let label = PushBytesBuf::try_from(label.as_bytes().to_vec()).expect("the label is too long");
+    Builder::new()
+        .push_slice(label)
+        .push_opcode(OP_RETURN_203)
+        .into_script()
+}
+
+/// Run the arm selected by the integer on top of the stack, which is consumed, as in
+/// `OP_SWITCH OP_CASE <arm 0> OP_CASE <arm 1> ... OP_ENDSWITCH`. The script fails if the
+/// integer is not the index of an arm.
+#[allow(non_snake_case)]
+pub fn OP_SWITCH() -> ScriptBuf {
+    ScriptBuf::from_bytes(vec![OP_RETURN_204.to_u8()])
+}
+
+#[allow(non_snake_case)]
+pub fn OP_CASE() -> ScriptBuf {
+    ScriptBuf::from_bytes(vec![OP_RETURN_205.to_u8()])
+}
+
+#[allow(non_snake_case)]
+pub fn OP_ENDSWITCH() -> ScriptBuf {
+    ScriptBuf::from_bytes(vec![OP_RETURN_206.to_u8()])
+}
@@ -1,90 +1,78 @@
-use crate::structured_script::{OwnedInstruction, OwnedInstructions, StructuredScript};
-use crate::{_OP_IF_RETURN_TRUE, _OP_RETURN_TRUE};
-use bitcoin::opcodes::all::OP_NOT;
+use crate::site_label::label_len;
+use crate::structured_script::{Node, OwnedInstruction, OwnedInstructions, StructuredScript};
+use crate::{_OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE, _OP_RETURN_TRUE};
 use bitcoin::opcodes::OP_TRUE;
 
 pub fn op_return_true_to_op_if_return_true(structure: &mut StructuredScript) {
-    match structure {
-        StructuredScript::Script(v) => {
+    // bottom up, so that a conditional sees whether its branch has been converted into an
+    // OP_RETURN_TRUE
+    *structure = std::mem::take(structure).fold(|node| match node {
+        Node::Script(v) => {
             let mut res = vec![];
-            let len = v.0.len();
 
-            for i in 0..len {
-                if v.0[i] == OwnedInstruction::Op(_OP_RETURN_TRUE) {
+            for inst in v.0 {
+                if inst == OwnedInstruction::Op(_OP_RETURN_TRUE) {
                     res.push(OwnedInstruction::Op(OP_TRUE));
                     res.push(OwnedInstruction::Op(_OP_IF_RETURN_TRUE));
                 } else {
-                    res.push(v.0[i].clone());
+                    res.push(inst);
                 }
             }
 
-            v.0 = res;
+            StructuredScript::Script(OwnedInstructions(res))
         }
-        StructuredScript::MultiScript(vv) => {
-            let mut len = vv.len();
+        Node::MultiScript(vv) => {
+            let mut res: Vec<StructuredScript> = vec![];
 
-            let mut i = 0;
-            while i < len {
-                op_return_true_to_op_if_return_true(&mut vv[i]);
-                if i > 0
-                    && matches!(vv[i - 1], StructuredScript::Script(_))
-                    && matches!(vv[i], StructuredScript::Script(_))
+            for mut v in vv {
+                if let (Some(StructuredScript::Script(v1)), StructuredScript::Script(v2)) =
+                    (res.last_mut(), &mut v)
                 {
-                    let mut instructions = vv[i].clone();
-                    match &mut vv[i - 1] {
-                        StructuredScript::Script(v1) => {
-                            if let StructuredScript::Script(v2) = &mut instructions {
-                                v1.0.append(&mut v2.0);
-                            }
-                            vv.remove(i);
-                            i -= 1;
-                            len -= 1;
-                        }
-                        _ => unreachable!(),
-                    }
+                    v1.0.append(&mut v2.0);
+                    continue;
                 }
-                i += 1;
+                res.push(v);
             }
 
-            if vv.len() == 1 {
-                let res = vv.pop().unwrap();
-                *structure = res;
+            if res.len() == 1 {
+                res.pop().unwrap()
+            } else {
+                StructuredScript::MultiScript(res)
             }
         }
-        StructuredScript::IfEndIf(v) => {
-            op_return_true_to_op_if_return_true(v);
-
-            if *v.as_ref()
-                == StructuredScript::Script(OwnedInstructions(vec![
-                OwnedInstruction::Op(OP_TRUE),
-                OwnedInstruction::Op(_OP_IF_RETURN_TRUE),
-                ]))
-            {
-                *structure =
-                    StructuredScript::Script(OwnedInstructions(vec![OwnedInstruction::Op(
-                        _OP_IF_RETURN_TRUE,
-                    )]));
+        Node::IfEndIf(v) => match op_return_true_label(&v) {
+            Some(label) => {
+                let mut res = vec![OwnedInstruction::Op(_OP_IF_RETURN_TRUE)];
+                res.extend(label);
+                StructuredScript::Script(OwnedInstructions(res))
             }
-        }
-        StructuredScript::NotIfEndIf(v) => {
-            op_return_true_to_op_if_return_true(v);
-
-            if *v.as_ref()
-                == StructuredScript::Script(OwnedInstructions(vec![
-                OwnedInstruction::Op(OP_TRUE),
-                OwnedInstruction::Op(_OP_IF_RETURN_TRUE),
-                ]))
-            {
-                *structure = StructuredScript::Script(OwnedInstructions(vec![
-                    OwnedInstruction::Op(OP_NOT),
-                    OwnedInstruction::Op(_OP_IF_RETURN_TRUE),
-                ]));
+            None => StructuredScript::IfEndIf(Box::new(v)),
+        },
+        Node::NotIfEndIf(v) => match op_return_true_label(&v) {
+            Some(label) => {
+                let mut res = vec![OwnedInstruction::Op(_OP_NOTIF_RETURN_TRUE)];
+                res.extend(label);
+                StructuredScript::Script(OwnedInstructions(res))
             }
+            None => StructuredScript::NotIfEndIf(Box::new(v)),
+        },
+        node => node.into(),
+    });
+}
+
+/// If the script is only `1 OP_IF_RETURN_TRUE`, which comes from an `OP_RETURN_TRUE`, return
+/// the label marker that follows it, which may be empty.
+fn op_return_true_label(structure: &StructuredScript) -> Option<Vec<OwnedInstruction>> {
+    match structure {
+        StructuredScript::Script(v)
+            if v.0.len() >= 2
+                && v.0[0] == OwnedInstruction::Op(OP_TRUE)
+                && v.0[1] == OwnedInstruction::Op(_OP_IF_RETURN_TRUE)
+                && v.0.len() == 2 + label_len(&v.0, 2) =>
+        {
+            Some(v.0[2..].to_vec())
         }
-        StructuredScript::IfElseEndIf(v1, v2) | StructuredScript::NotIfElseEndIf(v1, v2) => {
-            op_return_true_to_op_if_return_true(v1);
-            op_return_true_to_op_if_return_true(v2);
-        }
+        _ => None,
     }
 }
 
@@ -92,7 +80,7 @@ pub fn op_return_true_to_op_if_return_true(structure: &mut StructuredScript) {
 mod test {
     use crate::op_return_true_to_op_if_return_true::op_return_true_to_op_if_return_true;
     use crate::structured_script::StructuredScript;
-    use crate::{OP_IF_RETURN_TRUE, OP_RETURN_TRUE};
+    use crate::{OP_IF_RETURN_TRUE, OP_NOTIF_RETURN_TRUE, OP_RETURN_TRUE};
     use bitcoin_script::{define_pushable, script};
 
     define_pushable!();
@@ -115,6 +103,10 @@ mod test {
                 OP_ENDIF
             OP_ENDIF
             OP_NOP6
+            OP_NOTIF
+                OP_RETURN_TRUE
+            OP_ENDIF
+            OP_NOP7
             OP_RETURN_TRUE
         };
 
@@ -135,6 +127,8 @@ mod test {
                 OP_ENDIF
             OP_ENDIF
             OP_NOP6
+            OP_NOTIF_RETURN_TRUE
+            OP_NOP7
             1 OP_IF_RETURN_TRUE
         };
 
@@ -0,0 +1,18 @@
+//! Parallelism over independent parts of the work, behind the `parallel` feature.
+//!
+//! With the feature, the parts are processed on the rayon thread pool, which uses all the
+//! cores by default, and otherwise one after the other. The results are collected in the
+//! order of the parts in either case, so the output does not depend on the number of threads.
+
+/// `f` on every item, with the results in the order of the items.
+pub(crate) fn map<T: Send, U: Send>(items: Vec<T>, f: impl Fn(T) -> U + Sync + Send) -> Vec<U> {
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        items.into_par_iter().map(f).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        items.into_iter().map(f).collect()
+    }
+}
@@ -0,0 +1,392 @@
+//! Peephole optimization of the lowered script.
+//!
+//! The rules rewrite short sequences of instructions, and conditionals together with the
+//! instructions right before them, into smaller equivalent code. They run after the reduction
+//! and the final emit, so they also apply to the code that these add around the user's code.
+//! Every rule preserves whether the script succeeds, assuming MINIMALIF, which tapscript
+//! enforces for `OP_IF` and `OP_NOTIF`.
+
+use crate::structured_script::{Node, OwnedInstruction, OwnedInstructions, StructuredScript};
+use bitcoin::opcodes::all::*;
+use bitcoin::Opcode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeepholeRule {
+    /// `OP_NOT OP_IF` becomes `OP_NOTIF`, and `OP_NOT OP_NOTIF` becomes `OP_IF`, when the
+    /// operand of `OP_NOT` comes from an opcode that returns 0 or 1, such as `OP_EQUAL`. Other
+    /// operands would be rejected by MINIMALIF.
+    NotIf,
+    /// `1 OP_IF X OP_ELSE Y OP_ENDIF` becomes `X`, and `0 OP_IF X OP_ELSE Y OP_ENDIF` becomes
+    /// `Y`, and the same for `OP_NOTIF` and for conditionals without `OP_ELSE`.
+    ConstantCondition,
+    /// `OP_IF 1 OP_ELSE 0 OP_ENDIF` is removed, and `OP_IF 0 OP_ELSE 1 OP_ENDIF` becomes
+    /// `OP_NOT`, and the same for `OP_NOTIF`, when the condition comes from an opcode that
+    /// returns 0 or 1. Other conditions would fail MINIMALIF in the conditional.
+    FlagConditional,
+    /// `OP_EQUAL OP_VERIFY`, `OP_NUMEQUAL OP_VERIFY` and `OP_CHECKSIG OP_VERIFY` become
+    /// `OP_EQUALVERIFY`, `OP_NUMEQUALVERIFY` and `OP_CHECKSIGVERIFY`.
+    MergeVerify,
+    /// `OP_DROP OP_DROP` becomes `OP_2DROP`.
+    DoubleDrop,
+    /// `1 OP_VERIFY` is removed.
+    TrueVerify,
+}
+
+impl PeepholeRule {
+    pub const ALL: [PeepholeRule; 6] = [
+        PeepholeRule::NotIf,
+        PeepholeRule::ConstantCondition,
+        PeepholeRule::FlagConditional,
+        PeepholeRule::MergeVerify,
+        PeepholeRule::DoubleDrop,
+        PeepholeRule::TrueVerify,
+    ];
+}
+
+/// Apply all the rules until none applies, and return the number of rewrites.
+pub fn peephole_optimize(structure: &mut StructuredScript) -> usize {
+    peephole_optimize_with_rules(structure, &PeepholeRule::ALL)
+}
+
+/// Apply the given rules until none applies, and return the number of rewrites.
+pub fn peephole_optimize_with_rules(
+    structure: &mut StructuredScript,
+    rules: &[PeepholeRule],
+) -> usize {
+    let mut total = 0;
+    loop {
+        let count = rewrite(structure, rules);
+        if count == 0 {
+            return total;
+        }
+        total += count;
+    }
+}
+
+/// One pass of the rules over the structure, bottom up, with the top-level regions rewritten
+/// in parallel.
+fn rewrite(structure: &mut StructuredScript, rules: &[PeepholeRule]) -> usize {
+    let (rewritten, count) = std::mem::take(structure).fold_regions(|node| {
+        let mut count: usize = node.children().iter().map(|(_, count)| count).sum();
+        let mut structure = StructuredScript::from(node.map(|(v, _)| v));
+        match &mut structure {
+            StructuredScript::Script(v) => count += rewrite_instructions(&mut v.0, rules),
+            StructuredScript::MultiScript(vv) => count += rewrite_sequence(vv, rules),
+            _ => {}
+        }
+        normalize(&mut structure);
+        (structure, count)
+    });
+    *structure = rewritten;
+    count
+}
+
+/// Rules on instructions next to each other.
+fn rewrite_instructions(instructions: &mut Vec<OwnedInstruction>, rules: &[PeepholeRule]) -> usize {
+    let mut count = 0;
+    let mut res: Vec<OwnedInstruction> = vec![];
+    for inst in instructions.drain(..) {
+        let last = match res.last() {
+            Some(OwnedInstruction::Op(op)) => Some(*op),
+            _ => None,
+        };
+        match (last, &inst) {
+            (Some(op), OwnedInstruction::Op(OP_VERIFY))
+                if rules.contains(&PeepholeRule::MergeVerify) && verify_form(op).is_some() =>
+            {
+                *res.last_mut().unwrap() = OwnedInstruction::Op(verify_form(op).unwrap());
+                count += 1;
+            }
+            (Some(OP_PUSHNUM_1), OwnedInstruction::Op(OP_VERIFY))
+                if rules.contains(&PeepholeRule::TrueVerify) =>
+            {
+                res.pop();
+                count += 1;
+            }
+            (Some(OP_DROP), OwnedInstruction::Op(OP_DROP))
+                if rules.contains(&PeepholeRule::DoubleDrop) =>
+            {
+                *res.last_mut().unwrap() = OwnedInstruction::Op(OP_2DROP);
+                count += 1;
+            }
+            _ => res.push(inst),
+        }
+    }
+    *instructions = res;
+    count
+}
+
+/// Rules on conditionals and the instructions right before them.
+fn rewrite_sequence(vv: &mut Vec<StructuredScript>, rules: &[PeepholeRule]) -> usize {
+    let mut count = 0;
+    let mut i = 1;
+    while i < vv.len() {
+        let (before, after) = vv.split_at_mut(i);
+        let (StructuredScript::Script(prev), Some((negated, _, _))) =
+            (&mut before[i - 1], conditional(&after[0]))
+        else {
+            i += 1;
+            continue;
+        };
+
+        let last = match prev.0.last() {
+            Some(OwnedInstruction::Op(op)) => Some(*op),
+            _ => None,
+        };
+        let operand = match prev.0.iter().rev().nth(1) {
+            Some(OwnedInstruction::Op(op)) => Some(*op),
+            _ => None,
+        };
+
+        if rules.contains(&PeepholeRule::ConstantCondition)
+            && matches!(last, Some(OP_PUSHBYTES_0 | OP_PUSHNUM_1))
+        {
+            let take_first = (last == Some(OP_PUSHNUM_1)) != negated;
+            prev.0.pop();
+            let taken = match std::mem::take(&mut vv[i]).into_node() {
+                Node::IfEndIf(v) | Node::NotIfEndIf(v) => take_first.then_some(v),
+                Node::IfElseEndIf(v1, v2) | Node::NotIfElseEndIf(v1, v2) => {
+                    Some(if take_first { v1 } else { v2 })
+                }
+                _ => unreachable!(),
+            };
+            match taken {
+                Some(v) => vv[i] = v,
+                None => {
+                    vv.remove(i);
+                }
+            }
+            count += 1;
+        } else if let Some(replacement) = flag_conditional(&after[0])
+            .filter(|_| rules.contains(&PeepholeRule::FlagConditional))
+            .filter(|_| last.is_some_and(returns_boolean))
+        {
+            vv[i] = StructuredScript::Script(OwnedInstructions(replacement));
+            count += 1;
+            i += 1;
+        } else if rules.contains(&PeepholeRule::NotIf)
+            && last == Some(OP_NOT)
+            && operand.is_some_and(returns_boolean)
+        {
+            prev.0.pop();
+            negate(&mut vv[i]);
+            count += 1;
+        } else {
+            i += 1;
+        }
+    }
+    count
+}
+
+/// The replacement of an `OP_IF`/`OP_NOTIF` with constant 0 and 1 in its branches.
+fn flag_conditional(structure: &StructuredScript) -> Option<Vec<OwnedInstruction>> {
+    let (negated, v1, Some(v2)) = conditional(structure)? else {
+        return None;
+    };
+    let flag = |v: &StructuredScript| match v {
+        StructuredScript::Script(v) => match v.0.as_slice() {
+            [OwnedInstruction::Op(OP_PUSHNUM_1)] => Some(true),
+            [OwnedInstruction::Op(OP_PUSHBYTES_0)] => Some(false),
+            _ => None,
+        },
+        _ => None,
+    };
+    match (flag(v1)?, flag(v2)?) {
+        (true, false) if !negated => Some(vec![]),
+        (false, true) if negated => Some(vec![]),
+        (false, true) | (true, false) => Some(vec![OwnedInstruction::Op(OP_NOT)]),
+        _ => None,
+    }
+}
+
+/// Whether the conditional is an `OP_NOTIF`, and its branches.
+#[allow(clippy::type_complexity)]
+fn conditional(
+    structure: &StructuredScript,
+) -> Option<(bool, &StructuredScript, Option<&StructuredScript>)> {
+    match structure {
+        StructuredScript::IfEndIf(v) => Some((false, v, None)),
+        StructuredScript::NotIfEndIf(v) => Some((true, v, None)),
+        StructuredScript::IfElseEndIf(v1, v2) => Some((false, v1, Some(v2))),
+        StructuredScript::NotIfElseEndIf(v1, v2) => Some((true, v1, Some(v2))),
+        _ => None,
+    }
+}
+
+/// Swap `OP_IF` and `OP_NOTIF`.
+fn negate(structure: &mut StructuredScript) {
+    *structure = match std::mem::take(structure).into_node() {
+        Node::IfEndIf(v) => Node::NotIfEndIf(v),
+        Node::NotIfEndIf(v) => Node::IfEndIf(v),
+        Node::IfElseEndIf(v1, v2) => Node::NotIfElseEndIf(v1, v2),
+        Node::NotIfElseEndIf(v1, v2) => Node::IfElseEndIf(v1, v2),
+        _ => unreachable!(),
+    }
+    .into();
+}
+
+fn verify_form(opcode: Opcode) -> Option<Opcode> {
+    match opcode {
+        OP_EQUAL => Some(OP_EQUALVERIFY),
+        OP_NUMEQUAL => Some(OP_NUMEQUALVERIFY),
+        OP_CHECKSIG => Some(OP_CHECKSIGVERIFY),
+        _ => None,
+    }
+}
+
+/// Whether the opcode always leaves 0 or 1 on the stack.
+fn returns_boolean(opcode: Opcode) -> bool {
+    [
+        OP_EQUAL,
+        OP_NOT,
+        OP_0NOTEQUAL,
+        OP_BOOLAND,
+        OP_BOOLOR,
+        OP_NUMEQUAL,
+        OP_NUMNOTEQUAL,
+        OP_LESSTHAN,
+        OP_GREATERTHAN,
+        OP_LESSTHANOREQUAL,
+        OP_GREATERTHANOREQUAL,
+        OP_WITHIN,
+        OP_CHECKSIG,
+    ]
+    .contains(&opcode)
+}
+
+/// Flatten nested sequences and merge the instructions next to each other, so that the rules
+/// see across the boundaries left by the other passes.
+fn normalize(structure: &mut StructuredScript) {
+    let StructuredScript::MultiScript(vv) = structure else {
+        return;
+    };
+
+    let mut res: Vec<StructuredScript> = vec![];
+    for v in vv.drain(..) {
+        let children = match v.into_node() {
+            Node::MultiScript(inner) => inner,
+            node => vec![node.into()],
+        };
+        for v in children {
+            match (res.last_mut(), v.into_node()) {
+                (_, Node::Script(v)) if v.0.is_empty() => {}
+                (Some(StructuredScript::Script(last)), Node::Script(v)) => last.0.extend(v.0),
+                (_, node) => res.push(node.into()),
+            }
+        }
+    }
+
+    *structure = match res.len() {
+        0 => StructuredScript::Script(OwnedInstructions(vec![])),
+        1 => res.pop().unwrap(),
+        _ => StructuredScript::MultiScript(res),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peephole::{peephole_optimize, peephole_optimize_with_rules, PeepholeRule};
+    use crate::structured_script::StructuredScript;
+    use bitcoin::ScriptBuf;
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    fn optimize(script: ScriptBuf, rules: &[PeepholeRule]) -> (ScriptBuf, usize) {
+        let mut structure: StructuredScript = script.into();
+        let count = peephole_optimize_with_rules(&mut structure, rules);
+        (structure.into(), count)
+    }
+
+    #[test]
+    fn test_peephole_rules() {
+        let script = script! {
+            OP_EQUAL OP_NOT OP_IF OP_NOP1 OP_ENDIF
+            OP_SIZE OP_NOT OP_IF OP_NOP2 OP_ENDIF
+            OP_CHECKSIG OP_NOT OP_NOTIF OP_NOP3 OP_ELSE OP_NOP4 OP_ENDIF
+        };
+        let expected = script! {
+            OP_EQUAL OP_NOTIF OP_NOP1 OP_ENDIF
+            OP_SIZE OP_NOT OP_IF OP_NOP2 OP_ENDIF
+            OP_CHECKSIG OP_IF OP_NOP3 OP_ELSE OP_NOP4 OP_ENDIF
+        };
+        assert_eq!(optimize(script, &[PeepholeRule::NotIf]), (expected, 2));
+
+        let script = script! {
+            1 OP_IF OP_NOP1 OP_ELSE OP_NOP2 OP_ENDIF
+            0 OP_IF OP_NOP3 OP_ENDIF
+            0 OP_NOTIF OP_NOP4 OP_ENDIF
+            1 OP_NOTIF OP_NOP5 OP_ELSE OP_NOP6 OP_ENDIF
+        };
+        let expected = script! { OP_NOP1 OP_NOP4 OP_NOP6 };
+        assert_eq!(
+            optimize(script, &[PeepholeRule::ConstantCondition]),
+            (expected, 4)
+        );
+
+        let script = script! {
+            OP_EQUAL OP_IF 1 OP_ELSE 0 OP_ENDIF
+            OP_EQUAL OP_IF 0 OP_ELSE 1 OP_ENDIF
+            OP_EQUAL OP_NOTIF 0 OP_ELSE 1 OP_ENDIF
+            OP_EQUAL OP_NOTIF 1 OP_ELSE OP_NOP1 OP_ENDIF
+            OP_SIZE OP_IF 1 OP_ELSE 0 OP_ENDIF
+        };
+        let expected = script! {
+            OP_EQUAL
+            OP_EQUAL OP_NOT
+            OP_EQUAL
+            OP_EQUAL OP_NOTIF 1 OP_ELSE OP_NOP1 OP_ENDIF
+            OP_SIZE OP_IF 1 OP_ELSE 0 OP_ENDIF
+        };
+        assert_eq!(
+            optimize(script, &[PeepholeRule::FlagConditional]),
+            (expected, 3)
+        );
+
+        let script = script! {
+            OP_EQUAL OP_VERIFY OP_NUMEQUAL OP_VERIFY OP_CHECKSIG OP_VERIFY OP_ADD OP_VERIFY
+        };
+        let expected = script! {
+            OP_EQUALVERIFY OP_NUMEQUALVERIFY OP_CHECKSIGVERIFY OP_ADD OP_VERIFY
+        };
+        assert_eq!(
+            optimize(script, &[PeepholeRule::MergeVerify]),
+            (expected, 3)
+        );
+
+        let script = script! { OP_DROP OP_DROP OP_DROP 1 OP_VERIFY };
+        let expected = script! { OP_2DROP OP_DROP 1 OP_VERIFY };
+        assert_eq!(optimize(script, &[PeepholeRule::DoubleDrop]), (expected, 1));
+
+        let script = script! { OP_DROP 1 OP_VERIFY OP_DROP };
+        let expected = script! { OP_DROP OP_DROP };
+        assert_eq!(optimize(script, &[PeepholeRule::TrueVerify]), (expected, 1));
+    }
+
+    #[test]
+    fn test_peephole_optimize() {
+        // rewrites enable each other until none applies
+        let script = script! {
+            OP_DUP OP_EQUAL
+            OP_IF 1 OP_ELSE 0 OP_ENDIF
+            OP_NOT
+            OP_IF
+                1 OP_IF OP_DROP OP_ENDIF
+                OP_DROP
+            OP_ENDIF
+            1 OP_VERIFY
+            OP_EQUAL OP_VERIFY
+        };
+        let mut structure: StructuredScript = script.into();
+        assert_eq!(peephole_optimize(&mut structure), 6);
+
+        let expected = script! {
+            OP_DUP OP_EQUAL
+            OP_NOTIF
+                OP_2DROP
+            OP_ENDIF
+            OP_EQUALVERIFY
+        };
+        assert_eq!(ScriptBuf::from(structure), expected);
+    }
+}
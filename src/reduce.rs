@@ -1,6 +1,8 @@
-use crate::structured_script::{OwnedInstruction, OwnedInstructions, StructuredScript};
-use crate::_OP_IF_RETURN_TRUE;
-use bitcoin::opcodes::all::OP_PUSHNUM_1;
+use crate::site_label::label_len;
+use crate::structured_script::{Node, OwnedInstruction, OwnedInstructions, StructuredScript};
+use crate::{_OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE};
+use bitcoin::hashes::sha256;
+use bitcoin::opcodes::all::{OP_NOT, OP_PUSHNUM_1};
 use bitcoin::opcodes::OP_0;
 use bitcoin::Opcode;
 use std::cmp::PartialEq;
@@ -12,182 +14,766 @@ pub enum EmitOpIfSuccess {
 }
 
 pub fn reduce(structure: &mut StructuredScript) -> EmitOpIfSuccess {
-    match structure {
-        StructuredScript::Script(v) => {
-            // Find the first OP_IF_SUCCESS.
-            // If it exists:
-            // - slice the script with it
-            // - create the second chunk of the script and reduce it
-            // - return EmitOpIfSuccess::YES indicating
-
-            let len = v.0.len();
-
-            for i in 0..len {
-                if v.0[i] == OwnedInstruction::Op(_OP_IF_RETURN_TRUE) {
-                    if i != len - 1 {
-                        let existing_code = OwnedInstructions(v.0[0..i].to_vec());
-                        let mut rest_code = v.0[i + 1..len].to_vec();
-                        rest_code.push(OwnedInstruction::Op(OP_0));
-                        let rest_code = OwnedInstructions(rest_code);
-
-                        let mut new_if_else_statement = StructuredScript::IfElseEndIf(
-                            Box::new(StructuredScript::Script(OwnedInstructions(vec![
-                                OwnedInstruction::Op(OP_PUSHNUM_1),
-                            ]))),
-                            Box::new(StructuredScript::Script(rest_code)),
-                        );
-
-                        let emit_result = reduce(&mut new_if_else_statement);
-                        if emit_result == EmitOpIfSuccess::YES {
-                            *structure = StructuredScript::MultiScript(vec![
-                                StructuredScript::Script(existing_code),
-                                new_if_else_statement,
-                                StructuredScript::IfEndIf(Box::new(StructuredScript::Script(
-                                    OwnedInstructions(vec![OwnedInstruction::Op(OP_PUSHNUM_1)]),
-                                ))),
-                            ]);
-                        } else {
-                            *structure = StructuredScript::MultiScript(vec![
-                                StructuredScript::Script(existing_code),
-                                new_if_else_statement,
-                            ]);
-                        }
-                    } else {
-                        // remove the last OP_IF_SUCCESS and emit it to the upper layer
-                        v.0.truncate(i);
-                    }
+    reduce_with_memo(structure, &mut NoMemo)
+}
 
-                    return EmitOpIfSuccess::YES;
-                }
-            }
+/// Hook for reusing the reduction of subtrees of the input, see `crate::incremental`.
+///
+/// `lookup` is called for every subtree before it is reduced, top down in the order of the
+/// script, and `record` once a subtree for which `lookup` returned a key has been reduced.
+/// The conditionals and switches passed to `lookup` are the ones of the input, unchanged, so
+/// that what the memo needs of them can be computed once for the whole input.
+pub(crate) trait ReduceMemo {
+    fn lookup(&mut self, structure: &StructuredScript) -> Lookup;
+    fn record(
+        &mut self,
+        key: sha256::Hash,
+        reduced: &StructuredScript,
+        emit_result: EmitOpIfSuccess,
+    );
+}
 
-            EmitOpIfSuccess::NO
-        }
-        StructuredScript::MultiScript(vv) => {
-            let len = vv.len();
+pub(crate) enum Lookup {
+    /// The subtree does not need to be reduced, and does not leave a flag.
+    Unchanged,
+    /// The reduction of the subtree.
+    Reduced(StructuredScript, EmitOpIfSuccess),
+    /// The subtree has to be reduced, and then recorded under the key, if any.
+    Reduce(Option<sha256::Hash>),
+}
 
-            for i in 0..len {
-                let emit_result = reduce(&mut vv[i]);
-                if emit_result == EmitOpIfSuccess::YES {
-                    if i == len - 1 {
-                        // do nothing, emit it to the upper layer
-                    } else {
-                        // create a new If-Else statement
-                        let mut rest_code = if i + 1 == len - 1 {
-                            vv[i + 1].clone()
-                        } else {
-                            StructuredScript::MultiScript(vv[i + 1..len].to_vec())
-                        };
-                        append_opcode(&mut rest_code, OP_0);
-
-                        vv.truncate(i + 1);
-                        vv.push(StructuredScript::IfElseEndIf(
-                            Box::new(StructuredScript::Script(OwnedInstructions(vec![
-                                OwnedInstruction::Op(OP_PUSHNUM_1),
-                            ]))),
-                            Box::new(rest_code),
-                        ));
-
-                        let more_emit = reduce(&mut vv[i + 1]);
-                        if more_emit == EmitOpIfSuccess::YES {
-                            vv.push(StructuredScript::IfEndIf(Box::new(
-                                StructuredScript::Script(OwnedInstructions(vec![
-                                    OwnedInstruction::Op(OP_PUSHNUM_1),
-                                ])),
-                            )));
-                        }
+struct NoMemo;
+
+impl ReduceMemo for NoMemo {
+    fn lookup(&mut self, _: &StructuredScript) -> Lookup {
+        Lookup::Reduce(None)
+    }
+
+    fn record(&mut self, _: sha256::Hash, _: &StructuredScript, _: EmitOpIfSuccess) {}
+}
+
+/// A reduction waiting for the reduction of one of its subtrees.
+enum Frame {
+    /// The subtree is recorded in the memo under the key.
+    Record(sha256::Hash),
+    /// The branch of an `OP_IF`, or of an `OP_NOTIF` if it is true, without `OP_ELSE`.
+    Branch(bool),
+    /// The first branch of an `OP_IF`/`OP_NOTIF`, with the second branch.
+    FirstBranch(bool, StructuredScript),
+    /// The second branch of an `OP_IF`/`OP_NOTIF`, with the first branch reduced.
+    SecondBranch(bool, StructuredScript, EmitOpIfSuccess),
+    /// An arm of a switch, with the arms before it reduced, and the arms after it, the next
+    /// one last.
+    Arm(
+        Vec<(StructuredScript, EmitOpIfSuccess)>,
+        Vec<StructuredScript>,
+    ),
+    /// An element of a sequence.
+    Element(Sequence),
+}
+
+enum Step {
+    Reduce(StructuredScript),
+    Reduced(StructuredScript, EmitOpIfSuccess),
+}
+
+/// Reduce the structure, with the subtrees that wait for the reduction of their children on
+/// a stack of frames rather than on the call stack, so that the depth of the nesting is not
+/// limited by the size of the thread's stack.
+pub(crate) fn reduce_with_memo(
+    structure: &mut StructuredScript,
+    memo: &mut dyn ReduceMemo,
+) -> EmitOpIfSuccess {
+    let mut frames: Vec<Frame> = vec![];
+    let mut step = Step::Reduce(std::mem::take(structure));
+    loop {
+        step = match step {
+            Step::Reduce(subtree) => match memo.lookup(&subtree) {
+                Lookup::Unchanged => Step::Reduced(subtree, EmitOpIfSuccess::NO),
+                Lookup::Reduced(reduced, emit_result) => Step::Reduced(reduced, emit_result),
+                Lookup::Reduce(key) => {
+                    if let Some(key) = key {
+                        frames.push(Frame::Record(key));
                     }
+                    start(subtree, &mut frames, memo)
+                }
+            },
+            Step::Reduced(reduced, emit_result) => match frames.pop() {
+                Some(frame) => resume(frame, reduced, emit_result, &mut frames, memo),
+                None => {
+                    *structure = reduced;
+                    return emit_result;
+                }
+            },
+        };
+    }
+}
 
-                    return EmitOpIfSuccess::YES;
+/// Reduce a script right away, or push the frame of a node that waits for its first child,
+/// and return the child.
+fn start(structure: StructuredScript, frames: &mut Vec<Frame>, memo: &mut dyn ReduceMemo) -> Step {
+    match structure.into_node() {
+        Node::Script(v) => {
+            let (reduced, emit_result) = reduce_instructions(v.0, memo);
+            Step::Reduced(reduced, emit_result)
+        }
+        Node::MultiScript(vv) => Sequence::new(vv).next(frames, memo),
+        Node::IfEndIf(v) => {
+            frames.push(Frame::Branch(false));
+            Step::Reduce(v)
+        }
+        Node::NotIfEndIf(v) => {
+            frames.push(Frame::Branch(true));
+            Step::Reduce(v)
+        }
+        Node::IfElseEndIf(v1, v2) => {
+            frames.push(Frame::FirstBranch(false, v2));
+            Step::Reduce(v1)
+        }
+        Node::NotIfElseEndIf(v1, v2) => {
+            frames.push(Frame::FirstBranch(true, v2));
+            Step::Reduce(v1)
+        }
+        Node::Switch(arms) => next_arm(vec![], arms.into_iter().rev().collect(), frames),
+    }
+}
+
+/// Continue the reduction of the frame with the reduction of its subtree.
+fn resume(
+    frame: Frame,
+    mut reduced: StructuredScript,
+    emit_result: EmitOpIfSuccess,
+    frames: &mut Vec<Frame>,
+    memo: &mut dyn ReduceMemo,
+) -> Step {
+    match frame {
+        Frame::Record(key) => {
+            memo.record(key, &reduced, emit_result);
+            Step::Reduced(reduced, emit_result)
+        }
+        Frame::Branch(negated) => {
+            let v = Box::new(reduced);
+            let structure = match (emit_result, negated) {
+                (EmitOpIfSuccess::NO, false) => StructuredScript::IfEndIf(v),
+                (EmitOpIfSuccess::NO, true) => StructuredScript::NotIfEndIf(v),
+                (EmitOpIfSuccess::YES, false) => {
+                    StructuredScript::IfElseEndIf(v, Box::new(opcodes(&[OP_0])))
+                }
+                (EmitOpIfSuccess::YES, true) => {
+                    StructuredScript::NotIfElseEndIf(v, Box::new(opcodes(&[OP_0])))
                 }
+            };
+            Step::Reduced(structure, emit_result)
+        }
+        Frame::FirstBranch(negated, v2) => {
+            frames.push(Frame::SecondBranch(negated, reduced, emit_result));
+            Step::Reduce(v2)
+        }
+        Frame::SecondBranch(negated, mut v1, emit_result_1) => {
+            let emit_result = merge_flags(&mut v1, emit_result_1, &mut reduced, emit_result);
+            let (v1, v2) = (Box::new(v1), Box::new(reduced));
+            let structure = if negated {
+                StructuredScript::NotIfElseEndIf(v1, v2)
+            } else {
+                StructuredScript::IfElseEndIf(v1, v2)
+            };
+            Step::Reduced(structure, emit_result)
+        }
+        Frame::Arm(mut done, rest) => {
+            done.push((reduced, emit_result));
+            next_arm(done, rest, frames)
+        }
+        Frame::Element(mut sequence) => match sequence.push(reduced, emit_result, memo) {
+            Some((reduced, emit_result)) => {
+                let (reduced, emit_result) = sequence.finish(reduced, emit_result, memo);
+                Step::Reduced(reduced, emit_result)
             }
+            None => sequence.next(frames, memo),
+        },
+    }
+}
 
-            EmitOpIfSuccess::NO
-        }
-        StructuredScript::IfEndIf(v) => {
-            let emit_result = reduce(v);
-
-            if emit_result == EmitOpIfSuccess::YES {
-                let new_if_else_statement = StructuredScript::IfElseEndIf(
-                    v.clone(),
-                    Box::new(StructuredScript::Script(OwnedInstructions(vec![
-                        OwnedInstruction::Op(OP_0),
-                    ]))),
-                );
-                *structure = new_if_else_statement;
+/// Reduce the next arm of a switch, or finish the switch if all the arms are reduced.
+fn next_arm(
+    done: Vec<(StructuredScript, EmitOpIfSuccess)>,
+    mut rest: Vec<StructuredScript>,
+    frames: &mut Vec<Frame>,
+) -> Step {
+    if let Some(arm) = rest.pop() {
+        frames.push(Frame::Arm(done, rest));
+        return Step::Reduce(arm);
+    }
+
+    // the arms that may succeed share one flag, which the others set to 0
+    let emit_result = if done.iter().any(|(_, e)| *e == EmitOpIfSuccess::YES) {
+        EmitOpIfSuccess::YES
+    } else {
+        EmitOpIfSuccess::NO
+    };
+    let arms = done
+        .into_iter()
+        .map(|(mut v, arm_emit_result)| {
+            if arm_emit_result != emit_result {
+                append_opcode(&mut v, OP_0);
             }
+            v
+        })
+        .collect();
+    Step::Reduced(StructuredScript::Switch(arms), emit_result)
+}
+
+/// Reduce a script, where each `OP_IF_RETURN_TRUE`/`OP_NOTIF_RETURN_TRUE` that is not at the
+/// end moves the rest of the script into the else branch of a new if-else statement, which
+/// is followed by `OP_IF 1 OP_ENDIF` if the rest may succeed too.
+///
+/// The script is cut at the sites from the back, so that every instruction is moved once, and
+/// the if-else statements are nested from the innermost one out.
+fn reduce_instructions(
+    mut instructions: Vec<OwnedInstruction>,
+    memo: &mut dyn ReduceMemo,
+) -> (StructuredScript, EmitOpIfSuccess) {
+    let mut sites = vec![];
+    let mut i = 0;
+    while i < instructions.len() {
+        if is_conditional_site(&instructions[i]) {
+            let label_end = i + 1 + label_len(&instructions, i + 1);
+            sites.push((i, label_end));
+            i = label_end;
+        } else {
+            i += 1;
+        }
+    }
+    if sites.is_empty() {
+        return (
+            StructuredScript::Script(OwnedInstructions(instructions)),
+            EmitOpIfSuccess::NO,
+        );
+    }
+
+    // the code after each site, and the site with its label, from the last site
+    let mut rest_codes = vec![];
+    let mut sites_with_labels = vec![];
+    for (i, label_end) in sites.into_iter().rev() {
+        rest_codes.push(instructions.split_off(label_end));
+        let label = instructions.split_off(i + 1);
+        let is_notif = instructions.pop() == Some(OwnedInstruction::Op(_OP_NOTIF_RETURN_TRUE));
+        sites_with_labels.push((is_notif, label));
+    }
+
+    let mut rest_code = rest_codes.remove(0);
+    if sites_with_labels.len() == 1 && rest_code.is_empty() {
+        // remove the last OP_IF_SUCCESS and emit it to the upper layer
+        let (is_notif, label) = sites_with_labels.pop().unwrap();
+        if is_notif {
+            // there is no branch left to take the negation, so negate the
+            // condition into a flag
+            instructions.push(OwnedInstruction::Op(OP_NOT));
+        }
+        instructions.extend(label);
+        return (
+            StructuredScript::Script(OwnedInstructions(instructions)),
+            EmitOpIfSuccess::YES,
+        );
+    }
+
+    // every if-else statement adds a 0 at the end of the rest of the script, and the scripts
+    // made here have no conditionals, so reducing them does not nest
+    rest_code.extend(sites_with_labels.iter().map(|_| OwnedInstruction::Op(OP_0)));
+    let mut rest = StructuredScript::Script(OwnedInstructions(rest_code));
+    let mut emit_result = reduce_with_memo(&mut rest, memo);
+
+    let existing_codes = rest_codes.into_iter().chain(std::iter::once(instructions));
+    for ((is_notif, mut label), existing_code) in sites_with_labels.into_iter().zip(existing_codes)
+    {
+        // the label of the site, if any, goes into the success branch
+        label.push(OwnedInstruction::Op(OP_PUSHNUM_1));
+        let mut success_branch = StructuredScript::Script(OwnedInstructions(label));
+        let success_emit = reduce_with_memo(&mut success_branch, memo);
+        let more_emit = merge_flags(&mut success_branch, success_emit, &mut rest, emit_result);
+
+        let (success_branch, rest_branch) = (Box::new(success_branch), Box::new(rest));
+        let new_if_else_statement = if is_notif {
+            StructuredScript::NotIfElseEndIf(success_branch, rest_branch)
+        } else {
+            StructuredScript::IfElseEndIf(success_branch, rest_branch)
+        };
+        rest = sequence_with_flag(existing_code, new_if_else_statement, more_emit);
+        emit_result = EmitOpIfSuccess::YES;
+    }
+
+    (rest, EmitOpIfSuccess::YES)
+}
+
+/// Reduction of a sequence, where each element that may succeed, except the last one, moves
+/// the rest of the sequence into the else branch of `OP_IF 1 OP_ELSE <rest> 0 OP_ENDIF`,
+/// which is followed by `OP_IF 1 OP_ENDIF` if the rest may succeed too.
+///
+/// The elements are taken from a stack, so that every element is moved once, and the if-else
+/// statements are nested from the innermost one out.
+struct Sequence {
+    /// The elements left, the next one last.
+    rest: Vec<StructuredScript>,
+    /// The elements before each new if-else statement, from the outermost one.
+    levels: Vec<Vec<StructuredScript>>,
+    /// The elements reduced since the last if-else statement.
+    done: Vec<StructuredScript>,
+}
 
-            emit_result
+impl Sequence {
+    fn new(elements: Vec<StructuredScript>) -> Self {
+        Self {
+            rest: elements.into_iter().rev().collect(),
+            levels: vec![],
+            done: vec![],
         }
-        StructuredScript::NotIfEndIf(v) => {
-            let emit_result = reduce(v);
-
-            if emit_result == EmitOpIfSuccess::YES {
-                let new_if_else_statement = StructuredScript::NotIfElseEndIf(
-                    v.clone(),
-                    Box::new(StructuredScript::Script(OwnedInstructions(vec![
-                        OwnedInstruction::Op(OP_0),
-                    ]))),
-                );
-                *structure = new_if_else_statement;
+    }
+
+    /// Push the frame of the sequence and return its next element, or finish the sequence if
+    /// there is none left.
+    fn next(mut self, frames: &mut Vec<Frame>, memo: &mut dyn ReduceMemo) -> Step {
+        match self.rest.pop() {
+            Some(element) => {
+                frames.push(Frame::Element(self));
+                Step::Reduce(element)
             }
+            None => {
+                let reduced = StructuredScript::MultiScript(std::mem::take(&mut self.done));
+                let (reduced, emit_result) = self.finish(reduced, EmitOpIfSuccess::NO, memo);
+                Step::Reduced(reduced, emit_result)
+            }
+        }
+    }
+
+    /// Add the next element once reduced, and return the reduction of the innermost rest of
+    /// the sequence if it ends there.
+    fn push(
+        &mut self,
+        element: StructuredScript,
+        emit_result: EmitOpIfSuccess,
+        memo: &mut dyn ReduceMemo,
+    ) -> Option<(StructuredScript, EmitOpIfSuccess)> {
+        self.done.push(element);
 
-            emit_result
+        if emit_result == EmitOpIfSuccess::NO {
+            return None;
+        }
+        if self.rest.is_empty() {
+            // emit it to the upper layer
+            let done = std::mem::take(&mut self.done);
+            return Some((StructuredScript::MultiScript(done), EmitOpIfSuccess::YES));
         }
-        StructuredScript::IfElseEndIf(v1, v2) | StructuredScript::NotIfElseEndIf(v1, v2) => {
-            let emit_result_1 = reduce(v1);
-            let emit_result_2 = reduce(v2);
 
-            if emit_result_1 == emit_result_2 {
-                emit_result_1
-            } else {
-                if emit_result_1 == EmitOpIfSuccess::YES {
-                    append_opcode(v2, OP_0);
-                } else {
-                    append_opcode(v1, OP_0);
+        // create a new If-Else statement with the rest of the sequence and a trailing 0
+        self.levels.push(std::mem::take(&mut self.done));
+        if self.rest.len() == 1 {
+            let mut element = self.rest.pop().unwrap();
+            match &mut element {
+                StructuredScript::Script(v) => {
+                    v.0.push(OwnedInstruction::Op(OP_0));
+                    let emit_result = reduce_with_memo(&mut element, memo);
+                    return Some((element, emit_result));
+                }
+                StructuredScript::MultiScript(vv) => {
+                    self.rest = std::mem::take(vv).into_iter().rev().collect()
                 }
-                EmitOpIfSuccess::YES
+                _ => self.rest.push(element),
             }
         }
+        append_opcode_to_sequence(&mut self.rest, OP_0);
+        None
+    }
+
+    /// Nest the reduction of the innermost rest into the if-else statements.
+    fn finish(
+        mut self,
+        mut reduced: StructuredScript,
+        mut emit_result: EmitOpIfSuccess,
+        memo: &mut dyn ReduceMemo,
+    ) -> (StructuredScript, EmitOpIfSuccess) {
+        while let Some(existing_code) = self.levels.pop() {
+            let mut success_branch = opcodes(&[OP_PUSHNUM_1]);
+            let success_emit = reduce_with_memo(&mut success_branch, memo);
+            let more_emit =
+                merge_flags(&mut success_branch, success_emit, &mut reduced, emit_result);
+
+            let new_if_else_statement =
+                StructuredScript::IfElseEndIf(Box::new(success_branch), Box::new(reduced));
+            let mut level = existing_code;
+            level.push(new_if_else_statement);
+            if more_emit == EmitOpIfSuccess::YES {
+                level.push(StructuredScript::IfEndIf(Box::new(opcodes(&[
+                    OP_PUSHNUM_1,
+                ]))));
+            }
+            reduced = StructuredScript::MultiScript(level);
+            emit_result = EmitOpIfSuccess::YES;
+        }
+
+        (reduced, emit_result)
     }
 }
 
-fn append_opcode(structure: &mut StructuredScript, opcode: Opcode) {
-    match structure {
-        StructuredScript::Script(v) => {
-            v.0.push(OwnedInstruction::Op(opcode));
+fn is_conditional_site(inst: &OwnedInstruction) -> bool {
+    *inst == OwnedInstruction::Op(_OP_IF_RETURN_TRUE)
+        || *inst == OwnedInstruction::Op(_OP_NOTIF_RETURN_TRUE)
+}
+
+/// The code before a new if-else statement, the statement, and `OP_IF 1 OP_ENDIF` if the
+/// statement leaves a flag for the success code.
+fn sequence_with_flag(
+    existing_code: Vec<OwnedInstruction>,
+    new_if_else_statement: StructuredScript,
+    emit_result: EmitOpIfSuccess,
+) -> StructuredScript {
+    let mut level = vec![
+        StructuredScript::Script(OwnedInstructions(existing_code)),
+        new_if_else_statement,
+    ];
+    if emit_result == EmitOpIfSuccess::YES {
+        level.push(StructuredScript::IfEndIf(Box::new(opcodes(&[
+            OP_PUSHNUM_1,
+        ]))));
+    }
+    StructuredScript::MultiScript(level)
+}
+
+/// Make both branches of an if-else statement leave a flag if any of them does.
+fn merge_flags(
+    v1: &mut StructuredScript,
+    emit_result_1: EmitOpIfSuccess,
+    v2: &mut StructuredScript,
+    emit_result_2: EmitOpIfSuccess,
+) -> EmitOpIfSuccess {
+    if emit_result_1 == emit_result_2 {
+        emit_result_1
+    } else {
+        if emit_result_1 == EmitOpIfSuccess::YES {
+            append_opcode(v2, OP_0);
+        } else {
+            append_opcode(v1, OP_0);
         }
-        StructuredScript::MultiScript(vv) => {
-            let len = vv.len();
+        EmitOpIfSuccess::YES
+    }
+}
+
+fn opcodes(opcodes: &[Opcode]) -> StructuredScript {
+    StructuredScript::Script(OwnedInstructions(
+        opcodes.iter().map(|op| OwnedInstruction::Op(*op)).collect(),
+    ))
+}
 
-            if let StructuredScript::Script(v) = &mut vv[len - 1] {
+fn append_opcode(structure: &mut StructuredScript, opcode: Opcode) {
+    // into the last element of nested sequences
+    let mut structure = structure;
+    loop {
+        let descend = match &*structure {
+            StructuredScript::MultiScript(vv) => matches!(
+                vv.last(),
+                Some(StructuredScript::Script(_) | StructuredScript::MultiScript(_))
+            ),
+            _ => false,
+        };
+        if descend {
+            let StructuredScript::MultiScript(vv) = structure else {
+                unreachable!()
+            };
+            structure = vv.last_mut().unwrap();
+            continue;
+        }
+        match structure {
+            StructuredScript::Script(v) => {
                 v.0.push(OwnedInstruction::Op(opcode));
-            } else if let StructuredScript::MultiScript(_) = &mut vv[len - 1] {
-                append_opcode(&mut vv[len - 1], opcode);
-            } else {
-                vv.push(StructuredScript::Script(OwnedInstructions(vec![
-                    OwnedInstruction::Op(opcode),
-                ])));
+                return;
+            }
+            StructuredScript::MultiScript(vv) => {
+                vv.push(opcodes(&[opcode]));
+                return;
+            }
+            _ => {
+                let last = std::mem::take(structure);
+                *structure = StructuredScript::MultiScript(vec![last, opcodes(&[opcode])]);
+                return;
             }
         }
-        _ => {
-            *structure = StructuredScript::MultiScript(vec![
-                structure.clone(),
-                StructuredScript::Script(OwnedInstructions(vec![OwnedInstruction::Op(opcode)])),
-            ])
-        }
+    }
+}
+
+/// Same as `append_opcode` on the sequence of the elements, which are stored last first.
+fn append_opcode_to_sequence(reversed: &mut Vec<StructuredScript>, opcode: Opcode) {
+    match reversed.first_mut() {
+        Some(StructuredScript::Script(v)) => v.0.push(OwnedInstruction::Op(opcode)),
+        Some(last @ StructuredScript::MultiScript(_)) => append_opcode(last, opcode),
+        _ => reversed.insert(0, opcodes(&[opcode])),
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::reduce::{reduce, EmitOpIfSuccess};
-    use crate::structured_script::StructuredScript;
-    use crate::OP_IF_RETURN_TRUE;
+    use crate::reduce::{append_opcode, reduce, EmitOpIfSuccess};
+    use crate::site_label::label_len;
+    use crate::structured_script::{Node, OwnedInstruction, OwnedInstructions, StructuredScript};
+    use crate::test_util::Xorshift64;
+    use crate::{
+        _OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE, OP_CASE, OP_ENDSWITCH, OP_IF_RETURN_TRUE,
+        OP_NOTIF_RETURN_TRUE, OP_SITE_LABEL, OP_SWITCH,
+    };
+    use bitcoin::opcodes::all::{OP_NOT, OP_PUSHNUM_1};
+    use bitcoin::opcodes::OP_0;
+    use bitcoin::ScriptBuf;
     use bitcoin_script::{define_pushable, script};
 
     define_pushable!();
 
+    /// The reduction before it was made linear, which clones the rest of the script at every
+    /// success site.
+    fn reference_reduce(structure: &mut StructuredScript) -> EmitOpIfSuccess {
+        match structure {
+            StructuredScript::Script(v) => {
+                // Find the first OP_IF_SUCCESS.
+                // If it exists:
+                // - slice the script with it
+                // - create the second chunk of the script and reduce it
+                // - return EmitOpIfSuccess::YES indicating
+
+                let len = v.0.len();
+
+                for i in 0..len {
+                    let is_notif = v.0[i] == OwnedInstruction::Op(_OP_NOTIF_RETURN_TRUE);
+                    if v.0[i] == OwnedInstruction::Op(_OP_IF_RETURN_TRUE) || is_notif {
+                        // the label of the site, if any, goes into the success branch
+                        let label_end = i + 1 + label_len(&v.0, i + 1);
+                        let mut label = v.0[i + 1..label_end].to_vec();
+
+                        if label_end != len {
+                            let existing_code = OwnedInstructions(v.0[0..i].to_vec());
+                            let mut rest_code = v.0[label_end..len].to_vec();
+                            rest_code.push(OwnedInstruction::Op(OP_0));
+                            let rest_code = OwnedInstructions(rest_code);
+
+                            label.push(OwnedInstruction::Op(OP_PUSHNUM_1));
+                            let success_branch =
+                                Box::new(StructuredScript::Script(OwnedInstructions(label)));
+                            let rest_branch = Box::new(StructuredScript::Script(rest_code));
+                            let mut new_if_else_statement = if is_notif {
+                                StructuredScript::NotIfElseEndIf(success_branch, rest_branch)
+                            } else {
+                                StructuredScript::IfElseEndIf(success_branch, rest_branch)
+                            };
+
+                            let emit_result = reference_reduce(&mut new_if_else_statement);
+                            if emit_result == EmitOpIfSuccess::YES {
+                                *structure = StructuredScript::MultiScript(vec![
+                                    StructuredScript::Script(existing_code),
+                                    new_if_else_statement,
+                                    StructuredScript::IfEndIf(Box::new(StructuredScript::Script(
+                                        OwnedInstructions(vec![OwnedInstruction::Op(OP_PUSHNUM_1)]),
+                                    ))),
+                                ]);
+                            } else {
+                                *structure = StructuredScript::MultiScript(vec![
+                                    StructuredScript::Script(existing_code),
+                                    new_if_else_statement,
+                                ]);
+                            }
+                        } else {
+                            // remove the last OP_IF_SUCCESS and emit it to the upper layer
+                            v.0.truncate(i);
+
+                            if is_notif {
+                                // there is no branch left to take the negation, so negate the
+                                // condition into a flag
+                                v.0.push(OwnedInstruction::Op(OP_NOT));
+                            }
+                            v.0.extend(label);
+                        }
+
+                        return EmitOpIfSuccess::YES;
+                    }
+                }
+
+                EmitOpIfSuccess::NO
+            }
+            StructuredScript::MultiScript(vv) => {
+                let len = vv.len();
+
+                for i in 0..len {
+                    let emit_result = reference_reduce(&mut vv[i]);
+                    if emit_result == EmitOpIfSuccess::YES {
+                        if i == len - 1 {
+                            // do nothing, emit it to the upper layer
+                        } else {
+                            // create a new If-Else statement
+                            let mut rest_code = if i + 1 == len - 1 {
+                                vv[i + 1].clone()
+                            } else {
+                                StructuredScript::MultiScript(vv[i + 1..len].to_vec())
+                            };
+                            append_opcode(&mut rest_code, OP_0);
+
+                            vv.truncate(i + 1);
+                            vv.push(StructuredScript::IfElseEndIf(
+                                Box::new(StructuredScript::Script(OwnedInstructions(vec![
+                                    OwnedInstruction::Op(OP_PUSHNUM_1),
+                                ]))),
+                                Box::new(rest_code),
+                            ));
+
+                            let more_emit = reference_reduce(&mut vv[i + 1]);
+                            if more_emit == EmitOpIfSuccess::YES {
+                                vv.push(StructuredScript::IfEndIf(Box::new(
+                                    StructuredScript::Script(OwnedInstructions(vec![
+                                        OwnedInstruction::Op(OP_PUSHNUM_1),
+                                    ])),
+                                )));
+                            }
+                        }
+
+                        return EmitOpIfSuccess::YES;
+                    }
+                }
+
+                EmitOpIfSuccess::NO
+            }
+            StructuredScript::IfEndIf(v) => {
+                let emit_result = reference_reduce(v);
+
+                if emit_result == EmitOpIfSuccess::YES {
+                    let new_if_else_statement = StructuredScript::IfElseEndIf(
+                        v.clone(),
+                        Box::new(StructuredScript::Script(OwnedInstructions(vec![
+                            OwnedInstruction::Op(OP_0),
+                        ]))),
+                    );
+                    *structure = new_if_else_statement;
+                }
+
+                emit_result
+            }
+            StructuredScript::NotIfEndIf(v) => {
+                let emit_result = reference_reduce(v);
+
+                if emit_result == EmitOpIfSuccess::YES {
+                    let new_if_else_statement = StructuredScript::NotIfElseEndIf(
+                        v.clone(),
+                        Box::new(StructuredScript::Script(OwnedInstructions(vec![
+                            OwnedInstruction::Op(OP_0),
+                        ]))),
+                    );
+                    *structure = new_if_else_statement;
+                }
+
+                emit_result
+            }
+            StructuredScript::IfElseEndIf(v1, v2) | StructuredScript::NotIfElseEndIf(v1, v2) => {
+                let emit_result_1 = reference_reduce(v1);
+                let emit_result_2 = reference_reduce(v2);
+
+                if emit_result_1 == emit_result_2 {
+                    emit_result_1
+                } else {
+                    if emit_result_1 == EmitOpIfSuccess::YES {
+                        append_opcode(v2, OP_0);
+                    } else {
+                        append_opcode(v1, OP_0);
+                    }
+                    EmitOpIfSuccess::YES
+                }
+            }
+            StructuredScript::Switch(arms) => {
+                let emit_results = arms.iter_mut().map(reference_reduce).collect::<Vec<_>>();
+
+                // the arms that may succeed share one flag, which the others set to 0
+                if emit_results.contains(&EmitOpIfSuccess::YES) {
+                    for (v, emit_result) in arms.iter_mut().zip(emit_results) {
+                        if emit_result == EmitOpIfSuccess::NO {
+                            append_opcode(v, OP_0);
+                        }
+                    }
+                    EmitOpIfSuccess::YES
+                } else {
+                    EmitOpIfSuccess::NO
+                }
+            }
+        }
+    }
+
+    /// A generator of scripts with success sites in nested conditionals and switches.
+    struct Generator(Xorshift64);
+
+    impl Generator {
+        fn code(&mut self, depth: usize) -> ScriptBuf {
+            let len = self.0.next(5);
+            script! {
+                for _ in 0..len {
+                    { self.element(depth) }
+                }
+            }
+        }
+
+        fn element(&mut self, depth: usize) -> ScriptBuf {
+            match self.0.next(if depth == 0 { 5 } else { 9 }) {
+                0 => script! { OP_NOP1 },
+                1 => script! { OP_NOP2 },
+                2 => script! { OP_IF_RETURN_TRUE },
+                3 => script! { OP_NOTIF_RETURN_TRUE },
+                4 => script! { OP_IF_RETURN_TRUE { OP_SITE_LABEL("site") } },
+                5 => script! { OP_IF { self.code(depth - 1) } OP_ENDIF },
+                6 => script! { OP_NOTIF { self.code(depth - 1) } OP_ENDIF },
+                7 => script! {
+                    OP_IF { self.code(depth - 1) } OP_ELSE { self.code(depth - 1) } OP_ENDIF
+                },
+                _ => script! {
+                    OP_SWITCH
+                    OP_CASE { self.code(depth - 1) }
+                    OP_CASE { self.code(depth - 1) }
+                    OP_ENDSWITCH
+                },
+            }
+        }
+
+        /// Nest some of the sequences into sequences of their own, as the passes before the
+        /// reduction may do.
+        fn nest(&mut self, structure: StructuredScript) -> StructuredScript {
+            match structure.into_node() {
+                Node::MultiScript(vv) => {
+                    let mut vv: Vec<_> = vv.into_iter().map(|v| self.nest(v)).collect();
+                    if vv.len() > 1 && self.0.next(2) == 0 {
+                        let at = self.0.next(vv.len() as u64) as usize;
+                        let tail = vv.split_off(at);
+                        vv.push(StructuredScript::MultiScript(tail));
+                    }
+                    StructuredScript::MultiScript(vv)
+                }
+                Node::IfEndIf(v) => StructuredScript::IfEndIf(Box::new(self.nest(v))),
+                Node::NotIfEndIf(v) => StructuredScript::NotIfEndIf(Box::new(self.nest(v))),
+                Node::IfElseEndIf(v1, v2) => {
+                    StructuredScript::IfElseEndIf(Box::new(self.nest(v1)), Box::new(self.nest(v2)))
+                }
+                Node::NotIfElseEndIf(v1, v2) => StructuredScript::NotIfElseEndIf(
+                    Box::new(self.nest(v1)),
+                    Box::new(self.nest(v2)),
+                ),
+                Node::Switch(arms) => {
+                    StructuredScript::Switch(arms.into_iter().map(|v| self.nest(v)).collect())
+                }
+                Node::Script(v) => StructuredScript::Script(v),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reduce_reference() {
+        let mut generator = Generator(Xorshift64::new(0x9e3779b97f4a7c15));
+        for _ in 0..2000 {
+            let test_script = generator.code(3);
+            let mut script = generator.nest(test_script.into());
+            let mut expected = script.clone();
+
+            assert_eq!(reduce(&mut script), reference_reduce(&mut expected));
+            assert_eq!(expected, script);
+        }
+    }
+
     #[test]
     fn test_reduce2() {
         let test_script = script! {
@@ -299,4 +885,121 @@ mod test {
 
         assert_eq!(expected, script);
     }
+
+    #[test]
+    fn test_reduce_notif() {
+        let test_script = script! {
+            OP_NOP1
+            OP_NOTIF_RETURN_TRUE
+            OP_NOP2
+            OP_NOTIF_RETURN_TRUE
+        };
+        let mut script: StructuredScript = test_script.into();
+
+        let res = reduce(&mut script);
+        assert_eq!(res, EmitOpIfSuccess::YES);
+
+        let expected_script = script! {
+            OP_NOP1
+            OP_NOTIF
+                1
+                0
+            OP_ELSE
+                OP_NOP2
+                OP_NOTIF
+                    1
+                OP_ELSE
+                    0
+                    0
+                OP_ENDIF
+            OP_ENDIF
+            OP_IF 1 OP_ENDIF
+        };
+        let expected: StructuredScript = expected_script.into();
+
+        assert_eq!(expected, script);
+
+        // a trailing OP_NOTIF_RETURN_TRUE has no branch to take the negation
+        let mut script: StructuredScript = script! { OP_NOP1 OP_NOTIF_RETURN_TRUE }.into();
+        let res = reduce(&mut script);
+        assert_eq!(res, EmitOpIfSuccess::YES);
+
+        let expected: StructuredScript = script! { OP_NOP1 OP_NOT }.into();
+        assert_eq!(expected, script);
+    }
+
+    #[test]
+    fn test_reduce_label() {
+        let test_script = script! {
+            OP_NOP1
+            OP_IF_RETURN_TRUE { OP_SITE_LABEL("first") }
+            OP_NOP2
+            OP_IF_RETURN_TRUE { OP_SITE_LABEL("second") }
+        };
+        let mut script: StructuredScript = test_script.into();
+
+        let res = reduce(&mut script);
+        assert_eq!(res, EmitOpIfSuccess::YES);
+
+        let expected_script = script! {
+            OP_NOP1
+            OP_IF
+                { OP_SITE_LABEL("first") }
+                1
+                0
+            OP_ELSE
+                OP_NOP2
+                OP_IF
+                    { OP_SITE_LABEL("second") }
+                    1
+                OP_ELSE
+                    0
+                    0
+                OP_ENDIF
+            OP_ENDIF
+            OP_IF 1 OP_ENDIF
+        };
+        let expected: StructuredScript = expected_script.into();
+
+        assert_eq!(expected, script);
+    }
+
+    #[test]
+    fn test_reduce_switch() {
+        let test_script = script! {
+            OP_SWITCH
+            OP_CASE
+                OP_NOP1
+            OP_CASE
+                OP_IF_RETURN_TRUE
+            OP_CASE
+                OP_NOP2
+                OP_IF_RETURN_TRUE
+                OP_NOP3
+            OP_ENDSWITCH
+        };
+        let mut script: StructuredScript = test_script.into();
+
+        let res = reduce(&mut script);
+        assert_eq!(res, EmitOpIfSuccess::YES);
+
+        // the arms share a single flag
+        let expected = StructuredScript::Switch(vec![
+            script! { OP_NOP1 0 }.into(),
+            // the condition of the trailing OP_IF_RETURN_TRUE is the flag
+            StructuredScript::Script(OwnedInstructions(vec![])),
+            script! {
+                OP_NOP2
+                OP_IF
+                    1
+                OP_ELSE
+                    OP_NOP3
+                    0
+                OP_ENDIF
+            }
+            .into(),
+        ]);
+
+        assert_eq!(expected, script);
+    }
 }
@@ -0,0 +1,232 @@
+//! Splitting of the code after the success sites into guarded segments, shared by
+//! `altstack_flag` and `flat_guard`.
+//!
+//! Instead of nesting the code after a success site in the branch where the site did not
+//! succeed, as `reduce` does, a sequence is split after every site, and after every
+//! conditional or switch with sites, into segments. The first segment runs whenever the
+//! sequence runs, and the others only if the flag is still 0, so the segments of a sequence do
+//! not nest. A conditional with sites is lowered branch by branch, inside the segment it is
+//! part of.
+//!
+//! Where the flag lives, and thus how a site sets it and how a segment is guarded, is left to
+//! the `SiteFlag` of the lowering.
+
+use crate::site_label::label_len;
+use crate::structured_script::{Node, OwnedInstruction, OwnedInstructions, StructuredScript};
+use crate::{is_pseudo_opcode, _OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE, _OP_RETURN_TRUE};
+use bitcoin::Opcode;
+
+/// How a lowering keeps the flag of the success sites.
+pub(crate) trait SiteFlag {
+    /// The code of a site with the pseudo opcode `op`, which sets the flag if it succeeds.
+    fn site(op: Opcode) -> StructuredScript;
+    /// Push to `segments` a segment that is not the first of its sequence, guarded so that
+    /// it only runs if the flag is 0.
+    fn guard(segments: &mut Vec<StructuredScript>, segment: Vec<StructuredScript>);
+    /// The code that leaves a flag of 0 at the end of a sequence or a branch that does not
+    /// succeed, if the flag has to be pushed there.
+    fn no_success() -> Option<StructuredScript>;
+}
+
+/// Lower the success sites of a script that has some into segments.
+pub(crate) fn lower_segments<F: SiteFlag>(structure: StructuredScript) -> StructuredScript {
+    let Lowered::Items(items) = structure.fold(lower::<F>) else {
+        unreachable!()
+    };
+    lower_sequence::<F>(items)
+}
+
+/// A part of a sequence, in the order of the script.
+enum Item {
+    Instruction(OwnedInstruction),
+    /// The code of a site, which sets the flag.
+    Site(StructuredScript),
+    /// A conditional or a switch, and whether it has any success site.
+    Node(StructuredScript, bool),
+}
+
+/// A subtree once lowered: itself if it has no success site, or the items of the sequence it
+/// is part of.
+enum Lowered {
+    Plain(StructuredScript),
+    Items(Vec<Item>),
+}
+
+impl Lowered {
+    fn into_plain(self) -> StructuredScript {
+        match self {
+            Lowered::Plain(structure) => structure,
+            Lowered::Items(_) => unreachable!(),
+        }
+    }
+}
+
+/// Lower the subtree of the node, whose children have been lowered, bottom up so that every
+/// level is only visited once.
+fn lower<F: SiteFlag>(node: Node<Lowered>) -> Lowered {
+    let has_sites = match &node {
+        Node::Script(v) => {
+            v.0.iter()
+                .any(|inst| matches!(inst, OwnedInstruction::Op(op) if is_pseudo_opcode(*op)))
+        }
+        node => node
+            .children()
+            .into_iter()
+            .any(|v| matches!(v, Lowered::Items(_))),
+    };
+    if !has_sites {
+        return Lowered::Plain(node.map(Lowered::into_plain).into());
+    }
+
+    match node {
+        Node::Script(v) => {
+            let mut items = vec![];
+            collect_items::<F>(&v, &mut items);
+            Lowered::Items(items)
+        }
+        Node::MultiScript(vv) => {
+            let mut items = vec![];
+            for v in vv {
+                match v {
+                    Lowered::Plain(structure) => push_plain(&mut items, structure),
+                    Lowered::Items(v) => items.extend(v),
+                }
+            }
+            Lowered::Items(items)
+        }
+        node => Lowered::Items(vec![Item::Node(lower_node::<F>(node), true)]),
+    }
+}
+
+/// Lower a sequence with success sites.
+fn lower_sequence<F: SiteFlag>(items: Vec<Item>) -> StructuredScript {
+    // the first segment runs whenever the sequence runs, the others only if the flag is 0
+    let mut segments: Vec<StructuredScript> = vec![];
+    let mut segment: Vec<StructuredScript> = vec![];
+    let mut instructions: Vec<OwnedInstruction> = vec![];
+    for item in items {
+        let (node, ends_segment) = match item {
+            Item::Instruction(inst) => {
+                instructions.push(inst);
+                continue;
+            }
+            Item::Site(node) => (node, true),
+            Item::Node(node, has_sites) => (node, has_sites),
+        };
+        if !instructions.is_empty() {
+            segment.push(StructuredScript::Script(OwnedInstructions(std::mem::take(
+                &mut instructions,
+            ))));
+        }
+        segment.push(node);
+        if ends_segment {
+            push_segment::<F>(&mut segments, std::mem::take(&mut segment));
+        }
+    }
+    if !instructions.is_empty() {
+        segment.push(StructuredScript::Script(OwnedInstructions(instructions)));
+    }
+    if !segment.is_empty() {
+        // the tail after the last site does not succeed
+        segment.extend(F::no_success());
+        push_segment::<F>(&mut segments, segment);
+    }
+
+    StructuredScript::MultiScript(segments)
+}
+
+/// Join sequences lowered one by one, which each leave the flag, into one, where every
+/// sequence but the first one is guarded, and `tail`, which has no success site, is last.
+pub(crate) fn join_sequences<F: SiteFlag>(
+    sequences: Vec<StructuredScript>,
+    tail: Option<StructuredScript>,
+) -> StructuredScript {
+    let mut segments = vec![];
+    for sequence in sequences {
+        push_segment::<F>(&mut segments, vec![sequence]);
+    }
+    if let Some(tail) = tail {
+        let mut segment = vec![tail];
+        segment.extend(F::no_success());
+        push_segment::<F>(&mut segments, segment);
+    }
+    StructuredScript::MultiScript(segments)
+}
+
+fn push_segment<F: SiteFlag>(segments: &mut Vec<StructuredScript>, segment: Vec<StructuredScript>) {
+    if segments.is_empty() {
+        segments.push(StructuredScript::MultiScript(segment));
+    } else {
+        F::guard(segments, segment);
+    }
+}
+
+fn collect_items<F: SiteFlag>(v: &OwnedInstructions, items: &mut Vec<Item>) {
+    let mut i = 0;
+    while i < v.0.len() {
+        let inst = &v.0[i];
+        match inst {
+            OwnedInstruction::Op(op)
+                if *op == _OP_IF_RETURN_TRUE
+                    || *op == _OP_NOTIF_RETURN_TRUE
+                    || *op == _OP_RETURN_TRUE =>
+            {
+                items.push(Item::Site(F::site(*op)));
+                // the label is not needed anymore
+                i += 1 + label_len(&v.0, i + 1);
+            }
+            _ => {
+                items.push(Item::Instruction(inst.clone()));
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Push the items of a subtree without success sites, whose conditionals are kept as they are.
+fn push_plain(items: &mut Vec<Item>, structure: StructuredScript) {
+    // the subtrees left, the next one last
+    let mut stack = vec![structure];
+    while let Some(structure) = stack.pop() {
+        match structure.into_node() {
+            Node::Script(v) => items.extend(v.0.into_iter().map(Item::Instruction)),
+            Node::MultiScript(vv) => stack.extend(vv.into_iter().rev()),
+            node => items.push(Item::Node(node.into(), false)),
+        }
+    }
+}
+
+/// Lower a conditional or a switch with success sites. A branch starts with the flag at 0,
+/// since the conditional itself is guarded.
+fn lower_node<F: SiteFlag>(node: Node<Lowered>) -> StructuredScript {
+    let Some(no_success) = F::no_success() else {
+        return node.map(lower_branch::<F>).into();
+    };
+    match node {
+        // the missing branch does not succeed
+        Node::IfEndIf(v) => {
+            StructuredScript::IfElseEndIf(Box::new(lower_branch::<F>(v)), Box::new(no_success))
+        }
+        Node::NotIfEndIf(v) => {
+            StructuredScript::NotIfElseEndIf(Box::new(lower_branch::<F>(v)), Box::new(no_success))
+        }
+        node => node.map(lower_branch::<F>).into(),
+    }
+}
+
+fn lower_branch<F: SiteFlag>(lowered: Lowered) -> StructuredScript {
+    match lowered {
+        Lowered::Plain(structure) => match F::no_success() {
+            Some(no_success) => StructuredScript::MultiScript(vec![structure, no_success]),
+            None => structure,
+        },
+        Lowered::Items(items) => lower_sequence::<F>(items),
+    }
+}
+
+/// The script of the opcodes.
+pub(crate) fn script(opcodes: Vec<Opcode>) -> StructuredScript {
+    StructuredScript::Script(OwnedInstructions(
+        opcodes.into_iter().map(OwnedInstruction::Op).collect(),
+    ))
+}
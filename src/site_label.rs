@@ -0,0 +1,112 @@
+//! Labels of success sites.
+//!
+//! A success pseudo opcode can be followed by `<label> OP_SITE_LABEL`, which names the kind of
+//! misbehaviour it detects. The marker stays right after its site through the code cleanup
+//! and the conversion, and `reduce` moves it into the branch taken when the site succeeds.
+//! The markers are removed from the final script by `strip_site_labels`.
+
+use crate::structured_script::{OwnedInstruction, StructuredScript, WalkEvent};
+use crate::{
+    _OP_IF_RETURN_TRUE, _OP_NOTIF_RETURN_TRUE, _OP_RETURN_RESULT, _OP_RETURN_TRUE, _OP_SITE_LABEL,
+};
+use bitcoin::Opcode;
+
+/// Whether the opcode is a success site, which can carry a label.
+pub fn is_success_site(opcode: Opcode) -> bool {
+    [
+        _OP_RETURN_TRUE,
+        _OP_IF_RETURN_TRUE,
+        _OP_NOTIF_RETURN_TRUE,
+        _OP_RETURN_RESULT,
+    ]
+    .contains(&opcode)
+}
+
+/// Number of instructions of the label marker starting at `i`, which is 2 if there is a
+/// marker and 0 otherwise.
+pub fn label_len(instructions: &[OwnedInstruction], i: usize) -> usize {
+    match instructions.get(i..i + 2) {
+        Some([OwnedInstruction::PushBytes(_), OwnedInstruction::Op(op)])
+            if *op == _OP_SITE_LABEL =>
+        {
+            2
+        }
+        _ => 0,
+    }
+}
+
+/// Labels of the success sites, in the order of the script, with `None` for the sites
+/// without a label.
+pub fn site_labels(structure: &StructuredScript) -> Vec<Option<String>> {
+    let mut labels = vec![];
+    for event in structure.walk() {
+        let WalkEvent::Enter(StructuredScript::Script(v)) = event else {
+            continue;
+        };
+        for (i, inst) in v.0.iter().enumerate() {
+            if matches!(inst, OwnedInstruction::Op(op) if is_success_site(*op)) {
+                let label = match (label_len(&v.0, i + 1), v.0.get(i + 1)) {
+                    (2, Some(OwnedInstruction::PushBytes(bytes))) => {
+                        Some(String::from_utf8_lossy(bytes).into_owned())
+                    }
+                    _ => None,
+                };
+                labels.push(label);
+            }
+        }
+    }
+    labels
+}
+
+/// Remove all the label markers.
+pub fn strip_site_labels(structure: &mut StructuredScript) {
+    structure.for_each_script_mut(|v| {
+        let mut i = 0;
+        while i < v.0.len() {
+            if label_len(&v.0, i) == 2 {
+                v.0.drain(i..i + 2);
+            } else {
+                i += 1;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::site_label::{site_labels, strip_site_labels};
+    use crate::structured_script::StructuredScript;
+    use crate::{OP_IF_RETURN_TRUE, OP_RETURN_TRUE, OP_SITE_LABEL};
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_site_labels() {
+        let script = script! {
+            OP_DUP 1 OP_EQUAL OP_IF_RETURN_TRUE { OP_SITE_LABEL("first") }
+            OP_IF
+                OP_RETURN_TRUE
+            OP_ELSE
+                OP_RETURN_TRUE { OP_SITE_LABEL("third") }
+            OP_ENDIF
+        };
+
+        let mut structured_script = StructuredScript::from(script);
+        assert_eq!(
+            site_labels(&structured_script),
+            vec![Some("first".to_string()), None, Some("third".to_string())]
+        );
+
+        strip_site_labels(&mut structured_script);
+        let expected_script = script! {
+            OP_DUP 1 OP_EQUAL OP_IF_RETURN_TRUE
+            OP_IF
+                OP_RETURN_TRUE
+            OP_ELSE
+                OP_RETURN_TRUE
+            OP_ENDIF
+        };
+        assert_eq!(StructuredScript::from(expected_script), structured_script);
+    }
+}
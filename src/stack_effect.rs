@@ -0,0 +1,264 @@
+//! Stack effects of opcodes, for checking the depth of the stacks through a script.
+
+use bitcoin::opcodes::all::*;
+use bitcoin::opcodes::Opcode;
+use bitcoin::script::Instruction;
+use bitcoin::Script;
+use std::fmt::{Display, Formatter};
+
+/// Effect of an opcode on the main stack and the altstack.
+///
+/// An opcode needs at least `pops` elements on the main stack, removes them, and then adds
+/// `pushes` elements. The same holds for the altstack with `alt_pops` and `alt_pushes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub pops: usize,
+    pub pushes: usize,
+    pub alt_pops: usize,
+    pub alt_pushes: usize,
+}
+
+impl StackEffect {
+    const fn main(pops: usize, pushes: usize) -> Self {
+        Self {
+            pops,
+            pushes,
+            alt_pops: 0,
+            alt_pushes: 0,
+        }
+    }
+}
+
+/// Stack effect of an opcode in tapscript, or `None` if the opcode is a conditional, does not
+/// have a fixed effect, or always fails.
+///
+/// `OP_PICK` and `OP_ROLL` are counted as if their argument was 0, which is the least they
+/// need.
+pub fn opcode_stack_effect(opcode: Opcode) -> Option<StackEffect> {
+    let code = opcode.to_u8();
+    // pushes of data and small numbers
+    if code <= OP_PUSHDATA4.to_u8()
+        || code == OP_PUSHNUM_NEG1.to_u8()
+        || (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&code)
+    {
+        return Some(StackEffect::main(0, 1));
+    }
+
+    let effect = match opcode {
+        OP_NOP | OP_NOP1 | OP_NOP4 | OP_NOP5 | OP_NOP6 | OP_NOP7 | OP_NOP8 | OP_NOP9 | OP_NOP10
+        | OP_CODESEPARATOR => StackEffect::main(0, 0),
+        OP_CLTV | OP_CSV => StackEffect::main(1, 1),
+        OP_VERIFY | OP_DROP => StackEffect::main(1, 0),
+        OP_TOALTSTACK => StackEffect {
+            pops: 1,
+            pushes: 0,
+            alt_pops: 0,
+            alt_pushes: 1,
+        },
+        OP_FROMALTSTACK => StackEffect {
+            pops: 0,
+            pushes: 1,
+            alt_pops: 1,
+            alt_pushes: 0,
+        },
+        OP_2DROP => StackEffect::main(2, 0),
+        OP_2DUP => StackEffect::main(2, 4),
+        OP_3DUP => StackEffect::main(3, 6),
+        OP_2OVER => StackEffect::main(4, 6),
+        OP_2ROT => StackEffect::main(6, 6),
+        OP_2SWAP => StackEffect::main(4, 4),
+        OP_DEPTH => StackEffect::main(0, 1),
+        OP_DUP => StackEffect::main(1, 2),
+        OP_NIP => StackEffect::main(2, 1),
+        OP_OVER => StackEffect::main(2, 3),
+        OP_PICK => StackEffect::main(2, 2),
+        OP_ROLL => StackEffect::main(2, 1),
+        OP_ROT => StackEffect::main(3, 3),
+        OP_SWAP => StackEffect::main(2, 2),
+        OP_TUCK => StackEffect::main(2, 3),
+        OP_SIZE => StackEffect::main(1, 2),
+        OP_EQUAL => StackEffect::main(2, 1),
+        OP_EQUALVERIFY => StackEffect::main(2, 0),
+        OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => StackEffect::main(1, 1),
+        OP_ADD
+        | OP_SUB
+        | OP_BOOLAND
+        | OP_BOOLOR
+        | OP_NUMEQUAL
+        | OP_NUMNOTEQUAL
+        | OP_LESSTHAN
+        | OP_GREATERTHAN
+        | OP_LESSTHANOREQUAL
+        | OP_GREATERTHANOREQUAL
+        | OP_MIN
+        | OP_MAX => StackEffect::main(2, 1),
+        OP_NUMEQUALVERIFY => StackEffect::main(2, 0),
+        OP_WITHIN => StackEffect::main(3, 1),
+        OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => StackEffect::main(1, 1),
+        OP_CHECKSIG => StackEffect::main(2, 1),
+        OP_CHECKSIGVERIFY => StackEffect::main(2, 0),
+        OP_CHECKSIGADD => StackEffect::main(3, 1),
+        _ => return None,
+    };
+    Some(effect)
+}
+
+/// Most elements the main stack and the altstack can hold together.
+pub const MAX_STACK_ELEMENTS: usize = 1000;
+
+/// Depths of the main stack and the altstack.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StackDepth {
+    pub main: usize,
+    pub alt: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackEffectError {
+    /// The script cannot be decoded into instructions.
+    InvalidScript(String),
+    /// The instruction at `position` needs more elements than the stack has.
+    Underflow { position: usize },
+    /// The instruction at `position` does not have a fixed stack effect, see
+    /// `opcode_stack_effect`.
+    UnknownEffect { position: usize, opcode: Opcode },
+    /// The branches of the conditional ending at `position` leave the stacks at different
+    /// depths.
+    UnbalancedBranches { position: usize },
+    /// An `OP_IF`/`OP_NOTIF` without its `OP_ENDIF`, or an `OP_ELSE`/`OP_ENDIF` without its
+    /// `OP_IF`.
+    UnbalancedConditional,
+}
+
+impl Display for StackEffectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackEffectError::InvalidScript(e) => write!(f, "the script cannot be decoded: {}", e),
+            StackEffectError::Underflow { position } => {
+                write!(f, "stack underflow at instruction {}", position)
+            }
+            StackEffectError::UnknownEffect { position, opcode } => write!(
+                f,
+                "{} at instruction {} does not have a fixed stack effect",
+                opcode, position
+            ),
+            StackEffectError::UnbalancedBranches { position } => write!(
+                f,
+                "the branches of the conditional ending at instruction {} leave the stacks at different depths",
+                position
+            ),
+            StackEffectError::UnbalancedConditional => {
+                write!(f, "the script has unbalanced OP_IF/OP_NOTIF/OP_ELSE/OP_ENDIF")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StackEffectError {}
+
+/// Follow the depths of the stacks through the script, starting from `start`, and return the
+/// depths at the end.
+///
+/// Both branches of a conditional must leave the stacks at the same depths.
+pub fn simulate_stack_depth(
+    script: &Script,
+    start: StackDepth,
+) -> Result<StackDepth, StackEffectError> {
+    // for each open conditional, the depths at the start of its branches, and the depths at
+    // the end of its first branch once an OP_ELSE has been seen
+    let mut open: Vec<(StackDepth, Option<StackDepth>)> = vec![];
+    let mut depth = start;
+
+    for (position, inst) in script.instructions().enumerate() {
+        let inst = inst.map_err(|e| StackEffectError::InvalidScript(e.to_string()))?;
+        let opcode = match inst {
+            Instruction::PushBytes(_) => {
+                depth.main += 1;
+                continue;
+            }
+            Instruction::Op(opcode) => opcode,
+        };
+
+        if opcode == OP_IF || opcode == OP_NOTIF {
+            depth.main = depth
+                .main
+                .checked_sub(1)
+                .ok_or(StackEffectError::Underflow { position })?;
+            open.push((depth, None));
+        } else if opcode == OP_ELSE {
+            match open.last_mut() {
+                Some((branch_start, first_branch @ None)) => {
+                    *first_branch = Some(depth);
+                    depth = *branch_start;
+                }
+                _ => return Err(StackEffectError::UnbalancedConditional),
+            }
+        } else if opcode == OP_ENDIF {
+            let (branch_start, first_branch) =
+                open.pop().ok_or(StackEffectError::UnbalancedConditional)?;
+            // without OP_ELSE, the other branch does nothing
+            if first_branch.unwrap_or(branch_start) != depth {
+                return Err(StackEffectError::UnbalancedBranches { position });
+            }
+        } else {
+            let effect = opcode_stack_effect(opcode)
+                .ok_or(StackEffectError::UnknownEffect { position, opcode })?;
+            if depth.main < effect.pops || depth.alt < effect.alt_pops {
+                return Err(StackEffectError::Underflow { position });
+            }
+            depth.main = depth.main - effect.pops + effect.pushes;
+            depth.alt = depth.alt - effect.alt_pops + effect.alt_pushes;
+        }
+    }
+
+    if open.is_empty() {
+        Ok(depth)
+    } else {
+        Err(StackEffectError::UnbalancedConditional)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stack_effect::{simulate_stack_depth, StackDepth, StackEffectError};
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_simulate_stack_depth() {
+        let script = script! {
+            OP_FROMALTSTACK OP_DUP OP_SHA256
+            OP_IF
+                OP_DROP 1
+            OP_ELSE
+                OP_TOALTSTACK 0
+            OP_ENDIF
+        };
+        assert_eq!(
+            simulate_stack_depth(&script, StackDepth { main: 0, alt: 1 }),
+            Err(StackEffectError::UnbalancedBranches { position: 9 })
+        );
+
+        let script = script! {
+            OP_FROMALTSTACK OP_SHA256 OP_DUP
+            OP_IF
+                OP_DROP 1
+            OP_ENDIF
+        };
+        assert_eq!(
+            simulate_stack_depth(&script, StackDepth { main: 0, alt: 1 }),
+            Ok(StackDepth { main: 1, alt: 0 })
+        );
+        assert_eq!(
+            simulate_stack_depth(&script, StackDepth::default()),
+            Err(StackEffectError::Underflow { position: 0 })
+        );
+
+        let script = script! { 1 OP_IFDUP };
+        assert!(matches!(
+            simulate_stack_depth(&script, StackDepth::default()),
+            Err(StackEffectError::UnknownEffect { position: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+//! Check of the limit of `MAX_STACK_ELEMENTS` elements on the main stack and the altstack
+//! together, beyond which the execution fails.
+//!
+//! The limit is checked on the compiled script, so that the flags that carry a success and the
+//! success code are counted, with the depths followed by `analyze_stack_depths` from the number
+//! of witness elements. The compiled script is rewritten too much for its paths to be read, so
+//! when it may exceed the limit, the point of the input script where it holds the most
+//! elements is reported instead, with the branches taken to reach it.
+
+use crate::depth_analysis::{analyze_stack_depths, BranchTaken, StackAnalysis};
+use crate::stack_effect::{StackEffectError, MAX_STACK_ELEMENTS};
+use crate::structured_script::StructuredScript;
+use bitcoin::{Opcode, Script};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackLimitError {
+    /// The depths cannot be followed past the opcode at `position` of the input script, or of
+    /// the compiled script if the input script does not reach it, whose effect is not known.
+    UnknownEffect { position: usize, opcode: Opcode },
+    /// The compiled script may hold `elements` elements, more than `MAX_STACK_ELEMENTS`. The
+    /// input script holds the most elements, `input_elements`, first before the instruction at
+    /// `position`, which is reached through the branches of `path`, outermost first.
+    Exceeded {
+        elements: usize,
+        input_elements: usize,
+        position: usize,
+        path: Vec<BranchTaken>,
+    },
+}
+
+/// Most elements on the main stack and the altstack together through the compiled script,
+/// when it starts with `witness_elements` elements, or where the limit may be exceeded.
+///
+/// The input script is the one that was compiled, so it must have balanced conditionals and
+/// switches.
+pub fn check_stack_limit(
+    input: &Script,
+    compiled: &Script,
+    witness_elements: usize,
+) -> Result<usize, StackLimitError> {
+    let input = analyze(input)?;
+    let (_, elements) = most_elements(&analyze(compiled)?, witness_elements);
+    if elements <= MAX_STACK_ELEMENTS {
+        return Ok(elements);
+    }
+
+    let (position, input_elements) = most_elements(&input, witness_elements);
+    Err(StackLimitError::Exceeded {
+        elements,
+        input_elements,
+        position,
+        path: input.path(position),
+    })
+}
+
+fn analyze(script: &Script) -> Result<StackAnalysis, StackLimitError> {
+    let structure = StructuredScript::from(script.to_owned());
+    analyze_stack_depths(&structure).map_err(|e| match e {
+        StackEffectError::UnknownEffect { position, opcode } => {
+            StackLimitError::UnknownEffect { position, opcode }
+        }
+        e => unreachable!("the analysis only fails at an unknown effect: {}", e),
+    })
+}
+
+/// The first point with the most elements, and how many.
+fn most_elements(analysis: &StackAnalysis, witness_elements: usize) -> (usize, usize) {
+    // the witness at the start
+    let mut most = (0, witness_elements);
+    for (position, depths) in analysis.points.iter().enumerate() {
+        let Some(depths) = depths else {
+            continue;
+        };
+        let elements = (witness_elements as isize + depths.total.max).max(0) as usize;
+        if elements > most.1 {
+            most = (position, elements);
+        }
+    }
+    most
+}
+
+#[cfg(test)]
+mod test {
+    use crate::depth_analysis::BranchTaken;
+    use crate::stack_limit::{check_stack_limit, StackLimitError};
+    use bitcoin::opcodes::all::OP_CAT;
+    use bitcoin_script::{define_pushable, script};
+
+    define_pushable!();
+
+    #[test]
+    fn test_check_stack_limit() {
+        let script = script! {
+            OP_DUP
+            OP_IF
+                for _ in 0..500 {
+                    0
+                }
+                for _ in 0..499 {
+                    OP_DUP OP_TOALTSTACK
+                }
+            OP_ENDIF
+        };
+        // the condition is on the stack with the elements of the witness
+        assert_eq!(check_stack_limit(&script, &script, 1), Ok(1000));
+        assert_eq!(
+            check_stack_limit(&script, &script, 2),
+            Err(StackLimitError::Exceeded {
+                elements: 1001,
+                input_elements: 1001,
+                position: 1499,
+                path: vec![BranchTaken {
+                    position: 1,
+                    branch: 0
+                }],
+            })
+        );
+
+        let compiled = script! { { script.clone() } 1 };
+        assert!(matches!(
+            check_stack_limit(&script, &compiled, 1),
+            Err(StackLimitError::Exceeded {
+                elements: 1001,
+                input_elements: 1000,
+                ..
+            })
+        ));
+
+        // the code that is not reached does not matter
+        let script = script! { OP_RETURN OP_CAT };
+        assert_eq!(check_stack_limit(&script, &script, 1), Ok(1));
+        let script = script! { OP_CAT };
+        assert_eq!(
+            check_stack_limit(&script, &script, 1),
+            Err(StackLimitError::UnknownEffect {
+                position: 0,
+                opcode: OP_CAT
+            })
+        );
+    }
+}
@@ -1,10 +1,15 @@
-use bitcoin::opcodes::all::{OP_ELSE, OP_ENDIF, OP_IF, OP_NOTIF, OP_PUSHBYTES_0};
+use crate::parallel;
+use crate::{is_pseudo_opcode, _OP_CASE, _OP_ENDSWITCH, _OP_SWITCH};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::opcodes::all::{
+    OP_DROP, OP_DUP, OP_ELSE, OP_ENDIF, OP_IF, OP_NOTIF, OP_NUMEQUAL, OP_NUMEQUALVERIFY,
+    OP_PUSHBYTES_0,
+};
 use bitcoin::opcodes::Ordinary::{OP_PUSHDATA1, OP_PUSHDATA2};
-use bitcoin::script::Instruction;
+use bitcoin::script::{Builder, Instruction};
 use bitcoin::{Opcode, ScriptBuf};
 use std::cmp::PartialEq;
 use std::fmt::Debug;
-use std::iter::Peekable;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum OwnedInstruction {
@@ -12,10 +17,15 @@ pub enum OwnedInstruction {
     PushBytes(Vec<u8>),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct OwnedInstructions(pub Vec<OwnedInstruction>);
 
-#[derive(Debug, PartialEq, Clone)]
+/// The structure of a script, as a tree of conditionals.
+///
+/// Machine-generated scripts can nest conditionals many thousands of levels deep, so the
+/// traversals of the tree, including `Clone`, `PartialEq` and `Drop`, keep the nodes left to
+/// visit on the heap instead of recursing, see `walk` and `fold`.
+#[derive(Debug)]
 pub enum StructuredScript {
     Script(OwnedInstructions),
     MultiScript(Vec<StructuredScript>),
@@ -23,6 +33,392 @@ pub enum StructuredScript {
     NotIfEndIf(Box<StructuredScript>),
     IfElseEndIf(Box<StructuredScript>, Box<StructuredScript>),
     NotIfElseEndIf(Box<StructuredScript>, Box<StructuredScript>),
+    /// A switch on the integer on top of the stack, with one entry per arm.
+    Switch(Vec<StructuredScript>),
+}
+
+/// A node of a `StructuredScript` whose children have been replaced by values, as passed to
+/// `StructuredScript::fold`.
+#[derive(Debug)]
+pub enum Node<T> {
+    Script(OwnedInstructions),
+    MultiScript(Vec<T>),
+    IfEndIf(T),
+    NotIfEndIf(T),
+    IfElseEndIf(T, T),
+    NotIfElseEndIf(T, T),
+    Switch(Vec<T>),
+}
+
+impl<T> Node<T> {
+    /// The children, in the order of the script.
+    pub fn children(&self) -> Vec<&T> {
+        match self {
+            Node::Script(_) => vec![],
+            Node::MultiScript(vv) | Node::Switch(vv) => vv.iter().collect(),
+            Node::IfEndIf(v) | Node::NotIfEndIf(v) => vec![v],
+            Node::IfElseEndIf(v1, v2) | Node::NotIfElseEndIf(v1, v2) => vec![v1, v2],
+        }
+    }
+
+    /// The node of the same kind, with `f` applied to every child in the order of the script.
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> Node<U> {
+        match self {
+            Node::Script(v) => Node::Script(v),
+            Node::MultiScript(vv) => Node::MultiScript(vv.into_iter().map(f).collect()),
+            Node::IfEndIf(v) => Node::IfEndIf(f(v)),
+            Node::NotIfEndIf(v) => Node::NotIfEndIf(f(v)),
+            Node::IfElseEndIf(v1, v2) => {
+                let v1 = f(v1);
+                Node::IfElseEndIf(v1, f(v2))
+            }
+            Node::NotIfElseEndIf(v1, v2) => {
+                let v1 = f(v1);
+                Node::NotIfElseEndIf(v1, f(v2))
+            }
+            Node::Switch(arms) => Node::Switch(arms.into_iter().map(f).collect()),
+        }
+    }
+}
+
+impl From<Node<StructuredScript>> for StructuredScript {
+    fn from(value: Node<StructuredScript>) -> Self {
+        match value {
+            Node::Script(v) => StructuredScript::Script(v),
+            Node::MultiScript(vv) => StructuredScript::MultiScript(vv),
+            Node::IfEndIf(v) => StructuredScript::IfEndIf(Box::new(v)),
+            Node::NotIfEndIf(v) => StructuredScript::NotIfEndIf(Box::new(v)),
+            Node::IfElseEndIf(v1, v2) => StructuredScript::IfElseEndIf(Box::new(v1), Box::new(v2)),
+            Node::NotIfElseEndIf(v1, v2) => {
+                StructuredScript::NotIfElseEndIf(Box::new(v1), Box::new(v2))
+            }
+            Node::Switch(arms) => StructuredScript::Switch(arms),
+        }
+    }
+}
+
+/// A step of `StructuredScript::walk`.
+#[derive(Debug, Clone, Copy)]
+pub enum WalkEvent<'a> {
+    /// The node is reached, before its children.
+    Enter(&'a StructuredScript),
+    /// The node is left, after its children.
+    Leave(&'a StructuredScript),
+}
+
+/// Iterator returned by `StructuredScript::walk`.
+pub struct Walk<'a> {
+    // the events left, the next one last
+    stack: Vec<WalkEvent<'a>>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = WalkEvent<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = self.stack.pop()?;
+        if let WalkEvent::Enter(structure) = event {
+            self.stack.push(WalkEvent::Leave(structure));
+            match structure {
+                StructuredScript::Script(_) => {}
+                StructuredScript::MultiScript(vv) | StructuredScript::Switch(vv) => {
+                    self.stack.extend(vv.iter().rev().map(WalkEvent::Enter))
+                }
+                StructuredScript::IfEndIf(v) | StructuredScript::NotIfEndIf(v) => {
+                    self.stack.push(WalkEvent::Enter(v))
+                }
+                StructuredScript::IfElseEndIf(v1, v2)
+                | StructuredScript::NotIfElseEndIf(v1, v2) => {
+                    self.stack.push(WalkEvent::Enter(v2));
+                    self.stack.push(WalkEvent::Enter(v1));
+                }
+            }
+        }
+        Some(event)
+    }
+}
+
+impl StructuredScript {
+    /// Visit the nodes in the order of the script, each one entered before its children and
+    /// left after them.
+    pub fn walk(&self) -> Walk<'_> {
+        Walk {
+            stack: vec![WalkEvent::Enter(self)],
+        }
+    }
+
+    /// Compute a value for every node from the node and the values of its children, bottom
+    /// up, and return the value of the root.
+    pub fn fold_ref<T>(&self, mut f: impl FnMut(&StructuredScript, Vec<T>) -> T) -> T {
+        let mut values: Vec<T> = vec![];
+        for event in self.walk() {
+            if let WalkEvent::Leave(structure) = event {
+                let children = values.split_off(values.len() - structure.children_len());
+                values.push(f(structure, children));
+            }
+        }
+        values.pop().unwrap()
+    }
+
+    /// Same as `fold_ref`, but taking the nodes apart, so that `f` owns the instructions and
+    /// the values of the children. Rebuilding every node with `StructuredScript::from`
+    /// rewrites the structure bottom up.
+    pub fn fold<T>(self, mut f: impl FnMut(Node<T>) -> T) -> T {
+        // a node with its children taken out, the children left to fold, last first, and the
+        // values of the ones folded
+        struct Frame<T> {
+            structure: StructuredScript,
+            children: Vec<StructuredScript>,
+            values: Vec<T>,
+        }
+        let frame = |mut structure: StructuredScript| {
+            let mut children = structure.take_children();
+            children.reverse();
+            Frame {
+                structure,
+                children,
+                values: vec![],
+            }
+        };
+
+        let mut stack = vec![frame(self)];
+        loop {
+            let top = stack.last_mut().unwrap();
+            if let Some(child) = top.children.pop() {
+                stack.push(frame(child));
+                continue;
+            }
+
+            let Frame {
+                mut structure,
+                values,
+                ..
+            } = stack.pop().unwrap();
+            let node = match &mut structure {
+                StructuredScript::Script(v) => Node::Script(std::mem::take(v)),
+                structure => structure.node_with_children(values),
+            };
+            let value = f(node);
+            match stack.last_mut() {
+                Some(parent) => parent.values.push(value),
+                None => return value,
+            }
+        }
+    }
+
+    /// Call `f` on the instructions of every `Script` node, in the order of the script.
+    pub fn for_each_script_mut(&mut self, mut f: impl FnMut(&mut OwnedInstructions)) {
+        // the nodes left to visit, the next one last
+        let mut stack = vec![self];
+        while let Some(structure) = stack.pop() {
+            match structure {
+                StructuredScript::Script(v) => f(v),
+                StructuredScript::MultiScript(vv) | StructuredScript::Switch(vv) => {
+                    stack.extend(vv.iter_mut().rev())
+                }
+                StructuredScript::IfEndIf(v) | StructuredScript::NotIfEndIf(v) => stack.push(v),
+                StructuredScript::IfElseEndIf(v1, v2)
+                | StructuredScript::NotIfElseEndIf(v1, v2) => {
+                    stack.push(v2);
+                    stack.push(v1);
+                }
+            }
+        }
+    }
+
+    /// Same as `fold`, with the elements of a root `MultiScript`, which are the top-level
+    /// regions of the script, folded in parallel with the `parallel` feature.
+    pub fn fold_regions<T: Send>(self, f: impl Fn(Node<T>) -> T + Sync + Send) -> T {
+        match self.into_node() {
+            Node::MultiScript(regions) => {
+                let values = parallel::map(regions, |region| region.fold(&f));
+                f(Node::MultiScript(values))
+            }
+            node => StructuredScript::from(node).fold(f),
+        }
+    }
+
+    /// Take the node apart, to match on its instructions or its children by value.
+    pub fn into_node(mut self) -> Node<StructuredScript> {
+        match &mut self {
+            StructuredScript::Script(v) => Node::Script(std::mem::take(v)),
+            structure => {
+                let children = structure.take_children();
+                structure.node_with_children(children)
+            }
+        }
+    }
+
+    /// Hash of the subtree, which only depends on its structure and instructions, so that
+    /// identical subtrees have the same hash wherever they appear.
+    pub fn subtree_hash(&self) -> sha256::Hash {
+        self.fold_ref(|structure, children| structure.node_hash(&children))
+    }
+
+    /// Hash of the subtree from the hashes of the subtrees of its children, so that the
+    /// hashes of all the subtrees can be computed bottom up at once, see `subtree_hash`.
+    pub fn node_hash(&self, children: &[sha256::Hash]) -> sha256::Hash {
+        // every node starts with a tag, and variable-length parts are prefixed by their
+        // length, so that different trees never produce the same sequence of bytes
+        let mut engine = sha256::Hash::engine();
+        match self {
+            StructuredScript::Script(v) => {
+                let mut buf = vec![];
+                write_instructions(&mut buf, &v.0);
+                engine.input(&[0]);
+                engine.input(&(buf.len() as u64).to_le_bytes());
+                engine.input(&buf);
+            }
+            StructuredScript::MultiScript(vv) => {
+                engine.input(&[1]);
+                engine.input(&(vv.len() as u64).to_le_bytes());
+            }
+            StructuredScript::IfEndIf(_) => engine.input(&[2]),
+            StructuredScript::NotIfEndIf(_) => engine.input(&[3]),
+            StructuredScript::IfElseEndIf(..) => engine.input(&[4]),
+            StructuredScript::NotIfElseEndIf(..) => engine.input(&[5]),
+            StructuredScript::Switch(arms) => {
+                engine.input(&[6]);
+                engine.input(&(arms.len() as u64).to_le_bytes());
+            }
+        }
+        for child in children {
+            engine.input(child.as_byte_array());
+        }
+        sha256::Hash::from_engine(engine)
+    }
+
+    /// Whether the subtree contains any pseudo opcode, which the compiler would rewrite.
+    pub fn contains_pseudo_opcodes(&self) -> bool {
+        self.walk().any(|event| match event {
+            WalkEvent::Enter(StructuredScript::Script(v)) => {
+                v.0.iter()
+                    .any(|inst| matches!(inst, OwnedInstruction::Op(op) if is_pseudo_opcode(*op)))
+            }
+            _ => false,
+        })
+    }
+
+    fn children_len(&self) -> usize {
+        match self {
+            StructuredScript::Script(_) => 0,
+            StructuredScript::MultiScript(vv) | StructuredScript::Switch(vv) => vv.len(),
+            StructuredScript::IfEndIf(_) | StructuredScript::NotIfEndIf(_) => 1,
+            StructuredScript::IfElseEndIf(..) | StructuredScript::NotIfElseEndIf(..) => 2,
+        }
+    }
+
+    /// Move the children out of the node, leaving empty scripts in their place, to visit them
+    /// without recursing and put them back with `set_children`.
+    pub fn take_children(&mut self) -> Vec<StructuredScript> {
+        match self {
+            StructuredScript::Script(_) => vec![],
+            StructuredScript::MultiScript(vv) | StructuredScript::Switch(vv) => std::mem::take(vv),
+            StructuredScript::IfEndIf(v) | StructuredScript::NotIfEndIf(v) => {
+                vec![std::mem::take(&mut **v)]
+            }
+            StructuredScript::IfElseEndIf(v1, v2) | StructuredScript::NotIfElseEndIf(v1, v2) => {
+                vec![std::mem::take(&mut **v1), std::mem::take(&mut **v2)]
+            }
+        }
+    }
+
+    /// Replace the children of the node, of which a conditional takes as many as it has
+    /// branches.
+    pub fn set_children(&mut self, children: Vec<StructuredScript>) {
+        let mut children = children.into_iter();
+        match self {
+            StructuredScript::Script(_) => {}
+            StructuredScript::MultiScript(vv) | StructuredScript::Switch(vv) => {
+                *vv = children.collect()
+            }
+            StructuredScript::IfEndIf(v) | StructuredScript::NotIfEndIf(v) => {
+                **v = children.next().unwrap()
+            }
+            StructuredScript::IfElseEndIf(v1, v2) | StructuredScript::NotIfElseEndIf(v1, v2) => {
+                **v1 = children.next().unwrap();
+                **v2 = children.next().unwrap();
+            }
+        }
+    }
+
+    /// The node of the same kind, with the given children, for any node but a `Script`.
+    fn node_with_children<T>(&self, children: Vec<T>) -> Node<T> {
+        match self {
+            StructuredScript::Script(_) => unreachable!(),
+            StructuredScript::MultiScript(_) => Node::MultiScript(children),
+            StructuredScript::Switch(_) => Node::Switch(children),
+            _ => {
+                let mut children = children.into_iter();
+                let v1 = children.next().unwrap();
+                match (self, children.next()) {
+                    (StructuredScript::IfEndIf(_), None) => Node::IfEndIf(v1),
+                    (StructuredScript::NotIfEndIf(_), None) => Node::NotIfEndIf(v1),
+                    (StructuredScript::IfElseEndIf(..), Some(v2)) => Node::IfElseEndIf(v1, v2),
+                    (StructuredScript::NotIfElseEndIf(..), Some(v2)) => {
+                        Node::NotIfElseEndIf(v1, v2)
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+/// The empty script.
+impl Default for StructuredScript {
+    fn default() -> Self {
+        StructuredScript::Script(OwnedInstructions::default())
+    }
+}
+
+impl Clone for StructuredScript {
+    fn clone(&self) -> Self {
+        self.fold_ref(|structure, children| match structure {
+            StructuredScript::Script(v) => StructuredScript::Script(v.clone()),
+            structure => structure.node_with_children(children).into(),
+        })
+    }
+}
+
+impl PartialEq for StructuredScript {
+    fn eq(&self, other: &Self) -> bool {
+        // the nodes in the order of the script, with the number of their children, determine
+        // the tree
+        let enter = |event| match event {
+            WalkEvent::Enter(structure) => Some(structure),
+            WalkEvent::Leave(_) => None,
+        };
+        let mut nodes = self.walk().filter_map(enter);
+        let mut other_nodes = other.walk().filter_map(enter);
+        loop {
+            let same = match (nodes.next(), other_nodes.next()) {
+                (None, None) => return true,
+                (Some(StructuredScript::Script(v1)), Some(StructuredScript::Script(v2))) => {
+                    v1 == v2
+                }
+                (Some(v1), Some(v2)) => {
+                    std::mem::discriminant(v1) == std::mem::discriminant(v2)
+                        && v1.children_len() == v2.children_len()
+                }
+                _ => false,
+            };
+            if !same {
+                return false;
+            }
+        }
+    }
+}
+
+impl Drop for StructuredScript {
+    fn drop(&mut self) {
+        // the children are taken out before they are dropped, so that dropping a deep tree
+        // does not recurse
+        let mut children = self.take_children();
+        while let Some(mut child) = children.pop() {
+            children.extend(child.take_children());
+        }
+    }
 }
 
 impl From<ScriptBuf> for OwnedInstructions {
@@ -48,8 +444,7 @@ impl From<ScriptBuf> for OwnedInstructions {
 
 impl From<OwnedInstructions> for StructuredScript {
     fn from(value: OwnedInstructions) -> Self {
-        let mut iter = value.0.iter().peekable();
-        create_structured_script(&mut iter)
+        create_structured_script(&value.0)
     }
 }
 
@@ -68,168 +463,232 @@ impl From<StructuredScript> for ScriptBuf {
     }
 }
 
+/// A part of the script left to write.
+enum Piece<'a> {
+    Structure(&'a StructuredScript),
+    Opcode(Opcode),
+    Bytes(Vec<u8>),
+}
+
 fn write_script_buf(buf: &mut Vec<u8>, structure: &StructuredScript) {
-    match structure {
-        StructuredScript::Script(v) => {
-            for inst in v.0.iter() {
-                match inst {
-                    OwnedInstruction::Op(op) => buf.push(op.to_u8()),
-                    OwnedInstruction::PushBytes(v) => {
-                        let len = v.len();
-                        if len == 0 {
-                            buf.push(OP_PUSHBYTES_0.to_u8());
-                        } else if len <= 75 {
-                            buf.push(len as u8);
-                            buf.extend_from_slice(v);
-                        } else {
-                            if len <= 255 {
-                                buf.push(OP_PUSHDATA1.to_u8());
-                                buf.push(len as u8);
-                                buf.extend_from_slice(v);
-                            } else if len <= 65535 {
-                                buf.push(OP_PUSHDATA2.to_u8());
-                                buf.push((len & 0xff) as u8);
-                                buf.push((len >> 8) as u8);
-                                buf.extend_from_slice(v);
-                            } else {
-                                // one cannot push more than 520 bytes to the stack
-                                unreachable!()
-                            }
-                        }
+    // the pieces left, the next one last
+    let mut pieces = vec![Piece::Structure(structure)];
+    while let Some(piece) = pieces.pop() {
+        let structure = match piece {
+            Piece::Structure(structure) => structure,
+            Piece::Opcode(op) => {
+                buf.push(op.to_u8());
+                continue;
+            }
+            Piece::Bytes(bytes) => {
+                buf.extend_from_slice(&bytes);
+                continue;
+            }
+        };
+
+        let mut next = vec![];
+        match structure {
+            StructuredScript::Script(v) => write_instructions(buf, &v.0),
+            StructuredScript::MultiScript(vv) => next.extend(vv.iter().map(Piece::Structure)),
+            StructuredScript::IfEndIf(v) => next.extend([
+                Piece::Opcode(OP_IF),
+                Piece::Structure(v),
+                Piece::Opcode(OP_ENDIF),
+            ]),
+            StructuredScript::NotIfEndIf(v) => next.extend([
+                Piece::Opcode(OP_NOTIF),
+                Piece::Structure(v),
+                Piece::Opcode(OP_ENDIF),
+            ]),
+            StructuredScript::IfElseEndIf(v1, v2) => next.extend([
+                Piece::Opcode(OP_IF),
+                Piece::Structure(v1),
+                Piece::Opcode(OP_ELSE),
+                Piece::Structure(v2),
+                Piece::Opcode(OP_ENDIF),
+            ]),
+            StructuredScript::NotIfElseEndIf(v1, v2) => next.extend([
+                Piece::Opcode(OP_NOTIF),
+                Piece::Structure(v1),
+                Piece::Opcode(OP_ELSE),
+                Piece::Structure(v2),
+                Piece::Opcode(OP_ENDIF),
+            ]),
+            StructuredScript::Switch(arms) => {
+                // a chain of `OP_DUP <i> OP_NUMEQUAL OP_IF OP_DROP <arm i> OP_ELSE`, where the
+                // last arm checks its index with OP_NUMEQUALVERIFY, which rejects any integer
+                // out of range
+                let len = arms.len();
+                for (i, arm) in arms.iter().enumerate() {
+                    let index = Builder::new().push_int(i as i64).into_script();
+                    if i != len - 1 {
+                        next.push(Piece::Opcode(OP_DUP));
+                        next.push(Piece::Bytes(index.into_bytes()));
+                        next.push(Piece::Opcode(OP_NUMEQUAL));
+                        next.push(Piece::Opcode(OP_IF));
+                        next.push(Piece::Opcode(OP_DROP));
+                        next.push(Piece::Structure(arm));
+                        next.push(Piece::Opcode(OP_ELSE));
+                    } else {
+                        next.push(Piece::Bytes(index.into_bytes()));
+                        next.push(Piece::Opcode(OP_NUMEQUALVERIFY));
+                        next.push(Piece::Structure(arm));
                     }
                 }
+                for _ in 1..len {
+                    next.push(Piece::Opcode(OP_ENDIF));
+                }
             }
         }
-        StructuredScript::MultiScript(vv) => {
-            for v in vv.iter() {
-                write_script_buf(buf, v);
+        pieces.extend(next.into_iter().rev());
+    }
+}
+
+fn write_instructions(buf: &mut Vec<u8>, instructions: &[OwnedInstruction]) {
+    for inst in instructions.iter() {
+        match inst {
+            OwnedInstruction::Op(op) => buf.push(op.to_u8()),
+            OwnedInstruction::PushBytes(v) => {
+                let len = v.len();
+                if len == 0 {
+                    buf.push(OP_PUSHBYTES_0.to_u8());
+                } else if len <= 75 {
+                    buf.push(len as u8);
+                    buf.extend_from_slice(v);
+                } else {
+                    if len <= 255 {
+                        buf.push(OP_PUSHDATA1.to_u8());
+                        buf.push(len as u8);
+                        buf.extend_from_slice(v);
+                    } else if len <= 65535 {
+                        buf.push(OP_PUSHDATA2.to_u8());
+                        buf.push((len & 0xff) as u8);
+                        buf.push((len >> 8) as u8);
+                        buf.extend_from_slice(v);
+                    } else {
+                        // one cannot push more than 520 bytes to the stack
+                        unreachable!()
+                    }
+                }
             }
         }
-        StructuredScript::IfEndIf(v) => {
-            buf.push(OP_IF.to_u8());
-            write_script_buf(buf, v);
-            buf.push(OP_ENDIF.to_u8());
-        }
-        StructuredScript::NotIfEndIf(v) => {
-            buf.push(OP_NOTIF.to_u8());
-            write_script_buf(buf, v);
-            buf.push(OP_ENDIF.to_u8());
-        }
-        StructuredScript::IfElseEndIf(v1, v2) => {
-            buf.push(OP_IF.to_u8());
-            write_script_buf(buf, v1);
-            buf.push(OP_ELSE.to_u8());
-            write_script_buf(buf, v2);
-            buf.push(OP_ENDIF.to_u8());
+    }
+}
+
+/// A sequence being parsed: the structures so far, and the instructions after them.
+#[derive(Default)]
+struct Sequence {
+    all: Vec<StructuredScript>,
+    cur: Vec<OwnedInstruction>,
+}
+
+impl Sequence {
+    fn push(&mut self, structure: StructuredScript) {
+        self.flush();
+        self.all.push(structure);
+    }
+
+    fn flush(&mut self) {
+        if !self.cur.is_empty() {
+            let cur = std::mem::take(&mut self.cur);
+            self.all
+                .push(StructuredScript::Script(OwnedInstructions(cur)));
         }
-        StructuredScript::NotIfElseEndIf(v1, v2) => {
-            buf.push(OP_NOTIF.to_u8());
-            write_script_buf(buf, v1);
-            buf.push(OP_ELSE.to_u8());
-            write_script_buf(buf, v2);
-            buf.push(OP_ENDIF.to_u8());
+    }
+
+    fn finish(mut self) -> StructuredScript {
+        self.flush();
+        if self.all.len() == 1 {
+            self.all.pop().unwrap()
+        } else {
+            StructuredScript::MultiScript(self.all)
         }
     }
 }
 
-fn create_structured_script(
-    iter: &mut Peekable<core::slice::Iter<OwnedInstruction>>,
-) -> StructuredScript {
-    let mut cur = vec![];
-    let mut all = vec![];
-
-    while iter.peek().is_some() {
-        let next_instruction = iter.peek().unwrap();
-        if **next_instruction == OwnedInstruction::Op(OP_IF) {
-            iter.next().unwrap();
-            if !cur.is_empty() {
-                all.push(StructuredScript::Script(OwnedInstructions(cur.clone())));
-                cur.clear();
-            }
+fn create_structured_script(instructions: &[OwnedInstruction]) -> StructuredScript {
+    enum Block {
+        // whether it is an OP_NOTIF, and the branch before OP_ELSE once it has been seen
+        If(bool, Option<StructuredScript>),
+        // the arms before the current one
+        Switch(Vec<StructuredScript>),
+    }
+    // the blocks around the current position, each with the sequence before it
+    let mut open: Vec<(Block, Sequence)> = vec![];
+    let mut sequence = Sequence::default();
 
-            let if_branch = create_structured_script(iter);
-
-            // there must be a next instruction
-            let next_instruction = iter.next().unwrap();
-            if *next_instruction == OwnedInstruction::Op(OP_ELSE) {
-                let else_branch = create_structured_script(iter);
-
-                // there must be a next instruction
-                let next_instruction = iter.next().unwrap();
-                assert_eq!(*next_instruction, OwnedInstruction::Op(OP_ENDIF));
-
-                all.push(StructuredScript::IfElseEndIf(
-                    Box::new(if_branch),
-                    Box::new(else_branch),
-                ));
-            } else if *next_instruction == OwnedInstruction::Op(OP_ENDIF) {
-                all.push(StructuredScript::IfEndIf(Box::new(if_branch)));
-            } else {
-                panic!("An if branch does not seem to end correctly.");
-            }
-        } else if **next_instruction == OwnedInstruction::Op(OP_NOTIF) {
-            iter.next().unwrap();
-            if !cur.is_empty() {
-                all.push(StructuredScript::Script(OwnedInstructions(cur.clone())));
-                cur.clear();
+    let mut iter = instructions.iter();
+    while let Some(inst) = iter.next() {
+        let op = match inst {
+            OwnedInstruction::Op(op) => *op,
+            OwnedInstruction::PushBytes(_) => {
+                sequence.cur.push(inst.clone());
+                continue;
             }
+        };
 
-            let not_if_branch = create_structured_script(iter);
-
-            // there must be a next instruction
-            let next_instruction = iter.next().unwrap();
-            if *next_instruction == OwnedInstruction::Op(OP_ELSE) {
-                let else_branch = create_structured_script(iter);
-
-                // there must be a next instruction
-                let next_instruction = iter.next().unwrap();
-                assert_eq!(*next_instruction, OwnedInstruction::Op(OP_ENDIF));
-
-                all.push(StructuredScript::NotIfElseEndIf(
-                    Box::new(not_if_branch),
-                    Box::new(else_branch),
-                ));
-            } else if *next_instruction == OwnedInstruction::Op(OP_ENDIF) {
-                all.push(StructuredScript::NotIfEndIf(Box::new(not_if_branch)));
-            } else {
-                panic!("An not-if branch does not seem to end correctly.");
-            }
-        } else if **next_instruction == OwnedInstruction::Op(OP_ELSE)
-            || **next_instruction == OwnedInstruction::Op(OP_ENDIF)
-        {
-            if !cur.is_empty() {
-                all.push(StructuredScript::Script(OwnedInstructions(cur.clone())));
-                cur.clear();
+        if op == OP_IF || op == OP_NOTIF {
+            let outer = std::mem::take(&mut sequence);
+            open.push((Block::If(op == OP_NOTIF, None), outer));
+        } else if op == _OP_SWITCH {
+            // every arm starts with an OP_CASE, and the last arm ends with OP_ENDSWITCH
+            if iter.next() != Some(&OwnedInstruction::Op(_OP_CASE)) {
+                panic!("A switch does not seem to be structured correctly.");
             }
-
-            return if all.len() == 1 {
-                all.pop().unwrap()
-            } else {
-                StructuredScript::MultiScript(all)
+            let outer = std::mem::take(&mut sequence);
+            open.push((Block::Switch(vec![]), outer));
+        } else if op == OP_ELSE || op == OP_ENDIF || op == _OP_CASE || op == _OP_ENDSWITCH {
+            // the end of a branch, or of the script if there is no block open
+            let Some((block, outer)) = open.pop() else {
+                break;
             };
+            let branch = std::mem::replace(&mut sequence, outer).finish();
+
+            match block {
+                Block::If(negated, None) if op == OP_ELSE => {
+                    let outer = std::mem::take(&mut sequence);
+                    open.push((Block::If(negated, Some(branch)), outer));
+                }
+                Block::If(negated, first_branch) if op == OP_ENDIF => {
+                    let branch = Box::new(branch);
+                    sequence.push(match (negated, first_branch) {
+                        (false, None) => StructuredScript::IfEndIf(branch),
+                        (true, None) => StructuredScript::NotIfEndIf(branch),
+                        (false, Some(v1)) => StructuredScript::IfElseEndIf(Box::new(v1), branch),
+                        (true, Some(v1)) => StructuredScript::NotIfElseEndIf(Box::new(v1), branch),
+                    });
+                }
+                Block::If(false, _) => panic!("An if branch does not seem to end correctly."),
+                Block::If(true, _) => panic!("An not-if branch does not seem to end correctly."),
+                Block::Switch(mut arms) if op == _OP_CASE => {
+                    arms.push(branch);
+                    let outer = std::mem::take(&mut sequence);
+                    open.push((Block::Switch(arms), outer));
+                }
+                Block::Switch(mut arms) if op == _OP_ENDSWITCH => {
+                    arms.push(branch);
+                    sequence.push(StructuredScript::Switch(arms));
+                }
+                Block::Switch(_) => panic!("A switch does not seem to be structured correctly."),
+            }
         } else {
-            cur.push((*next_instruction).clone());
-            iter.next().unwrap();
+            sequence.cur.push(inst.clone());
         }
     }
 
-    if !cur.is_empty() {
-        all.push(StructuredScript::Script(OwnedInstructions(cur.clone())));
-        cur.clear();
-    }
-
-    if all.len() == 1 {
-        all.pop().unwrap()
-    } else {
-        StructuredScript::MultiScript(all)
+    if !open.is_empty() {
+        panic!("A conditional or a switch does not seem to end.");
     }
+    sequence.finish()
 }
 
 #[cfg(test)]
 mod test {
     use crate::structured_script::{OwnedInstruction, OwnedInstructions, StructuredScript};
+    use crate::{OP_CASE, OP_ENDSWITCH, OP_SWITCH};
     use bitcoin::opcodes::all::{OP_NOP1, OP_NOP4};
     use bitcoin::opcodes::{OP_NOP2, OP_NOP3};
+    use bitcoin::ScriptBuf;
     use bitcoin_script::{define_pushable, script};
 
     define_pushable!();
@@ -300,4 +759,44 @@ mod test {
 
         assert_eq!(expected, structued_script);
     }
+
+    #[test]
+    fn test_switch() {
+        let script = script! {
+            OP_NOP1
+            OP_SWITCH
+            OP_CASE
+                OP_NOP2
+            OP_CASE
+            OP_CASE
+                OP_NOP3
+            OP_ENDSWITCH
+        };
+
+        let structured_script = StructuredScript::from(script);
+
+        let expected = StructuredScript::MultiScript(vec![
+            StructuredScript::Script(OwnedInstructions(vec![OwnedInstruction::Op(OP_NOP1)])),
+            StructuredScript::Switch(vec![
+                StructuredScript::Script(OwnedInstructions(vec![OwnedInstruction::Op(OP_NOP2)])),
+                StructuredScript::MultiScript(vec![]),
+                StructuredScript::Script(OwnedInstructions(vec![OwnedInstruction::Op(OP_NOP3)])),
+            ]),
+        ]);
+        assert_eq!(expected, structured_script);
+
+        let lowered = script! {
+            OP_NOP1
+            OP_DUP 0 OP_NUMEQUAL OP_IF
+                OP_DROP OP_NOP2
+            OP_ELSE
+                OP_DUP 1 OP_NUMEQUAL OP_IF
+                    OP_DROP
+                OP_ELSE
+                    2 OP_NUMEQUALVERIFY OP_NOP3
+                OP_ENDIF
+            OP_ENDIF
+        };
+        assert_eq!(ScriptBuf::from(structured_script), lowered);
+    }
 }
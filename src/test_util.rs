@@ -0,0 +1,20 @@
+//! Helpers shared by the unit tests.
+
+/// A seeded xorshift64 generator, so that the generated tests are the same on every run.
+pub struct Xorshift64(u64);
+
+impl Xorshift64 {
+    /// The seed must not be 0.
+    pub fn new(seed: u64) -> Self {
+        assert_ne!(seed, 0);
+        Self(seed)
+    }
+
+    /// A number below `n`.
+    pub fn next(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}